@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::constants::PROGRAM_RAM_START;
+use crate::instruction::Instruction;
+
+// Includes nested deeper than this are assumed to be a cycle
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// An error and where in the source it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Assembles a source file into a ROM to be loaded at 0x200. Includes are
+// relative to the including file.
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        message: e.to_string(),
+    })?;
+
+    assemble_source(&source, path)
+}
+
+// Assembles source text, resolving includes relative to the working directory
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_source(source, Path::new("<input>"))
+}
+
+fn assemble_source(source: &str, path: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    read_lines(source, path, 0, &mut lines)?;
+
+    let mut assembler = Assembler { symbols: HashMap::new() };
+    assembler.define_symbols(&lines)?;
+    assembler.emit(&lines)
+}
+
+// One line of source with includes expanded
+struct Line {
+    file: String,
+    number: usize,
+    label: Option<String>,
+    statement: Option<Statement>,
+}
+
+impl Line {
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError { file: self.file.clone(), line: self.number, message: message.into() }
+    }
+}
+
+struct Statement {
+    // Lowercase
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+fn read_lines(source: &str, path: &Path, depth: usize, lines: &mut Vec<Line>) -> Result<(), AsmError> {
+    let file = path.display().to_string();
+
+    for (index, text) in source.lines().enumerate() {
+        let mut line = parse_line(text);
+        line.file = file.clone();
+        line.number = index + 1;
+
+        let include = match &line.statement {
+            Some(statement) if statement.mnemonic == "include" => statement.operands.clone(),
+            _ => {
+                lines.push(line);
+                continue;
+            },
+        };
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("includes nested too deeply"));
+        }
+        let [name] = include.as_slice() else {
+            return Err(line.error("include takes one file name"));
+        };
+        let name = name.trim_matches('"');
+        let include_path = path.parent().unwrap_or(Path::new("")).join(name);
+        let source = fs::read_to_string(&include_path)
+            .map_err(|e| line.error(format!("failed to include {}: {}", name, e)))?;
+
+        // Keep the label of the include line, pointing at the included code
+        lines.push(Line { statement: None, ..line });
+        read_lines(&source, &include_path, depth + 1, lines)?;
+    }
+
+    Ok(())
+}
+
+fn parse_line(text: &str) -> Line {
+    let mut text = text.split(';').next().unwrap_or("").trim();
+
+    let mut label = None;
+    if let Some((name, rest)) = text.split_once(':') {
+        if is_symbol(name.trim()) {
+            label = Some(name.trim().to_string());
+            text = rest.trim();
+        }
+    }
+
+    Line { file: String::new(), number: 0, label, statement: parse_statement(text) }
+}
+
+fn parse_statement(text: &str) -> Option<Statement> {
+    if text.is_empty() { return None; }
+    let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let rest = rest.trim();
+
+    // `NAME equ value`
+    if let Some((equ, value)) = rest.split_once(char::is_whitespace) {
+        if equ.eq_ignore_ascii_case("equ") {
+            return Some(Statement {
+                mnemonic: "equ".into(),
+                operands: vec![mnemonic.to_string(), value.trim().to_string()],
+            });
+        }
+    }
+
+    let operands = if rest.is_empty() {
+        Vec::new()
+    } else {
+        rest.split(',').map(|operand| operand.trim().to_string()).collect()
+    };
+    Some(Statement { mnemonic: mnemonic.to_ascii_lowercase(), operands })
+}
+
+struct Assembler {
+    symbols: HashMap<String, u32>,
+}
+
+impl Assembler {
+    // First pass: addresses of labels and values of constants
+    fn define_symbols(&mut self, lines: &[Line]) -> Result<(), AsmError> {
+        let mut addr = PROGRAM_RAM_START as u32;
+        let mut constants = Vec::new();
+
+        for line in lines {
+            if let Some(Statement { mnemonic, operands }) = &line.statement {
+                if mnemonic == "equ" {
+                    constants.push((line, operands));
+                    continue;
+                }
+            }
+
+            if let Some(label) = &line.label {
+                self.define(line, label.clone(), addr)?;
+            }
+            if let Some(statement) = &line.statement {
+                addr += size_of(statement).map_err(|e| line.error(e))?;
+            }
+        }
+
+        // Constants don't change any sizes, so they're worked out once every
+        // label is known. Each round defines the ones whose symbols are all
+        // known, until none are left or no more can be.
+        while !constants.is_empty() {
+            let mut error = None;
+            let mut remaining = Vec::new();
+            for (line, operands) in constants.iter().copied() {
+                match self.eval(&operands[1]) {
+                    Ok(value) => self.define(line, operands[0].clone(), value)?,
+                    Err(e) => {
+                        error.get_or_insert(line.error(e));
+                        remaining.push((line, operands));
+                    },
+                }
+            }
+            if remaining.len() == constants.len() {
+                return Err(error.unwrap());
+            }
+            constants = remaining;
+        }
+
+        Ok(())
+    }
+
+    fn define(&mut self, line: &Line, name: String, value: u32) -> Result<(), AsmError> {
+        if is_reserved(&name) {
+            return Err(line.error(format!("`{}` is a reserved name", name)));
+        }
+        if self.symbols.insert(name.clone(), value).is_some() {
+            return Err(line.error(format!("`{}` is defined more than once", name)));
+        }
+
+        Ok(())
+    }
+
+    // Second pass: encode everything, now that all symbols are known
+    fn emit(&self, lines: &[Line]) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+
+        for line in lines {
+            let Some(statement) = &line.statement else { continue };
+
+            match statement.mnemonic.as_str() {
+                "equ" => {},
+                "db" => {
+                    for operand in &statement.operands {
+                        let value = self.value(operand, 0xff).map_err(|e| line.error(e))?;
+                        rom.push(value as u8);
+                    }
+                },
+                "dw" => {
+                    for operand in &statement.operands {
+                        let value = self.value(operand, 0xffff).map_err(|e| line.error(e))?;
+                        rom.extend_from_slice(&(value as u16).to_be_bytes());
+                    }
+                },
+                _ => {
+                    let (instruction, long) = self.instruction(statement)
+                        .map_err(|e| line.error(e))?;
+                    rom.extend_from_slice(&instruction.encode().to_be_bytes());
+                    if let Some(addr) = long {
+                        rom.extend_from_slice(&addr.to_be_bytes());
+                    }
+                },
+            }
+        }
+
+        Ok(rom)
+    }
+
+    // The instruction for a statement, and the address word of `LD I, long`
+    fn instruction(&self, statement: &Statement) -> Result<(Instruction, Option<u16>), String> {
+        let ops: Vec<Operand> = statement.operands.iter().map(|op| Operand::parse(op)).collect();
+        let name = statement.mnemonic.as_str();
+
+        let instruction = match (name, ops.as_slice()) {
+            ("cls", []) => Instruction::Cls,
+            ("ret", []) => Instruction::Ret,
+            ("scr", []) => Instruction::ScrollRight,
+            ("scl", []) => Instruction::ScrollLeft,
+            ("exit", []) => Instruction::Exit,
+            ("low", []) => Instruction::Low,
+            ("high", []) => Instruction::High,
+            ("audio", []) => Instruction::Audio,
+            ("scd", [Operand::Value(n)]) => Instruction::ScrollDown(self.value(n, 0xf)? as u8),
+            ("scu", [Operand::Value(n)]) => Instruction::ScrollUp(self.value(n, 0xf)? as u8),
+            ("plane", [Operand::Value(n)]) => Instruction::Plane(self.value(n, 0x3)? as u8),
+            ("sys", [Operand::Value(addr)]) => Instruction::Sys(self.addr(addr)?),
+            ("jp", [Operand::Value(addr)]) => Instruction::Jp(self.addr(addr)?),
+            ("jp", [Operand::V(0), Operand::Value(addr)]) => Instruction::JpV0(self.addr(addr)?),
+            ("call", [Operand::Value(addr)]) => Instruction::Call(self.addr(addr)?),
+            ("se", [Operand::V(x), Operand::V(y)]) => Instruction::SeReg { x: *x, y: *y },
+            ("se", [Operand::V(x), Operand::Value(kk)]) => Instruction::SeByte { x: *x, kk: self.byte(kk)? },
+            ("sne", [Operand::V(x), Operand::V(y)]) => Instruction::SneReg { x: *x, y: *y },
+            ("sne", [Operand::V(x), Operand::Value(kk)]) => Instruction::SneByte { x: *x, kk: self.byte(kk)? },
+            ("save", [Operand::V(x), Operand::V(y)]) => Instruction::Save { x: *x, y: *y },
+            ("load", [Operand::V(x), Operand::V(y)]) => Instruction::Load { x: *x, y: *y },
+            ("ld", [Operand::V(x), Operand::V(y)]) => Instruction::LdReg { x: *x, y: *y },
+            ("ld", [Operand::V(x), Operand::Value(kk)]) => Instruction::LdByte { x: *x, kk: self.byte(kk)? },
+            ("ld", [Operand::I, Operand::Long(addr)]) => {
+                let addr = self.value(addr, 0xffff)? as u16;
+                return Ok((Instruction::LdILong, Some(addr)));
+            },
+            ("ld", [Operand::I, Operand::Value(addr)]) => Instruction::LdI(self.addr(addr)?),
+            ("ld", [Operand::V(x), Operand::Dt]) => Instruction::LdVxDt(*x),
+            ("ld", [Operand::V(x), Operand::K]) => Instruction::LdVxK(*x),
+            ("ld", [Operand::Dt, Operand::V(x)]) => Instruction::LdDtVx(*x),
+            ("ld", [Operand::St, Operand::V(x)]) => Instruction::LdStVx(*x),
+            ("ld", [Operand::F, Operand::V(x)]) => Instruction::LdF(*x),
+            ("ld", [Operand::Hf, Operand::V(x)]) => Instruction::LdHf(*x),
+            ("ld", [Operand::B, Operand::V(x)]) => Instruction::LdB(*x),
+            ("ld", [Operand::MemI, Operand::V(x)]) => Instruction::LdMemVx(*x),
+            ("ld", [Operand::V(x), Operand::MemI]) => Instruction::LdVxMem(*x),
+            ("ld", [Operand::R, Operand::V(x)]) => Instruction::LdRVx(*x),
+            ("ld", [Operand::V(x), Operand::R]) => Instruction::LdVxR(*x),
+            ("add", [Operand::V(x), Operand::V(y)]) => Instruction::AddReg { x: *x, y: *y },
+            ("add", [Operand::V(x), Operand::Value(kk)]) => Instruction::AddByte { x: *x, kk: self.byte(kk)? },
+            ("add", [Operand::I, Operand::V(x)]) => Instruction::AddI(*x),
+            ("or", [Operand::V(x), Operand::V(y)]) => Instruction::Or { x: *x, y: *y },
+            ("and", [Operand::V(x), Operand::V(y)]) => Instruction::And { x: *x, y: *y },
+            ("xor", [Operand::V(x), Operand::V(y)]) => Instruction::Xor { x: *x, y: *y },
+            ("sub", [Operand::V(x), Operand::V(y)]) => Instruction::Sub { x: *x, y: *y },
+            ("subn", [Operand::V(x), Operand::V(y)]) => Instruction::Subn { x: *x, y: *y },
+            ("shr", [Operand::V(x)]) => Instruction::Shr { x: *x, y: *x },
+            ("shr", [Operand::V(x), Operand::V(y)]) => Instruction::Shr { x: *x, y: *y },
+            ("shl", [Operand::V(x)]) => Instruction::Shl { x: *x, y: *x },
+            ("shl", [Operand::V(x), Operand::V(y)]) => Instruction::Shl { x: *x, y: *y },
+            ("rnd", [Operand::V(x), Operand::Value(kk)]) => Instruction::Rnd { x: *x, kk: self.byte(kk)? },
+            ("drw", [Operand::V(x), Operand::V(y), Operand::Value(n)]) =>
+                Instruction::Drw { x: *x, y: *y, n: self.value(n, 0xf)? as u8 },
+            ("skp", [Operand::V(x)]) => Instruction::Skp(*x),
+            ("sknp", [Operand::V(x)]) => Instruction::Sknp(*x),
+            ("pitch", [Operand::V(x)]) => Instruction::Pitch(*x),
+            _ if is_mnemonic(name) => {
+                return Err(format!(
+                    "invalid operands `{}` for {}",
+                    statement.operands.join(", "),
+                    name.to_ascii_uppercase()
+                ));
+            },
+            _ => return Err(format!("unknown instruction `{}`", name)),
+        };
+
+        Ok((instruction, None))
+    }
+
+    fn addr(&self, expr: &str) -> Result<u16, String> {
+        self.value(expr, 0xfff).map(|value| value as u16)
+    }
+
+    fn byte(&self, expr: &str) -> Result<u8, String> {
+        self.value(expr, 0xff).map(|value| value as u8)
+    }
+
+    fn value(&self, expr: &str, max: u32) -> Result<u32, String> {
+        let value = self.eval(expr)?;
+        if value > max {
+            return Err(format!("`{}` ({:#x}) is out of range, the maximum is {:#x}", expr, value, max));
+        }
+        Ok(value)
+    }
+
+    // Numbers and symbols joined by + and -
+    fn eval(&self, expr: &str) -> Result<u32, String> {
+        let mut total: i64 = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+
+        for c in expr.chars().chain(std::iter::once('+')) {
+            if c == '+' || c == '-' {
+                let term_text = term.trim();
+                if term_text.is_empty() {
+                    // A leading sign
+                    if c == '-' { sign = -sign; }
+                    continue;
+                }
+                total += sign * self.term(term_text)? as i64;
+                sign = if c == '-' { -1 } else { 1 };
+                term.clear();
+            } else {
+                term.push(c);
+            }
+        }
+
+        u32::try_from(total).map_err(|_| format!("`{}` is negative", expr))
+    }
+
+    fn term(&self, text: &str) -> Result<u32, String> {
+        if let Some(value) = parse_number(text) { return Ok(value); }
+        if !is_symbol(text) {
+            return Err(format!("invalid number `{}`", text));
+        }
+        self.symbols.get(text).copied().ok_or_else(|| format!("unknown symbol `{}`", text))
+    }
+}
+
+#[derive(Debug)]
+enum Operand<'a> {
+    V(u8),
+    I,
+    MemI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(&'a str),
+    Value(&'a str),
+}
+
+impl<'a> Operand<'a> {
+    fn parse(text: &'a str) -> Operand<'a> {
+        if let Some(expr) = long_operand(text) {
+            return Operand::Long(expr);
+        }
+
+        match text.to_ascii_lowercase().as_str() {
+            "i" => Operand::I,
+            "[i]" => Operand::MemI,
+            "dt" => Operand::Dt,
+            "st" => Operand::St,
+            "k" => Operand::K,
+            "f" => Operand::F,
+            "hf" => Operand::Hf,
+            "b" => Operand::B,
+            "r" => Operand::R,
+            lower => match register(lower) {
+                Some(reg) => Operand::V(reg),
+                None => Operand::Value(text),
+            },
+        }
+    }
+}
+
+// Bytes a statement assembles to
+fn size_of(statement: &Statement) -> Result<u32, String> {
+    let count = statement.operands.len() as u32;
+    match statement.mnemonic.as_str() {
+        "equ" => Ok(0),
+        "db" => Ok(count),
+        "dw" => Ok(count * 2),
+        "ld" if statement.operands.get(1).is_some_and(|op| long_operand(op).is_some()) => Ok(4),
+        name if is_mnemonic(name) => Ok(2),
+        name => Err(format!("unknown instruction `{}`", name)),
+    }
+}
+
+// The expression of a `long <expr>` operand, in any case
+fn long_operand(text: &str) -> Option<&str> {
+    let (keyword, expr) = text.split_once(char::is_whitespace)?;
+    keyword.eq_ignore_ascii_case("long").then(|| expr.trim())
+}
+
+fn is_mnemonic(name: &str) -> bool {
+    matches!(
+        name,
+        "cls" | "ret" | "scr" | "scl" | "exit" | "low" | "high" | "audio" | "scd" | "scu"
+            | "plane" | "sys" | "jp" | "call" | "se" | "sne" | "save" | "load" | "ld" | "add"
+            | "or" | "and" | "xor" | "sub" | "subn" | "shr" | "shl" | "rnd" | "drw" | "skp"
+            | "sknp" | "pitch"
+    )
+}
+
+// Register and operand names can't be used as symbols
+fn is_reserved(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    register(&lower).is_some()
+        || matches!(lower.as_str(), "i" | "dt" | "st" | "k" | "f" | "hf" | "b" | "r" | "long")
+}
+
+fn register(lower: &str) -> Option<u8> {
+    let digit = lower.strip_prefix('v')?;
+    if digit.len() != 1 { return None; }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn is_symbol(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Decimal, 0x or $ hex, or 0b binary
+fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_is_case_insensitive() {
+        assert_eq!(assemble("LD I, LONG 0x1234").unwrap(), vec![0xf0, 0x00, 0x12, 0x34]);
+        assert_eq!(assemble("ld i, long 0x1234").unwrap(), vec![0xf0, 0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn constants_can_use_later_symbols() {
+        let source = "SPEED equ BASE + 2\nBASE equ 3\nTARGET equ end\nld v0, SPEED\njp TARGET\nend: cls\n";
+        assert_eq!(assemble(source).unwrap(), vec![0x60, 0x05, 0x12, 0x04, 0x00, 0xe0]);
+    }
+
+    #[test]
+    fn circular_constants_are_errors() {
+        let error = assemble("A equ B\nB equ A\n").unwrap_err();
+        assert_eq!(error.line, 1);
+        assert_eq!(error.message, "unknown symbol `B`");
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        let source = "start: call sub\njp end\nsub: ret\nend: jp start\n";
+        assert_eq!(assemble(source).unwrap(), vec![0x22, 0x04, 0x12, 0x06, 0x00, 0xee, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn data_directives() {
+        let source = "ld i, table\ntable: db 1, 0x02, $03, 0b100\ndw 0x1234, table + 1\n";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![0xa2, 0x02, 0x01, 0x02, 0x03, 0x04, 0x12, 0x34, 0x02, 0x03]
+        );
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let dir = std::env::temp_dir().join(format!("chip_8r_asm_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "ld i, sprite\nsprites: include \"lib/sprites.asm\"\njp sprites\n").unwrap();
+        fs::write(dir.join("lib/sprites.asm"), "sprite: db 0xff, 0x81\n").unwrap();
+        let rom = assemble_file(&dir.join("main.asm"));
+        fs::write(dir.join("lib/sprites.asm"), "sprite: db 0xff\nld v0, 0x100\n").unwrap();
+        let error = assemble_file(&dir.join("main.asm")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rom.unwrap(), vec![0xa2, 0x02, 0xff, 0x81, 0x12, 0x02]);
+        assert!(error.file.ends_with("sprites.asm"), "{}", error.file);
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn errors_carry_their_line() {
+        let cases = [
+            ("cls\nld v0, 0x100\n", 2, "`0x100` (0x100) is out of range, the maximum is 0xff"),
+            ("cls\n\nmov v0, v1\n", 3, "unknown instruction `mov`"),
+            ("add v0\n", 1, "invalid operands `v0` for ADD"),
+            ("jp nowhere\n", 1, "unknown symbol `nowhere`"),
+            ("a: cls\n; comment\na: cls\n", 3, "`a` is defined more than once"),
+            ("v1: cls\n", 1, "`v1` is a reserved name"),
+            ("include \"missing.asm\"\n", 1, "failed to include missing.asm"),
+        ];
+        for (source, line, message) in cases {
+            let error = assemble(source).unwrap_err();
+            assert_eq!(error.line, line, "{}", source);
+            assert!(error.message.starts_with(message), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn every_instruction_assembles_from_its_disassembly() {
+        for opcode in 0..=u16::MAX {
+            let Ok(instruction) = crate::instruction::decode(opcode) else { continue };
+            // The assembler only takes the planes XO-CHIP has
+            if matches!(instruction, Instruction::LdILong | Instruction::Plane(4..)) { continue; }
+            // Bits the decoder ignores, like the N of 9XYN, aren't kept
+            let text = instruction.to_string();
+            assert_eq!(assemble(&text).unwrap(), instruction.encode().to_be_bytes(), "{}", text);
+        }
+        assert_eq!(assemble("LD I, long 0xfedc").unwrap(), vec![0xf0, 0x00, 0xfe, 0xdc]);
+    }
+}
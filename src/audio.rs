@@ -1,10 +1,13 @@
 use sdl2::{Sdl, audio::{AudioDevice, AudioCallback, AudioSpecDesired}};
 
-use crate::constants::{WAVETABLE_SIZE, PITCH, SAMPLE_RATE, VOLUME, CHANNELS};
+use chip_8r::constants::{SAMPLE_RATE, CHANNELS, DEFAULT_PITCH};
+use chip_8r::sound::{AudioPattern, Oscillator, BUZZER_PATTERN};
 
 pub struct Audio {
-    device: AudioDevice<Oscillator>,
+    device: AudioDevice<Speaker>,
     playing: bool,
+    pattern: AudioPattern,
+    pitch: u8,
 }
 
 impl Audio {
@@ -20,13 +23,15 @@ impl Audio {
         let device = audio_subsystem.open_playback(
             None,
             &desired_spec,
-            |_spec| {
-                Oscillator::new()
+            |spec| {
+                Speaker(Oscillator::new(spec.freq as f32))
         })?;
 
         Ok(Audio {
             device,
             playing: false,
+            pattern: BUZZER_PATTERN,
+            pitch: DEFAULT_PITCH,
         })
     }
 
@@ -40,69 +45,29 @@ impl Audio {
         self.playing = false;
     }
 
-    // Getters
-    pub fn is_playing(&self) -> bool { self.playing }
-}
+    // Only locks the device when the program changed its sound
+    pub fn set_sound(&mut self, pattern: &AudioPattern, pitch: u8) {
+        if *pattern == self.pattern && pitch == self.pitch { return; }
 
-type WaveTable = [f32; WAVETABLE_SIZE];
-struct Oscillator {
-    table: WaveTable,
-    cursor: f32,
-    phase_inc: f32,
-}
-
-impl Oscillator {
-    pub fn new() -> Oscillator {
-        let mut table: WaveTable = [0.0; WAVETABLE_SIZE];
-        for (i, sample) in table.iter_mut().enumerate() {
-            *sample = (
-                2.0 * (i as  f32 / WAVETABLE_SIZE as f32).tan().atan()
-            ).sin();
-        }
-
-        let phase_inc = PITCH * WAVETABLE_SIZE as f32 / SAMPLE_RATE;
-
-        Oscillator {
-            table,
-            cursor: 0.0,
-            phase_inc,
-        }
+        let mut speaker = self.device.lock();
+        speaker.0.set_pattern(*pattern);
+        speaker.0.set_pitch(pitch);
+        self.pattern = *pattern;
+        self.pitch = pitch;
     }
 
-    pub fn get_sample(&mut self) -> f32 {
-        let sample = self.lerp();
-        self.cursor += self.phase_inc;
-        self.cursor %= WAVETABLE_SIZE as f32;
-
-        sample
-    }
-
-    fn lerp(&self) -> f32 {
-        let first_index = self.cursor as usize;
-        let next_index = (first_index + 1) % WAVETABLE_SIZE;
-
-        let next_index_weight = self.cursor - first_index as f32;
-        let first_index_weight = 1.0 - next_index_weight;
-
-        first_index_weight * self.table[first_index]
-            + next_index_weight * self.table[next_index]
-    }
+    // Getters
+    pub fn is_playing(&self) -> bool { self.playing }
 }
 
-impl Iterator for Oscillator {
-    type Item = f32;
+struct Speaker(Oscillator);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(self.get_sample())
-    }
-}
-
-impl AudioCallback for Oscillator {
+impl AudioCallback for Speaker {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
         for x in out.iter_mut() {
-            *x = self.get_sample() * VOLUME;
+            *x = self.0.get_sample();
         }
     }
-}
\ No newline at end of file
+}
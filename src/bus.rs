@@ -1,111 +1,102 @@
-extern crate native_dialog;
-extern crate sdl2;
-
-use native_dialog::{FileDialog, MessageDialog, MessageType};
-use std::{
-    error::Error,
-    fs::File,
-    io::Read,
-};
-
-use crate::audio::Audio;
+use crate::error::{Error, ErrorType};
+use crate::instruction::{decode_for, DecodeError, Instruction};
+use crate::variant::Variant;
 use crate::constants::{
+    BIG_FONT_HEIGHT,
+    BIG_FONT_RAM_START,
     FONT_RAM_START,
     FONT_RAM_END,
     PROGRAM_RAM_START,
     PROGRAM_RAM_END, RAM_SIZE,
     FONT_HEIGHT
 };
-use crate::cpu::FrameBuffer;
-use crate::display::Display;
-use crate::keyboard::Keyboard;
 
-type RamType = [u8; (RAM_SIZE - 1) as usize];
+// 4 KiB for CHIP-8 and SUPER-CHIP, 64 KiB for XO-CHIP
+type RamType = Vec<u8>;
+type DecodeCache = Vec<Option<Result<Instruction, DecodeError>>>;
 pub struct Bus {
-    audio: Audio,
-    display: Display,
-    pub keyboard: Keyboard,
-    ram: RamType
+    pub(crate) ram: RamType,
+    // Instruction decoded at each address, dropped when either byte changes
+    decoded: DecodeCache,
 }
 
 impl Bus {
-    pub fn new() -> Result<Bus, String> {
-        let sdl_context = sdl2::init()?;
-
-        let audio = Audio::new(&sdl_context)?;
-        let display = Display::new(&sdl_context)?;
-        let keyboard = Keyboard::new(&sdl_context)?;
-
-        Ok(Bus {
-            audio,
-            display,
-            keyboard,
-            ram: [0; (RAM_SIZE - 1) as usize],
-        })
+    pub fn new(size: usize) -> Bus {
+        Bus {
+            ram: vec![0; size],
+            decoded: vec![None; size],
+        }
     }
 
     pub fn init_ram(&mut self) {
         load_fonts(&mut self.ram);
+        self.clear_decoded();
     }
 
-    pub fn load_rom(&mut self) -> Result<(), Box<dyn Error>> {
-        show_intro();
-
-        let mut buffer: Vec<u8> = Vec::new();
-        if let Some(path) = FileDialog::new()
-            .set_location("./")
-            .add_filter("name", &["ch8"])
-            .show_open_single_file().unwrap() {
-                let mut file = File::open(path)?;
-
-                let rom_size = file.read_to_end(&mut buffer)?;
-
-                if rom_size > PROGRAM_RAM_END - PROGRAM_RAM_START {
-                    return Err("invalid ROM".into());
-                }
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        // The top of the 4 KiB machines is reserved for the interpreter
+        let end = if self.size() > RAM_SIZE { self.size() } else { PROGRAM_RAM_END };
+        let max = end - PROGRAM_RAM_START;
+        if rom.len() > max {
+            return Err(Error::new(ErrorType::RomTooLarge { size: rom.len(), max }));
+        }
 
-                self.ram[PROGRAM_RAM_START..(buffer.len() + PROGRAM_RAM_START)]
-                    .copy_from_slice(&buffer[..]);
-            } else {
-                return Err("Unable to load rom".into());
-            }
+        self.ram[PROGRAM_RAM_START..(rom.len() + PROGRAM_RAM_START)]
+            .copy_from_slice(rom);
+        self.clear_decoded();
 
         Ok(())
     }
 
-    pub fn handle_input(&mut self) -> bool { self.keyboard.handle_input() }
+    pub fn size(&self) -> usize { self.ram.len() }
 
-    pub fn render(&mut self, buffer: &FrameBuffer) {
-        self.display.render(buffer);
-    }
+    // Addresses past the end of RAM wrap around to its start
+    pub fn read_byte(&self, addr: u16) -> u8 { self.ram[addr as usize % self.size()] }
 
-    pub fn play_audio(&mut self) {
-        if !self.audio.is_playing() {
-            self.audio.play();
-        }
+    pub fn read_word(&self, addr: u16) -> u16 {
+        (self.read_byte(addr) as u16) << 8 | self.read_byte(addr.wrapping_add(1)) as u16
     }
 
-    pub fn stop_audio(&mut self) {
-        if self.audio.is_playing() {
-            self.audio.stop();
-        }
+    pub fn write_byte(&mut self, addr: usize, byte: u8) {
+        self.ram[addr] = byte;
+        self.decoded[addr] = None;
+        if addr > 0 { self.decoded[addr - 1] = None; }
     }
 
-    pub fn is_pressed(&self, key: u8) -> bool { self.keyboard.is_pressed(key) }
+    // Replaces all of RAM, e.g. when loading a save state
+    pub(crate) fn restore_ram(&mut self, ram: &[u8]) {
+        self.ram.copy_from_slice(ram);
+        self.clear_decoded();
+    }
 
-    pub fn get_keyup(&self) -> Option<u8> { self.keyboard.get_keyup() }
+    // Decodes the opcode at `addr`, which must have a byte after it. A bus
+    // only ever serves one machine, so the cache assumes a single variant.
+    pub fn decode(&mut self, addr: u16, variant: Variant) -> Result<Instruction, DecodeError> {
+        if let Some(result) = self.decoded[addr as usize] { return result; }
 
-    pub fn read_byte(&self, addr: u16) -> u8 { self.ram[addr as usize] }
+        let result = decode_for(self.read_word(addr), variant);
+        self.decoded[addr as usize] = Some(result);
+        result
+    }
 
-    pub fn write_byte(&mut self, addr: usize, byte: u8) {
-        self.ram[addr] = byte;
+    fn clear_decoded(&mut self) {
+        self.decoded.fill(None);
     }
 }
 
-fn load_fonts(ram: &mut RamType) {
+impl Default for Bus {
+    fn default() -> Self { Bus::new(RAM_SIZE) }
+}
+
+fn load_fonts(ram: &mut [u8]) {
     for i in FONT_RAM_START..FONT_RAM_END {
         ram[i] = FONT_SPRITES[i / FONT_HEIGHT][i % FONT_HEIGHT];
     }
+
+    for (digit, sprite) in BIG_FONT_SPRITES.iter().enumerate() {
+        let start = BIG_FONT_RAM_START + digit * BIG_FONT_HEIGHT;
+        ram[start..start + BIG_FONT_HEIGHT].copy_from_slice(sprite);
+    }
 }
 
 type FontHex = [u8; FONT_HEIGHT];
@@ -144,18 +135,39 @@ const FONT_SPRITES: [FontHex; 16] = [
     [0xf0, 0x80, 0xf0, 0x80, 0x80]
 ];
 
-fn show_intro() {
-    MessageDialog::new()
-        .set_type(MessageType::Info)
-        .set_title("Welcome to Chip-8!")
-        .set_text("This emulator was written in Rust, by Jon Choukroun.")
-        .show_alert()
-        .unwrap();
-
-    MessageDialog::new()
-        .set_type(MessageType::Info)
-        .set_title("Welcome to Chip-8!")
-        .set_text("Click `OK` to load a ROM. Press `Esc` at any time to quit.")
-        .show_alert()
-        .unwrap();
-}
\ No newline at end of file
+// SUPER-CHIP 8x10 digits, with XO-CHIP's A-F
+type BigFontHex = [u8; BIG_FONT_HEIGHT];
+const BIG_FONT_SPRITES: [BigFontHex; 16] = [
+    // 0
+    [0xff, 0xff, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff],
+    // 1
+    [0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xff, 0xff],
+    // 2
+    [0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff],
+    // 3
+    [0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff],
+    // 4
+    [0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0x03, 0x03],
+    // 5
+    [0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff],
+    // 6
+    [0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff],
+    // 7
+    [0xff, 0xff, 0x03, 0x03, 0x06, 0x0c, 0x18, 0x18, 0x18, 0x18],
+    // 8
+    [0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff],
+    // 9
+    [0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff],
+    // a
+    [0x7e, 0xff, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xc3],
+    // b
+    [0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc],
+    // c
+    [0x3c, 0xff, 0xc3, 0xc0, 0xc0, 0xc0, 0xc0, 0xc3, 0xff, 0x3c],
+    // d
+    [0xfc, 0xfe, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xfe, 0xfc],
+    // e
+    [0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff],
+    // f
+    [0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xc0, 0xc0]
+];
@@ -0,0 +1,156 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chip_8r::constants::SAMPLE_RATE;
+use chip_8r::framebuffer::FrameBuffer;
+use chip_8r::machine::Machine;
+use chip_8r::movie::Movie;
+use chip_8r::palette::Palette;
+use chip_8r::recording::{GifRecorder, WavRecorder};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+// GIF recording to a file
+pub struct Recording {
+    recorder: GifRecorder<BufWriter<File>>,
+    path: PathBuf,
+}
+
+impl Recording {
+    pub fn start(path: &Path, palette: &Palette, scale: u32) -> Result<Recording, String> {
+        let failed = |e: std::io::Error| format!("failed to record to {}: {}", path.display(), e);
+        let file = File::create(path).map_err(failed)?;
+        let recorder = GifRecorder::new(BufWriter::new(file), palette, scale as usize).map_err(failed)?;
+
+        println!("Recording to {}", path.display());
+        Ok(Recording { recorder, path: path.to_path_buf() })
+    }
+
+    pub fn push(&mut self, frame_buffer: &FrameBuffer) -> Result<(), String> {
+        self.recorder.push(frame_buffer)
+            .map_err(|e| format!("failed to record to {}: {}", self.path.display(), e))
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        let frames = self.recorder.frames();
+        self.recorder.finish()
+            .and_then(|mut writer| std::io::Write::flush(&mut writer))
+            .map_err(|e| format!("failed to record to {}: {}", self.path.display(), e))?;
+
+        println!("Saved {} frames to {}", frames, self.path.display());
+        Ok(())
+    }
+}
+
+// WAV recording of the buzzer to a file
+pub struct AudioRecording {
+    recorder: WavRecorder<BufWriter<File>>,
+    path: PathBuf,
+}
+
+impl AudioRecording {
+    pub fn start(path: &Path) -> Result<AudioRecording, String> {
+        let failed = |e: std::io::Error| format!("failed to record to {}: {}", path.display(), e);
+        let file = File::create(path).map_err(failed)?;
+        let recorder = WavRecorder::new(BufWriter::new(file)).map_err(failed)?;
+
+        println!("Recording audio to {}", path.display());
+        Ok(AudioRecording { recorder, path: path.to_path_buf() })
+    }
+
+    pub fn push(&mut self, machine: &Machine) -> Result<(), String> {
+        self.recorder.push(machine)
+            .map_err(|e| format!("failed to record to {}: {}", self.path.display(), e))
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        let seconds = self.recorder.samples() as f32 / SAMPLE_RATE;
+        self.recorder.finish()
+            .map_err(|e| format!("failed to record to {}: {}", self.path.display(), e))?;
+
+        println!("Saved {:.1} seconds of audio to {}", seconds, self.path.display());
+        Ok(())
+    }
+}
+
+// Movie of the keypad, written to a file when finished
+pub struct InputRecording {
+    movie: Movie,
+    path: PathBuf,
+}
+
+impl InputRecording {
+    // The machine must not have run yet, since movies start from frame 0
+    pub fn start(path: &Path, machine: &Machine) -> Result<InputRecording, String> {
+        // Fail now rather than losing the whole session at the end
+        File::create(path).map_err(|e| format!("failed to record to {}: {}", path.display(), e))?;
+
+        println!("Recording input to {}", path.display());
+        Ok(InputRecording { movie: Movie::new(machine), path: path.to_path_buf() })
+    }
+
+    // Records the keys held on the frames run since the last call. Frames
+    // rewound since then are dropped.
+    pub fn push(&mut self, machine: &Machine, keys: u16) {
+        let frames = machine.frame_count() as usize;
+        self.movie.truncate(frames);
+        while self.movie.len() < frames {
+            self.movie.push(keys);
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        fs::write(&self.path, self.movie.to_bytes())
+            .map_err(|e| format!("failed to write {}: {}", self.path.display(), e))?;
+
+        println!("Saved {} frames of input to {}", self.movie.len(), self.path.display());
+        Ok(())
+    }
+}
+
+// Name for a screenshot or recording of a ROM, in the working directory:
+// `pong.ch8` gives `pong-20261018-153012.png`. A number is added when a
+// file with that name already exists.
+pub fn timestamped_path(rom_path: &Path, extension: &str) -> PathBuf {
+    let name = rom_path.file_stem().map_or("chip_8r".into(), |stem| stem.to_string_lossy());
+    let base = format!("{}-{}", name, timestamp());
+
+    let mut path = PathBuf::from(format!("{}.{}", base, extension));
+    let mut count = 2;
+    while path.exists() {
+        path = PathBuf::from(format!("{}-{}.{}", base, count, extension));
+        count += 1;
+    }
+    path
+}
+
+// Current UTC time as `YYYYMMDD-HHMMSS`
+fn timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let time = seconds % SECONDS_PER_DAY;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, time / 3600, time / 60 % 60, time % 60
+    )
+}
+
+// Date of a day counted from 1970-01-01, from Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
@@ -0,0 +1,290 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::{Args, Parser, Subcommand};
+
+use chip_8r::constants::{DEFAULT_GDB_PORT, MAX_REWIND_SECONDS};
+use chip_8r::image::ImageFormat;
+use chip_8r::quirks::Profile;
+use chip_8r::variant::Variant;
+
+#[derive(Parser, Debug)]
+#[command(name = "chip_8r", version, about = "Chip-8 interpreter written in Rust, with SDL")]
+pub struct Cli {
+    /// Config file. Defaults to `chip_8r.toml` in the working directory, if present
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run a ROM in an SDL window
+    Run(RunArgs),
+    /// Run a ROM under the debugger, taking commands on stdin
+    Debug(DebugArgs),
+    /// Run a ROM with a GDB remote stub listening on localhost
+    Gdb(GdbArgs),
+    /// Print a ROM as assembly
+    Disasm(DisasmArgs),
+    /// Print a ROM as Octo source
+    Decompile(DecompileArgs),
+    /// Assemble a source file into a ROM
+    Asm(AsmArgs),
+    /// Run a ROM without a window and compare the screen with an image
+    Test(TestArgs),
+}
+
+#[derive(Args, Debug, Default)]
+pub struct RunArgs {
+    /// ROM to load. A file dialog is shown when omitted
+    pub rom: Option<PathBuf>,
+
+    /// Instructions executed per 60 Hz frame
+    #[arg(long)]
+    pub ipf: Option<u32>,
+
+    /// Size in screen pixels of a single CHIP-8 pixel
+    #[arg(long)]
+    pub scale: Option<u32>,
+
+    /// Quirk profile: vip, chip48, schip, modern or xochip
+    #[arg(long)]
+    pub quirks: Option<Profile>,
+
+    /// Instruction set: chip8, schip or xochip. Defaults to the one of the quirk profile
+    #[arg(long)]
+    pub variant: Option<Variant>,
+
+    /// Seconds of gameplay kept for rewinding, up to an hour; 0 disables rewind
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=MAX_REWIND_SECONDS as i64))]
+    pub rewind: Option<u32>,
+
+    /// Disable sound
+    #[arg(long)]
+    pub mute: bool,
+
+    /// Size in image pixels of a single CHIP-8 pixel in screenshots
+    #[arg(long)]
+    pub screenshot_scale: Option<u32>,
+
+    /// Screenshot file format: png, pbm, pgm or txt
+    #[arg(long)]
+    pub screenshot_format: Option<ImageFormat>,
+
+    /// Record the screen to an animated GIF from the start
+    #[arg(long, value_name = "GIF")]
+    pub record: Option<PathBuf>,
+
+    /// Record the sound to a WAV file from the start
+    #[arg(long, value_name = "WAV")]
+    pub record_audio: Option<PathBuf>,
+
+    /// Seed for the random numbers of RND, to make runs reproducible
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Bytes RND returns in turn instead of random numbers, repeating, as
+    /// a comma-separated list like `0x12,0x34,200`
+    #[arg(long, value_name = "BYTES", value_delimiter = ',', value_parser = parse_byte)]
+    pub random_bytes: Vec<u8>,
+
+    /// Record the keypad to a movie file that `--replay` plays back exactly
+    #[arg(long, value_name = "MOVIE")]
+    pub record_input: Option<PathBuf>,
+
+    /// Play back a movie recorded with `--record-input`, using its quirks,
+    /// speed and RND seed
+    #[arg(long, value_name = "MOVIE")]
+    pub replay: Option<PathBuf>,
+}
+
+// Decimal, or hex with a `0x` prefix
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let s = s.trim();
+    let byte = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    byte.map_err(|_| format!("invalid byte `{}`", s))
+}
+
+#[derive(Args, Debug)]
+pub struct DebugArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Run without a window, sound or keypad input
+    #[arg(long)]
+    pub headless: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct GdbArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// TCP port to listen on
+    #[arg(long, default_value_t = DEFAULT_GDB_PORT)]
+    pub port: u16,
+
+    /// Run without a window, sound or keypad input
+    #[arg(long)]
+    pub headless: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct DisasmArgs {
+    /// ROM to disassemble
+    pub rom: PathBuf,
+
+    /// Quirk profile, which selects the default instruction set
+    #[arg(long)]
+    pub quirks: Option<Profile>,
+
+    /// Instruction set: chip8, schip or xochip
+    #[arg(long)]
+    pub variant: Option<Variant>,
+}
+
+#[derive(Args, Debug)]
+pub struct DecompileArgs {
+    /// ROM to decompile
+    pub rom: PathBuf,
+
+    /// File to write the source to, instead of printing it
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Quirk profile, which selects the default instruction set
+    #[arg(long)]
+    pub quirks: Option<Profile>,
+
+    /// Instruction set: chip8, schip or xochip
+    #[arg(long)]
+    pub variant: Option<Variant>,
+}
+
+#[derive(Args, Debug)]
+pub struct AsmArgs {
+    /// Assembly source file
+    pub source: PathBuf,
+
+    /// ROM to write. Defaults to the source file with a `.ch8` extension
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Frames to run before looking at the screen
+    #[arg(long, default_value_t = 300)]
+    pub frames: u64,
+
+    /// Hold a key, as FRAME:KEY or FRAME:KEY:DURATION in frames (default 1).
+    /// KEY is a hex digit. Can be given more than once
+    #[arg(long = "key", value_name = "FRAME:KEY[:DURATION]")]
+    pub keys: Vec<KeyPress>,
+
+    /// Golden image to compare the final screen with: a `.pbm` file, or text
+    #[arg(long)]
+    pub expect: Option<PathBuf>,
+
+    /// Write the final screen to an image: `.png`, `.pbm`, `.pgm` or text
+    #[arg(long)]
+    pub save: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyPress {
+    pub frame: u64,
+    pub key: u8,
+    pub duration: u64,
+}
+
+impl FromStr for KeyPress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KeyPress, String> {
+        let invalid = || format!("invalid key press `{}` (expected FRAME:KEY[:DURATION])", s);
+        let fields: Vec<&str> = s.split(':').collect();
+        if fields.len() < 2 || fields.len() > 3 { return Err(invalid()); }
+
+        let frame = fields[0].parse().map_err(|_| invalid())?;
+        let key = u8::from_str_radix(fields[1], 16).ok().filter(|key| *key < 16).ok_or_else(invalid)?;
+        let duration = match fields.get(2) {
+            Some(duration) => duration.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+
+        Ok(KeyPress { frame, key, duration })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("chip_8r").chain(args.iter().copied()))
+    }
+
+    fn run_args(args: &[&str]) -> RunArgs {
+        match parse(args).unwrap().command {
+            Some(Command::Run(args)) => args,
+            command => panic!("not a run command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parses_run_options() {
+        let args = run_args(&[
+            "run", "game.ch8", "--ipf", "20", "--quirks", "schip", "--variant", "xochip",
+            "--mute", "--random-bytes", "0x12,34",
+        ]);
+        assert_eq!(args.rom, Some(PathBuf::from("game.ch8")));
+        assert_eq!(args.ipf, Some(20));
+        assert_eq!(args.quirks, Some(Profile::SuperChip));
+        assert_eq!(args.variant, Some(Variant::XoChip));
+        assert!(args.mute);
+        assert_eq!(args.random_bytes, [0x12, 34]);
+        assert_eq!(args.scale, None);
+    }
+
+    #[test]
+    fn rom_and_command_are_optional() {
+        assert!(parse(&[]).unwrap().command.is_none());
+        assert_eq!(run_args(&["run"]).rom, None);
+
+        let cli = parse(&["gdb", "game.ch8", "--config", "other.toml"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("other.toml")));
+        match cli.command {
+            Some(Command::Gdb(args)) => assert_eq!(args.port, DEFAULT_GDB_PORT),
+            command => panic!("not a gdb command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        assert!(parse(&["run", "--quirks", "chip-9"]).is_err());
+        assert!(parse(&["run", "--ipf", "-1"]).is_err());
+        assert!(parse(&["run", "--rewind", "3601"]).is_err());
+        assert!(parse(&["run", "--random-bytes", "256"]).is_err());
+        assert!(parse(&["run", "--speed", "2"]).is_err());
+        assert!(parse(&["disasm"]).is_err());
+    }
+
+    #[test]
+    fn parses_key_presses() {
+        assert_eq!("30:a".parse(), Ok(KeyPress { frame: 30, key: 0xa, duration: 1 }));
+        assert_eq!("0:F:10".parse(), Ok(KeyPress { frame: 0, key: 0xf, duration: 10 }));
+        for press in ["30", "30:10", "x:1", "1:2:3:4", "1:2:-1"] {
+            assert!(press.parse::<KeyPress>().is_err(), "{}", press);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chip_8r::constants::{DEFAULT_IPF, DEFAULT_REWIND_SECONDS, MAX_REWIND_SECONDS, PIXEL_SIZE};
+use chip_8r::image::ImageFormat;
+use chip_8r::palette::Palette;
+use chip_8r::quirks::{Profile, Quirks};
+use chip_8r::variant::Variant;
+
+use crate::cli::RunArgs;
+
+pub const DEFAULT_CONFIG_PATH: &str = "chip_8r.toml";
+
+// Contents of `chip_8r.toml`. Top-level keys apply to every ROM, and a
+// `[roms."name.ch8"]` table overrides them for the ROM with that file name.
+#[derive(Default, Debug)]
+pub struct Config {
+    pub defaults: Settings,
+    pub roms: HashMap<String, Settings>,
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub quirks: Option<String>,
+    pub variant: Option<String>,
+    pub ipf: Option<u32>,
+    pub scale: Option<u32>,
+    pub mute: Option<bool>,
+    pub rewind: Option<u32>,
+    pub seed: Option<u64>,
+    // Colors as `#rrggbb`: background, plane 1, plane 2 and both planes
+    pub palette: Option<Vec<String>>,
+    pub screenshot_scale: Option<u32>,
+    pub screenshot_format: Option<String>,
+}
+
+// Final options for a run, after the CLI has been applied over the config
+#[derive(Debug)]
+pub struct Options {
+    pub ipf: u32,
+    pub scale: u32,
+    pub mute: bool,
+    // Seconds kept in the rewind buffer
+    pub rewind: u32,
+    // RND seed, random when not set, and bytes to force RND results
+    pub seed: Option<u64>,
+    pub random_bytes: Vec<u8>,
+    pub variant: Variant,
+    pub quirks: Quirks,
+    pub palette: Palette,
+    pub screenshot_scale: u32,
+    pub screenshot_format: ImageFormat,
+    // GIF and WAV to record to from the start
+    pub record: Option<PathBuf>,
+    pub record_audio: Option<PathBuf>,
+    // Movies to record input to, and to play back
+    pub record_input: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+}
+
+impl Config {
+    // An explicit path must exist; the default path is optional
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Config::default()),
+        };
+
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))
+    }
+
+    // The top-level keys are read as `Settings` on their own, rather than
+    // flattened into `Config`, so that unknown keys are still rejected
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let roms = match table.remove("roms") {
+            Some(roms) => roms.try_into()?,
+            None => HashMap::new(),
+        };
+
+        Ok(Config { defaults: table.try_into()?, roms })
+    }
+
+    pub fn settings_for(&self, rom: &Path) -> Settings {
+        let mut settings = self.defaults.clone();
+        let name = rom.file_name().and_then(|name| name.to_str());
+        if let Some(rom_settings) = name.and_then(|name| self.roms.get(name)) {
+            let rom_settings = rom_settings.clone();
+            settings.quirks = rom_settings.quirks.or(settings.quirks);
+            settings.variant = rom_settings.variant.or(settings.variant);
+            settings.ipf = rom_settings.ipf.or(settings.ipf);
+            settings.scale = rom_settings.scale.or(settings.scale);
+            settings.mute = rom_settings.mute.or(settings.mute);
+            settings.rewind = rom_settings.rewind.or(settings.rewind);
+            settings.seed = rom_settings.seed.or(settings.seed);
+            settings.palette = rom_settings.palette.or(settings.palette);
+            settings.screenshot_scale = rom_settings.screenshot_scale.or(settings.screenshot_scale);
+            settings.screenshot_format = rom_settings.screenshot_format.or(settings.screenshot_format);
+        }
+
+        settings
+    }
+}
+
+impl Options {
+    pub fn resolve(args: &RunArgs, settings: &Settings) -> Result<Options, String> {
+        let profile = match (args.quirks, &settings.quirks) {
+            (Some(profile), _) => profile,
+            (None, Some(name)) => name.parse::<Profile>()?,
+            (None, None) => Profile::default(),
+        };
+        let variant = match (args.variant, &settings.variant) {
+            (Some(variant), _) => variant,
+            (None, Some(name)) => name.parse::<Variant>()?,
+            (None, None) => profile.variant(),
+        };
+        let palette = match &settings.palette {
+            Some(colors) => Palette::from_colors(colors)?,
+            None => Palette::default(),
+        };
+        let screenshot_format = match (args.screenshot_format, &settings.screenshot_format) {
+            (Some(format), _) => format,
+            (None, Some(name)) => name.parse::<ImageFormat>()?,
+            (None, None) => ImageFormat::default(),
+        };
+
+        let rewind = args.rewind.or(settings.rewind).unwrap_or(DEFAULT_REWIND_SECONDS);
+        if rewind > MAX_REWIND_SECONDS {
+            return Err(format!("rewind can keep at most {} seconds, not {}", MAX_REWIND_SECONDS, rewind));
+        }
+
+        Ok(Options {
+            ipf: args.ipf.or(settings.ipf).unwrap_or(DEFAULT_IPF),
+            scale: args.scale.or(settings.scale).unwrap_or(PIXEL_SIZE),
+            mute: args.mute || settings.mute.unwrap_or(false),
+            rewind,
+            seed: args.seed.or(settings.seed),
+            random_bytes: args.random_bytes.clone(),
+            variant,
+            quirks: profile.quirks(),
+            palette,
+            screenshot_scale: args.screenshot_scale.or(settings.screenshot_scale).unwrap_or(1),
+            screenshot_format,
+            record: args.record.clone(),
+            record_audio: args.record_audio.clone(),
+            record_input: args.record_input.clone(),
+            replay: args.replay.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_tables_override_the_defaults() {
+        let config = Config::parse(concat!(
+            "quirks = \"vip\"\n",
+            "ipf = 20\n",
+            "[roms.\"game.ch8\"]\n",
+            "quirks = \"schip\"\n",
+        )).unwrap();
+
+        let settings = config.settings_for(Path::new("roms/game.ch8"));
+        assert_eq!(settings.quirks.as_deref(), Some("schip"));
+        assert_eq!(settings.ipf, Some(20));
+        let settings = config.settings_for(Path::new("other.ch8"));
+        assert_eq!(settings.quirks.as_deref(), Some("vip"));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Config::parse("quirk = \"vip\"\n").is_err());
+        assert!(Config::parse("[roms.\"game.ch8\"]\nspeed = 20\n").is_err());
+    }
+
+    #[test]
+    fn profiles_pick_quirks_and_variant() {
+        let settings = Settings { quirks: Some("schip".into()), ..Settings::default() };
+        let options = Options::resolve(&RunArgs::default(), &settings).unwrap();
+        assert_eq!(options.quirks, Quirks::SUPER_CHIP);
+        assert_eq!(options.variant, Variant::SuperChip);
+
+        let settings = Settings { quirks: Some("chip-9".into()), ..Settings::default() };
+        assert!(Options::resolve(&RunArgs::default(), &settings).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    io::{self, BufRead, Write},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use chip_8r::debugger::{self, Command, Debugger, Symbols};
+use chip_8r::machine::Machine;
+
+const PROMPT: &str = "(chip_8r) ";
+
+// Debugger driven from stdin. Lines are read on their own thread so the SDL
+// window keeps rendering while the terminal waits for input.
+pub struct Console {
+    debugger: Debugger,
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn new(machine: &Machine, symbols: Symbols) -> Console {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() { break; }
+            }
+        });
+
+        println!("Type `help` for a list of commands");
+        println!("{}", debugger::format_instruction(machine, machine.registers().pc));
+        prompt();
+
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols);
+
+        Console { debugger, lines }
+    }
+
+    pub fn is_paused(&self) -> bool { self.debugger.is_paused() }
+
+    // Handles pending commands, then runs a frame unless paused. With `wait`
+    // set, blocks for commands while paused instead of returning. Returns
+    // false once the user quits or stdin is closed.
+    pub fn update(&mut self, machine: &mut Machine, wait: bool) -> bool {
+        loop {
+            let line = if wait && self.debugger.is_paused() {
+                match self.lines.recv() {
+                    Ok(line) => line,
+                    Err(_) => return false,
+                }
+            } else {
+                match self.lines.try_recv() {
+                    Ok(line) => line,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return false,
+                }
+            };
+            if !self.handle_line(&line, machine) { return false; }
+        }
+
+        if let Some(stop) = self.debugger.run_frame(machine) {
+            println!("{}", self.debugger.describe_stop(stop, machine));
+            prompt();
+        }
+
+        true
+    }
+
+    fn handle_line(&mut self, line: &str, machine: &Machine) -> bool {
+        match self.debugger.parse_command(line) {
+            Ok(Command::Quit) => return false,
+            Ok(command) => {
+                let output = self.debugger.execute(command, machine);
+                if !output.is_empty() { println!("{}", output); }
+            },
+            Err(e) => println!("{}", e),
+        }
+
+        if self.debugger.is_paused() { prompt(); }
+        true
+    }
+}
+
+fn prompt() {
+    print!("{}", PROMPT);
+    io::stdout().flush().ok();
+}
@@ -1,34 +1,55 @@
 pub const FONT_RAM_START: usize = 0x000;
 pub const FONT_RAM_END: usize = FONT_RAM_START + (16 * 5);
 pub const FONT_HEIGHT: usize = 5;
+pub const BIG_FONT_RAM_START: usize = FONT_RAM_END;
+pub const BIG_FONT_RAM_END: usize = BIG_FONT_RAM_START + (16 * 10);
+pub const BIG_FONT_HEIGHT: usize = 10;
 pub const PROGRAM_RAM_START: usize = 0x200;
 pub const PROGRAM_RAM_END: usize = 0xe8f;
-pub const RAM_SIZE: u16 = 0x0fff;
+pub const RAM_SIZE: usize = 0x1000;
+pub const XO_RAM_SIZE: usize = 0x10000;
 
 pub const REGISTER_COUNT: u8 = 16;
 pub const FLAG_REGISTER: usize = 0xf;
 pub const STACK_SIZE: u8 = 8;
 pub const BIT_MASK: u8 = 0b10000000;
+pub const RPL_FLAG_COUNT: usize = 16;
 
 pub const WINDOW_TITLE: &str = "CHIP-8r";
 pub const DISPLAY_WIDTH: u32 = 64;
 pub const DISPLAY_HEIGHT: u32 = 32;
+pub const HIRES_WIDTH: u32 = 128;
+pub const HIRES_HEIGHT: u32 = 64;
+pub const SCROLL_COLS: usize = 4;
+pub const PLANE_COUNT: usize = 2;
 pub const PIXEL_SIZE: u32 = 15;
-pub const WINDOW_WIDTH: u32 = DISPLAY_WIDTH * PIXEL_SIZE;
-pub const WINDOW_HEIGHT: u32 = DISPLAY_HEIGHT * PIXEL_SIZE;
 pub const SPRITE_WIDTH: u8 = 8;
+pub const BIG_SPRITE_SIZE: u8 = 16;
 pub const BG_RED: u8 = 28;
 pub const BG_GREEN: u8 = 28;
 pub const BG_BLUE: u8 = 28;
 pub const FG_RED: u8 = 51;
 pub const FG_GREEN: u8 = 255;
 pub const FG_BLUE: u8 = 51;
+// XO-CHIP colors for pixels set only in plane 2, and in both planes
+pub const PLANE2_RED: u8 = 255;
+pub const PLANE2_GREEN: u8 = 136;
+pub const PLANE2_BLUE: u8 = 0;
+pub const BOTH_RED: u8 = 255;
+pub const BOTH_GREEN: u8 = 255;
+pub const BOTH_BLUE: u8 = 255;
 
-pub const MICROS_PER_FRAME: u128 = 1_000_000 / 60;
-pub const MICROS_PER_CYCLE: f32 = 1_000_000.0 / 700.0;
+pub const FRAME_RATE: u32 = 60;
+pub const MICROS_PER_FRAME: u128 = 1_000_000 / FRAME_RATE as u128;
+pub const DEFAULT_IPF: u32 = 12;
+pub const DEFAULT_REWIND_SECONDS: u32 = 10;
+pub const MAX_REWIND_SECONDS: u32 = 3600;
 
-pub const SAMPLE_RATE: f32 = 441000.0;
+pub const SAMPLE_RATE: f32 = 44100.0;
 pub const CHANNELS: u8 = 1;
-pub const WAVETABLE_SIZE: usize = 128;
-pub const PITCH: f32 = 330.0;
-pub const VOLUME: f32 = 0.25;
\ No newline at end of file
+pub const VOLUME: f32 = 0.25;
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+pub const AUDIO_PATTERN_BITS: usize = AUDIO_PATTERN_SIZE * 8;
+pub const DEFAULT_PITCH: u8 = 64;
+
+pub const DEFAULT_GDB_PORT: u16 = 2159;
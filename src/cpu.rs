@@ -1,389 +1,608 @@
-use std::thread;
-use std::time::{Instant, Duration};
-
-use crate::bus::Bus;
 use crate::constants::{
+    BIG_FONT_HEIGHT,
+    BIG_FONT_RAM_START,
+    BIG_SPRITE_SIZE,
     BIT_MASK,
-    DISPLAY_HEIGHT,
-    DISPLAY_WIDTH,
     FLAG_REGISTER,
+    SCROLL_COLS,
     SPRITE_WIDTH,
     FONT_HEIGHT,
     FONT_RAM_START,
-    MICROS_PER_FRAME, MICROS_PER_CYCLE
+    STACK_SIZE,
 };
 use crate::error::{Error, ErrorType};
-use crate::registers::Registers;
-
-pub struct Cpu {
-    bus: Bus,
-    registers: Registers,
-    frame_buffer: FrameBuffer,
-    opcode: u16,
-    halted: bool,
-    fps_timer: Instant,
+use crate::instruction::Instruction;
+use crate::machine::Machine;
+
+// Fetch and decode
+impl Machine {
+    pub(crate) fn fetch(&mut self) -> Result<Instruction, Error> {
+        let pc = self.registers.pc;
+        if pc < 0x200 || pc as usize + 1 >= self.bus.size() {
+            return Err(Error::new(ErrorType::PcOutOfRange { pc }));
+        }
+        self.opcode_addr = pc;
+        self.opcode = self.bus.read_word(pc);
+        self.registers.pc = pc.wrapping_add(2);
+
+        self.bus.decode(pc, self.variant).map_err(|_| self.invalid_opcode())
+    }
 }
 
-pub type FrameBuffer = [u8; (DISPLAY_WIDTH * DISPLAY_HEIGHT) as usize];
-    
-impl Cpu {
-    pub fn new() -> Result<Cpu, String> {
-        let mut bus = Bus::new()?;
-        bus.init_ram();
-        if bus.load_rom().is_err() {
-            return Err(String::from("Failed to load ROM"));
+// Instructions
+impl Machine {
+    pub(crate) fn execute(&mut self, instruction: Instruction) -> Result<(), Error> {
+        match instruction {
+            Instruction::Sys(_) => (),
+            Instruction::Cls => self.frame_buffer.clear(),
+            Instruction::Ret => {
+                if self.registers.sp == 0 {
+                    return Err(Error::new(ErrorType::StackUnderflow { addr: self.opcode_addr }));
+                }
+                self.registers.sp -= 1;
+                self.registers.pc = self.registers.stack[self.registers.sp];
+            },
+            Instruction::ScrollDown(n) => self.frame_buffer.scroll_down(n as usize),
+            Instruction::ScrollUp(n) => self.frame_buffer.scroll_up(n as usize),
+            Instruction::ScrollRight => self.frame_buffer.scroll_right(SCROLL_COLS),
+            Instruction::ScrollLeft => self.frame_buffer.scroll_left(SCROLL_COLS),
+            Instruction::Exit => self.exited = true,
+            Instruction::Low => self.frame_buffer.set_hires(false),
+            Instruction::High => self.frame_buffer.set_hires(true),
+            Instruction::Jp(nnn) => self.registers.pc = nnn,
+            Instruction::Call(nnn) => {
+                if self.registers.sp >= STACK_SIZE as usize {
+                    return Err(Error::new(ErrorType::StackOverflow { addr: self.opcode_addr }));
+                }
+                self.registers.stack[self.registers.sp] = self.registers.pc;
+                self.registers.sp += 1;
+                self.registers.pc = nnn;
+            },
+            Instruction::SeByte { x, kk } => {
+                if self.registers.v[x as usize] == kk { self.skip_next(); }
+            },
+            Instruction::SneByte { x, kk } => {
+                if self.registers.v[x as usize] != kk { self.skip_next(); }
+            },
+            Instruction::SeReg { x, y } => {
+                if self.registers.v[x as usize] == self.registers.v[y as usize] { self.skip_next(); }
+            },
+            Instruction::Save { x, y } => {
+                let range = register_range(x, y);
+                self.check_i(range.len())?;
+                let i = self.registers.i as usize;
+                for (offset, reg) in range.into_iter().enumerate() {
+                    self.bus.write_byte(i + offset, self.registers.v[reg]);
+                }
+            },
+            Instruction::Load { x, y } => {
+                let range = register_range(x, y);
+                self.check_i(range.len())?;
+                let i = self.registers.i;
+                for (offset, reg) in range.into_iter().enumerate() {
+                    self.registers.v[reg] = self.bus.read_byte(i + offset as u16);
+                }
+            },
+            Instruction::LdByte { x, kk } => self.registers.v[x as usize] = kk,
+            Instruction::AddByte { x, kk } => {
+                let value = self.registers.v[x as usize];
+                self.registers.v[x as usize] = value.wrapping_add(kk);
+            },
+            Instruction::LdReg { x, y } => self.registers.v[x as usize] = self.registers.v[y as usize],
+            Instruction::Or { x, y } => {
+                self.registers.v[x as usize] |= self.registers.v[y as usize];
+                if self.quirks.logic_reset_vf { self.registers.v[FLAG_REGISTER] = 0; }
+            },
+            Instruction::And { x, y } => {
+                self.registers.v[x as usize] &= self.registers.v[y as usize];
+                if self.quirks.logic_reset_vf { self.registers.v[FLAG_REGISTER] = 0; }
+            },
+            Instruction::Xor { x, y } => {
+                self.registers.v[x as usize] ^= self.registers.v[y as usize];
+                if self.quirks.logic_reset_vf { self.registers.v[FLAG_REGISTER] = 0; }
+            },
+            Instruction::AddReg { x, y } => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vx.wrapping_add(vy);
+                self.registers.v[FLAG_REGISTER] = (vx as u16 + vy as u16 > 0xff) as u8;
+            },
+            Instruction::Sub { x, y } => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vx.wrapping_sub(vy);
+                self.registers.v[FLAG_REGISTER] = (vx >= vy) as u8;
+            },
+            Instruction::Shr { x, y } => {
+                let value = self.shift_source(x, y);
+                self.registers.v[x as usize] = value >> 1;
+                self.registers.v[FLAG_REGISTER] = value & 1;
+            },
+            Instruction::Subn { x, y } => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vy.wrapping_sub(vx);
+                self.registers.v[FLAG_REGISTER] = (vy >= vx) as u8;
+            },
+            Instruction::Shl { x, y } => {
+                let value = self.shift_source(x, y);
+                self.registers.v[x as usize] = value << 1;
+                self.registers.v[FLAG_REGISTER] = (value & 0b10000000) >> 7;
+            },
+            Instruction::SneReg { x, y } => {
+                if self.registers.v[x as usize] != self.registers.v[y as usize] { self.skip_next(); }
+            },
+            Instruction::LdI(nnn) => self.registers.i = nnn,
+            Instruction::JpV0(nnn) => {
+                // With the quirk, BXNN jumps to XNN + VX
+                let offset = if self.quirks.jump_vx {
+                    self.registers.v[(nnn >> 8) as usize]
+                } else {
+                    self.registers.v[0]
+                };
+                self.registers.pc = offset as u16 + nnn;
+            },
+            Instruction::Rnd { x, kk } => {
+                self.registers.v[x as usize] = self.rng.next_u8() & kk;
+            },
+            Instruction::Drw { x, y, n } => self.draw(x, y, n)?,
+            Instruction::Skp(x) => {
+                if self.keypad.is_pressed(self.registers.v[x as usize]) { self.skip_next(); }
+            },
+            Instruction::Sknp(x) => {
+                if !self.keypad.is_pressed(self.registers.v[x as usize]) { self.skip_next(); }
+            },
+            Instruction::LdILong => {
+                let pc = self.registers.pc;
+                if pc as usize + 1 >= self.bus.size() {
+                    return Err(Error::new(ErrorType::PcOutOfRange { pc }));
+                }
+                self.registers.i = self.bus.read_word(pc);
+                self.registers.pc = pc.wrapping_add(2);
+            },
+            Instruction::Audio => {
+                self.check_i(self.audio_pattern.len())?;
+                for (j, byte) in self.audio_pattern.iter_mut().enumerate() {
+                    *byte = self.bus.read_byte(self.registers.i + j as u16);
+                }
+            },
+            Instruction::Plane(n) => self.frame_buffer.select_planes(n),
+            Instruction::LdVxDt(x) => self.registers.v[x as usize] = self.registers.dt,
+            Instruction::LdVxK(_) => {
+                self.halted = true;
+                self.wait_key = None;
+                self.held_keys = self.keypad.state();
+            },
+            Instruction::LdDtVx(x) => self.registers.dt = self.registers.v[x as usize],
+            Instruction::LdStVx(x) => self.registers.st = self.registers.v[x as usize],
+            Instruction::AddI(x) => {
+                self.registers.i = self.registers.i.wrapping_add(self.registers.v[x as usize] as u16);
+            },
+            Instruction::LdF(x) => {
+                let digit = self.registers.v[x as usize] & 0xf;
+                self.registers.i = digit as u16
+                    * FONT_HEIGHT as u16
+                    + FONT_RAM_START as u16;
+            },
+            Instruction::LdHf(x) => {
+                let digit = self.registers.v[x as usize] & 0xf;
+                self.registers.i = digit as u16
+                    * BIG_FONT_HEIGHT as u16
+                    + BIG_FONT_RAM_START as u16;
+            },
+            Instruction::LdB(x) => {
+                self.check_i(3)?;
+                let i = self.registers.i as usize;
+                let value = self.registers.v[x as usize];
+                self.bus.write_byte(i, value / 100);
+                self.bus.write_byte(i + 1, value % 100 / 10);
+                self.bus.write_byte(i + 2, value % 10);
+            },
+            Instruction::Pitch(x) => self.pitch = self.registers.v[x as usize],
+            Instruction::LdMemVx(x) => {
+                let x = x as usize;
+                self.check_i(x + 1)?;
+                let i = self.registers.i as usize;
+                for j in 0..=x {
+                    self.bus.write_byte(i + j, self.registers.v[j]);
+                }
+                // I wraps around the 64 KiB of XO-CHIP RAM, like in Octo
+                if self.quirks.memory_increment_i {
+                    self.registers.i = self.registers.i.wrapping_add(x as u16 + 1);
+                }
+            },
+            Instruction::LdVxMem(x) => {
+                let x = x as usize;
+                self.check_i(x + 1)?;
+                let i = self.registers.i;
+                for j in 0..=x {
+                    self.registers.v[j] = self.bus.read_byte(i + j as u16);
+                }
+                // I wraps around the 64 KiB of XO-CHIP RAM, like in Octo
+                if self.quirks.memory_increment_i {
+                    self.registers.i = self.registers.i.wrapping_add(x as u16 + 1);
+                }
+            },
+            Instruction::LdRVx(x) => {
+                let x = x as usize;
+                self.rpl[..=x].copy_from_slice(&self.registers.v[..=x]);
+            },
+            Instruction::LdVxR(x) => {
+                let x = x as usize;
+                self.registers.v[..=x].copy_from_slice(&self.rpl[..=x]);
+            },
         }
 
-        let buffer: FrameBuffer = [
-            0; (DISPLAY_WIDTH * DISPLAY_HEIGHT) as usize
-        ];
-
-        Ok(Cpu {
-            bus,
-            registers: Registers::new(),
-            frame_buffer: buffer,
-            opcode: 0x0000,
-            halted: false,
-            fps_timer: Instant::now(),
-        })
+        Ok(())
     }
 
-    pub fn run(&mut self) -> bool {
-        loop {
-            let cycle_timer = Instant::now();
+    // DRW Vx, Vy, n
+    fn draw(&mut self, x: u8, y: u8, n: u8) -> Result<(), Error> {
+        let width = self.frame_buffer.width();
+        let height = self.frame_buffer.height();
+        let x = self.registers.v[x as usize] as usize % width;
+        let y = self.registers.v[y as usize] as usize % height;
+        let i = self.registers.i;
 
-            if !self.bus.handle_input() { break; }
+        // DXY0 draws a 16x16 sprite on SUPER-CHIP, or 8x16 in low resolution
+        // with the quirk
+        let (sprite_width, rows) = match n {
+            0 if self.variant.has_super_chip() => {
+                let tall = self.quirks.lores_tall_sprites && !self.frame_buffer.is_hires();
+                (if tall { SPRITE_WIDTH } else { BIG_SPRITE_SIZE }, BIG_SPRITE_SIZE)
+            },
+            n => (SPRITE_WIDTH, n),
+        };
+        let bytes_per_row = (sprite_width / 8) as u16;
 
-            if self.halted {
-                if let Some(key) = self.bus.get_keyup() {
-                    self.registers.v[get_x(self.opcode)] = key;
-                    self.halted = false;
-                }
-            } else {
-                if let Some(_e) = self.fetch() {
-                    return false;
-                };
-                self.execute();
+        // Each selected XO-CHIP plane takes the next sprite's worth of bytes
+        let sprite_size = rows as u16 * bytes_per_row;
+        self.check_i(sprite_size as usize * self.frame_buffer.planes().count_ones() as usize)?;
+
+        self.registers.v[FLAG_REGISTER] = 0;
+
+        let selected = self.frame_buffer.planes();
+        for (index, plane) in [1, 2].into_iter().filter(|plane| selected & plane != 0).enumerate() {
+            let addr = i + index as u16 * sprite_size;
+            if self.draw_sprite(plane, x, y, addr, sprite_width, rows) {
+                self.registers.v[FLAG_REGISTER] = 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Draws one plane of a sprite, returning true on collision
+    fn draw_sprite(&mut self, plane: u8, x: usize, y: usize, addr: u16, sprite_width: u8, rows: u8) -> bool {
+        let width = self.frame_buffer.width();
+        let height = self.frame_buffer.height();
+        let bytes_per_row = (sprite_width / 8) as u16;
+        let mut collision = false;
+
+        for row in 0..rows {
+            let mut pixel_y = y + row as usize;
+            if pixel_y >= height {
+                if self.quirks.clip_sprites { break; }
+                pixel_y %= height;
             }
 
-            if self.fps_timer.elapsed().as_micros() >= MICROS_PER_FRAME {
-                self.bus.render(&self.frame_buffer);
-                
-                self.decrement_timers();
-                self.fps_timer = Instant::now();
+            // Sprite rows are left-aligned in 16 bits
+            let row_addr = addr + row as u16 * bytes_per_row;
+            let mut sprite = (self.bus.read_byte(row_addr) as u16) << 8;
+            if bytes_per_row == 2 {
+                sprite |= self.bus.read_byte(row_addr + 1) as u16;
             }
 
-            self.handle_audio();
+            for col in 0..sprite_width {
+                if sprite & ((BIT_MASK as u16) << 8 >> col) == 0 { continue; }
 
-            let diff = MICROS_PER_CYCLE - cycle_timer.elapsed().as_micros() as f32;
-            if diff > 0.0 {
-                thread::sleep(Duration::from_micros(diff as u64));
+                let mut pixel_x = x + col as usize;
+                if pixel_x >= width {
+                    if self.quirks.clip_sprites { break; }
+                    pixel_x %= width;
+                }
+
+                collision |= self.frame_buffer.toggle(pixel_x, pixel_y, plane);
             }
         }
-    
-        true
+
+        collision
     }
 
-    fn fetch(&mut self) -> Option<Error> {
-        if self.registers.pc < 0x200 || self.registers.pc >= 0xffe {
-            return Some(Error::new(ErrorType::InaccessibleMemoryAddress))
+    // Fails unless `len` bytes starting at I are in RAM
+    fn check_i(&self, len: usize) -> Result<(), Error> {
+        let i = self.registers.i;
+        if i as usize + len > self.bus.size() {
+            return Err(Error::new(ErrorType::IOutOfRange { i, addr: self.opcode_addr }));
         }
-        let high = self.bus.read_byte(self.registers.pc) as u16;
-        self.registers.pc += 1;
-        let low = self.bus.read_byte(self.registers.pc) as u16;
-        self.registers.pc += 1;
-
-        self.opcode = (high << 8) | low;
-
-        None
-    }
-
-    fn execute(&mut self) -> Option<Error> {
-        match (self.opcode & 0xf000) >> 12 {
-            0x0 => { self.opcode_0(self.opcode); },
-            0x1 => { self.opcode_1(self.opcode); },
-            0x2 => { self.opcode_2(self.opcode); },
-            0x3 => { self.opcode_3(self.opcode); },
-            0x4 => { self.opcode_4(self.opcode); },
-            0x5 => { self.opcode_5(self.opcode); },
-            0x6 => { self.opcode_6(self.opcode); },
-            0x7 => { self.opcode_7(self.opcode); },
-            0x8 => { self.opcode_8(self.opcode); },
-            0x9 => { self.opcode_9(self.opcode); },
-            0xa => { self.opcode_a(self.opcode); },
-            0xb => { self.opcode_b(self.opcode); },
-            0xc => { self.opcode_c(self.opcode); },
-            0xd => { self.opcode_d(self.opcode); },
-            0xe => { self.opcode_e(self.opcode); },
-            0xf => { self.opcode_f(self.opcode); },
-            _ => { Error::new(ErrorType::InvalidOpcode); }
-            
-        };
-    
-        None
+
+        Ok(())
     }
 
-    fn decrement_timers(&mut self) {
-        if self.registers.dt > 0 { self.registers.dt -= 1; }
-        if self.registers.st > 0 { self.registers.st -= 1; }
+    fn invalid_opcode(&self) -> Error {
+        Error::new(ErrorType::InvalidOpcode { opcode: self.opcode, addr: self.opcode_addr })
     }
 
-    fn handle_audio(&mut self) {
-        if self.registers.st > 0 { self.bus.play_audio(); }
-        else { self.bus.stop_audio(); }
+    // Skips the next instruction, which is 4 bytes long for XO-CHIP's LD I, long
+    fn skip_next(&mut self) {
+        let next = self.bus.read_word(self.registers.pc);
+        // Skipping past the end of RAM is caught by the next fetch
+        let len = if self.variant.has_xo_chip() && next == 0xf000 { 4 } else { 2 };
+        self.registers.pc = self.registers.pc.wrapping_add(len);
     }
-}
 
-// Instructions
-impl Cpu {
-    fn opcode_0(&mut self, opcode: u16) {
-        match opcode {
-            // CLS
-            0x00e0 => {
-                self.frame_buffer = [0; (DISPLAY_WIDTH * DISPLAY_HEIGHT) as usize];
-            },
-            // RET
-            0x00ee => {
-                self.registers.pc = self.registers.stack[self.registers.sp];
-                self.registers.sp -= 1;
-            }
-            // SYS
-            _ => ()
+    // Operand of SHR/SHL, which depends on the shift quirk
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_vy {
+            self.registers.v[y as usize]
+        } else {
+            self.registers.v[x as usize]
         }
     }
+}
 
-    // JMP nnn
-    fn opcode_1(&mut self, opcode: u16) {
-        self.registers.pc = opcode & 0x0fff;
+// Registers X to Y of 5XY2/5XY3, in descending order when X > Y
+fn register_range(x: u8, y: u8) -> Vec<usize> {
+    let (x, y) = (x as usize, y as usize);
+    if x <= y { (x..=y).collect() } else { (y..=x).rev().collect() }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::{
+        BIG_FONT_HEIGHT, BIG_FONT_RAM_START, PROGRAM_RAM_END, PROGRAM_RAM_START, STACK_SIZE,
+    };
+    use crate::error::{Error, ErrorType};
+    use crate::machine::Machine;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    fn xo_machine(rom: &[u8]) -> Machine {
+        let mut machine = Machine::new(Variant::XoChip, Quirks::XO_CHIP);
+        machine.load_rom(rom).unwrap();
+        machine
     }
 
-    // CALL nnn
-    fn opcode_2(&mut self, opcode: u16) {
-        self.registers.sp += 1;
-        self.registers.stack[self.registers.sp] = self.registers.pc;
-        self.registers.pc = opcode & 0x0fff;
+    #[test]
+    fn skip_at_the_end_of_ram_is_an_error() {
+        // JP 0xffd; SE V0, 0x30 at 0xffd
+        let mut machine = Machine::default();
+        machine.load_rom(&[0x1f, 0xfd]).unwrap();
+        machine.bus_mut().write_byte(0xffd, 0x30);
+        machine.bus_mut().write_byte(0xffe, 0x30);
+        machine.registers_mut().v[0] = 0x30;
+        machine.step().unwrap();
+        machine.step().unwrap();
+
+        let error = machine.step().unwrap_err();
+        assert_eq!(error.kind(), ErrorType::PcOutOfRange { pc: 0x1001 });
     }
 
-    // SE Vx, kk
-    fn opcode_3(&mut self, opcode: u16) {
-        if self.registers.v[get_x(opcode)] == get_kk(opcode) {
-            self.registers.pc += 2;
-        }
+    #[test]
+    fn self_modifying_code_runs_the_new_instruction() {
+        // LD VA, 1; LD V0, 5; LD I, 0x201; LD [I], V0; JP 0x200. The store
+        // changes only the low byte of the first instruction, to LD VA, 5.
+        let mut machine = Machine::default();
+        machine.load_rom(&[0x6a, 0x01, 0x60, 0x05, 0xa2, 0x01, 0xf0, 0x55, 0x12, 0x00]).unwrap();
+        for _ in 0..5 { machine.step().unwrap(); }
+        assert_eq!(machine.registers().v[0xa], 1);
+        assert_eq!(machine.registers().pc, 0x200);
+
+        machine.step().unwrap();
+        assert_eq!(machine.registers().v[0xa], 5);
     }
 
-    // SNE Vx, kk
-    fn opcode_4(&mut self, opcode: u16) {
-        if self.registers.v[get_x(opcode)] != get_kk(opcode) {
-            self.registers.pc += 2;
-        }
+    // Runs `rom` until it fails
+    fn error(rom: &[u8]) -> ErrorType {
+        let mut machine = Machine::default();
+        machine.load_rom(rom).unwrap();
+        (0..100).find_map(|_| machine.step().err()).expect("program fails").kind()
     }
 
-    // SE Vx, Vy
-    fn opcode_5(&mut self, opcode: u16) {
-        if self.registers.v[get_x(opcode)] == self.registers.v[get_y(opcode)] {
-            self.registers.pc += 2;
-        }
+    #[test]
+    fn calls_past_the_stack_overflow() {
+        // CALL 0x200, forever
+        assert_eq!(error(&[0x22, 0x00]), ErrorType::StackOverflow { addr: 0x200 });
+
+        // Eight levels are fine
+        let mut machine = Machine::default();
+        machine.load_rom(&[0x22, 0x00]).unwrap();
+        for _ in 0..STACK_SIZE { machine.step().unwrap(); }
+        assert_eq!(machine.registers().sp, STACK_SIZE as usize);
     }
 
-    // LD Vx
-    fn opcode_6(&mut self, opcode: u16) {
-        self.registers.v[get_x(opcode)] = get_kk(opcode);
-    }
-
-    // ADD Vx
-    fn opcode_7(&mut self, opcode: u16) {
-        let x = get_x(opcode) as u8;
-        let value = self.registers.v[x as usize];
-        self.registers.v[x as usize] = value.wrapping_add(get_kk(opcode));
-    }
-
-    // Bitwise, Carry Arithmetic Instructions
-    fn opcode_8(&mut self, opcode: u16) {
-        match opcode & 0x000f {
-            // LD Vx, Vy
-            0x0 => self.registers.v[get_x(opcode)] = self.registers.v[get_y(opcode)],
-            // OR Vx, Yy
-            0x1 => self.registers.v[get_x(opcode)] |= self.registers.v[get_y(opcode)],
-            // AND Vx, Vy
-            0x2 => self.registers.v[get_x(opcode)] &= self.registers.v[get_y(opcode)],
-            // XOR Vx, Vy
-            0x3 => self.registers.v[get_x(opcode)] ^= self.registers.v[get_y(opcode)],
-            // ADC Vx, Vy
-            0x4 => {
-                let x = self.registers.v[get_x(opcode)];
-                let y = self.registers.v[get_y(opcode)];
-                self.registers.v[get_x(opcode)] = x.wrapping_add(y);
-                if x as u16 + y as u16 > 0xff { self.registers.v[FLAG_REGISTER] = 1; }
-                else { self.registers.v[FLAG_REGISTER] = 0; }
-            },
-            // SUB Vx, Vy
-            0x5 => {
-                let x = self.registers.v[get_x(opcode)];
-                let y = self.registers.v[get_y(opcode)];
-                self.registers.v[get_x(opcode)] = x.wrapping_sub(y);
-                if x > y { self.registers.v[FLAG_REGISTER] = 1; }
-                else { self.registers.v[FLAG_REGISTER] = 0; }
-            },
-            // SHR Vx, Vy
-            0x6 => {
-                let y = self.registers.v[get_y(opcode)];
-                self.registers.v[get_x(opcode)] = y >> 1;
-                self.registers.v[FLAG_REGISTER] = y & 1;
-            },
-            // SUB Vy, Vx
-            0x7 => {
-                let x = self.registers.v[get_x(opcode)];
-                let y = self.registers.v[get_y(opcode)];
-                self.registers.v[get_x(opcode)] = y.wrapping_sub(x);
-                if y > x { self.registers.v[FLAG_REGISTER] = 1; }
-                else { self.registers.v[FLAG_REGISTER] = 0; }
-            },
-            // SHL Vx, Vy
-            0xe => {
-                let y = self.registers.v[get_y(opcode)];
-                self.registers.v[get_x(opcode)] = y << 1;
-                self.registers.v[FLAG_REGISTER] = (y & 0b10000000) >> 7;
-            },
-            _ => println!("Invalid opcode {:#X}", opcode),
-        }
+    #[test]
+    fn return_on_an_empty_stack_underflows() {
+        // CLS; RET
+        assert_eq!(error(&[0x00, 0xe0, 0x00, 0xee]), ErrorType::StackUnderflow { addr: 0x202 });
     }
 
-    fn opcode_9(&mut self, opcode: u16) {
-        if self.registers.v[get_x(opcode)] != self.registers.v[get_y(opcode)] {
-            self.registers.pc += 2;
-        }
+    #[test]
+    fn unknown_opcodes_are_invalid() {
+        // CLS; then 8XYF, which no variant has
+        let kind = error(&[0x00, 0xe0, 0x81, 0x2f]);
+        assert_eq!(kind, ErrorType::InvalidOpcode { opcode: 0x812f, addr: 0x202 });
+        assert_eq!(Error::new(kind).to_string(), "invalid opcode 0x812f at 0x0202");
+
+        // XO-CHIP's SAVE isn't CHIP-8
+        assert_eq!(error(&[0x51, 0x22]), ErrorType::InvalidOpcode { opcode: 0x5122, addr: 0x200 });
     }
 
-    // LD I
-    fn opcode_a(&mut self, opcode: u16) {
-        self.registers.i = opcode & get_nnn(opcode);
+    #[test]
+    fn memory_access_past_ram_is_an_error() {
+        // LD I, 0xffe; LD B, V0
+        assert_eq!(error(&[0xaf, 0xfe, 0xf0, 0x33]), ErrorType::IOutOfRange { i: 0xffe, addr: 0x202 });
     }
 
-    // JP V0, nnn
-    fn opcode_b(&mut self, opcode: u16) {
-        self.registers.pc = self.registers.v[0] as u16 + get_nnn(opcode);
+    #[test]
+    fn roms_must_fit_in_program_memory() {
+        // The top of a 4 KiB machine is the interpreter's
+        let max = PROGRAM_RAM_END - PROGRAM_RAM_START;
+        let mut machine = Machine::default();
+        machine.load_rom(&vec![0; max]).unwrap();
+        let error = machine.load_rom(&vec![0; max + 1]).unwrap_err();
+        assert_eq!(error.kind(), ErrorType::RomTooLarge { size: max + 1, max });
     }
 
-    // RND Vx, kk
-    fn opcode_c(&mut self, opcode: u16) {
-        let rnd = rand::random::<u8>();
-        self.registers.v[get_x(opcode)] = rnd & get_kk(opcode);
+    // LD I, 0x206; DRW V0, V0, 0; then 32 bytes of 0xff
+    fn draw_big_sprite(quirks: Quirks) -> Machine {
+        let mut rom = vec![0xa2, 0x06, 0xd0, 0x00, 0x00, 0x00];
+        rom.extend([0xff; 32]);
+        let mut machine = Machine::new(Variant::SuperChip, quirks);
+        machine.load_rom(&rom).unwrap();
+        machine.step().unwrap();
+        machine.step().unwrap();
+        machine
     }
 
-    // DRW
-    fn opcode_d(&mut self, opcode: u16) {
-        let x = self.registers.v[get_x(opcode)] % DISPLAY_WIDTH as u8;
-        let y = self.registers.v[get_y(opcode)] % DISPLAY_HEIGHT as u8;
-        let i = self.registers.i;
+    fn lit_pixels(machine: &Machine) -> usize {
+        machine.frame_buffer().pixels().iter().filter(|pixel| **pixel != 0).count()
+    }
 
-        self.registers.v[FLAG_REGISTER] = 0;
+    #[test]
+    fn lores_big_sprites_are_8x16_with_the_quirk() {
+        assert_eq!(lit_pixels(&draw_big_sprite(Quirks::SUPER_CHIP)), 8 * 16);
+    }
 
-        for row in 0..get_n(opcode) {
-            let sprite = self.bus.read_byte(i + row as u16);
-            for col in 0..(SPRITE_WIDTH) {
-                let pixel_x = x + col;
-                if pixel_x >= DISPLAY_WIDTH as u8 { break; };
-
-                let pixel_y = y + row;
-                if pixel_y >= DISPLAY_HEIGHT as u8 { break; };
-
-                let pixel_idx = 
-                    pixel_y as usize * DISPLAY_WIDTH as usize + pixel_x as usize;
-                let current_pixel = self.frame_buffer[pixel_idx];
-                let new_pixel = (sprite & (BIT_MASK >> col)) >>
-                    ((SPRITE_WIDTH - 1) - col);
-                self.frame_buffer[pixel_idx] ^= new_pixel;
-                if current_pixel == 1 && self.frame_buffer[pixel_idx] == 1 {
-                    self.registers.v[FLAG_REGISTER] = 1;
-                }
-            }
-        }
+    #[test]
+    fn lores_big_sprites_are_16x16_without_the_quirk() {
+        let quirks = Quirks { lores_tall_sprites: false, ..Quirks::SUPER_CHIP };
+        assert_eq!(lit_pixels(&draw_big_sprite(quirks)), 16 * 16);
     }
 
-    fn opcode_e(&mut self, opcode: u16) {
-        let x = self.registers.v[get_x(opcode)];
-        match get_kk(opcode) {
-            // SKP Vx
-            0x9e => {
-                if self.bus.is_pressed(x) {
-                    self.registers.pc += 2;
-                }
-            },
-            // SKNP Vx
-            0xa1 => {
-                if !self.bus.is_pressed(x) {
-                    self.registers.pc += 2;
-                }
-            },
-            _ => println!("Invalid opcode {:#X}", opcode),
+    #[test]
+    fn save_wraps_i_at_the_top_of_ram() {
+        // LD I, long 0xfff0; LD [I], VF
+        let mut machine = xo_machine(&[0xf0, 0x00, 0xff, 0xf0, 0xff, 0x55]);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers().i, 0x0000);
+    }
+
+    #[test]
+    fn long_load_at_the_top_of_ram_wraps_pc() {
+        // LD I, long 0x1234 in the last four bytes of RAM
+        let mut machine = xo_machine(&[]);
+        for (offset, byte) in [0xf0, 0x00, 0x12, 0x34].into_iter().enumerate() {
+            machine.bus_mut().write_byte(0xfffc + offset, byte);
         }
+        machine.registers_mut().pc = 0xfffc;
+        machine.step().unwrap();
+        assert_eq!(machine.registers().i, 0x1234);
+        assert_eq!(machine.registers().pc, 0x0000);
     }
 
-    fn opcode_f(&mut self, opcode: u16) {
-        match get_kk(opcode) {
-            // LD Vx, DT
-            0x07 => {
-                self.registers.v[get_x(opcode)] = self.registers.dt;
-            },
-            // LD Vx, K
-            0x0a => {
-                self.halted = true;
-            },
-            // LD DT, Vx
-            0x15 => {
-                self.registers.dt = self.registers.v[get_x(opcode)];
-            },
-            // LD ST, Vx
-            0x18 => {
-                self.registers.st = self.registers.v[get_x(opcode)];
-            },
-            // ADD I, Vx
-            0x1e => {
-                self.registers.i += self.registers.v[get_x(opcode)] as u16;
-            },
-            // LD F, Vx
-            0x29 => {
-                let x = self.registers.v[get_x(opcode)] & 0xf;
-                self.registers.i = x as u16 
-                    * FONT_HEIGHT as u16
-                    + FONT_RAM_START as u16;
-            },
-            // LD BCD, Vx
-            0x33 => {
-                let i = self.registers.i as usize;
-                let x = self.registers.v[get_x(opcode)];
-                self.bus.write_byte(i, x / 100);
-                self.bus.write_byte(i + 1, x % 100 / 10);
-                self.bus.write_byte(i + 2, x % 10);
-            },
-            // LD [I], Vx
-            0x55 => {
-                let i = self.registers.i as usize;
-                for j in 0..=get_x(opcode) {
-                    self.bus.write_byte(i + j, self.registers.v[j]);
-                }
-            },
-            // LD Vx, [I]
-            0x65 => {
-                let i = self.registers.i;
-                for j in 0..=get_x(opcode) {
-                    self.registers.v[j] = self.bus.read_byte(i + j as u16);
-                }
-            },
-            _ => {
-                println!("Invalid opcode {:#X}", opcode);
-            }
+    #[test]
+    fn load_wraps_i_at_the_top_of_ram() {
+        // LD I, long 0xfff0; LD VF, [I]
+        let mut machine = xo_machine(&[0xf0, 0x00, 0xff, 0xf0, 0xff, 0x65]);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers().i, 0x0000);
+    }
 
-        }
+    #[test]
+    fn add_i_wraps() {
+        // LD I, long 0xffff; LD V0, 2; ADD I, V0
+        let mut machine = xo_machine(&[0xf0, 0x00, 0xff, 0xff, 0x60, 0x02, 0xf0, 0x1e]);
+        for _ in 0..3 { machine.step().unwrap(); }
+        assert_eq!(machine.registers().i, 0x0001);
     }
-}
 
-fn get_x(opcode: u16) -> usize { ((opcode & 0x0f00) >> 8) as usize }
+    #[test]
+    fn draw_reads_the_last_sprite_byte_of_ram() {
+        // LD I, long 0xfff1; DRW V0, V0, 15
+        let mut machine = xo_machine(&[0xf0, 0x00, 0xff, 0xf1, 0xd0, 0x0f]);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers().v[0xf], 0);
+    }
+
+    #[test]
+    fn draw_takes_each_plane_from_its_own_bytes() {
+        // PLANE 3; LD I, 0x208; DRW V0, V0, 1; bytes 0x80 (plane 1) and 0x40 (plane 2)
+        let mut machine = xo_machine(&[0xf3, 0x01, 0xa2, 0x08, 0xd0, 0x01, 0x00, 0x00, 0x80, 0x40]);
+        for _ in 0..3 { machine.step().unwrap(); }
+        assert_eq!(&machine.frame_buffer().pixels()[..2], &[1, 2]);
+    }
+
+    // Runs `steps` instructions of a SUPER-CHIP program
+    fn schip_machine(rom: &[u8], steps: usize) -> Machine {
+        let mut machine = Machine::new(Variant::SuperChip, Quirks::SUPER_CHIP);
+        machine.load_rom(rom).unwrap();
+        for _ in 0..steps { machine.step().unwrap(); }
+        machine
+    }
+
+    fn lit(machine: &Machine) -> Vec<(usize, usize)> {
+        let frame_buffer = machine.frame_buffer();
+        (0..frame_buffer.height())
+            .flat_map(|y| (0..frame_buffer.width()).map(move |x| (x, y)))
+            .filter(|(x, y)| frame_buffer.get(*x, *y) != 0)
+            .collect()
+    }
+
+    #[test]
+    fn hires_sprites_are_16x16() {
+        // HIGH; LD I, 0x208; DRW V0, V0, 0; JP 0x206; then 32 bytes of 0xff
+        let mut rom = vec![0x00, 0xff, 0xa2, 0x08, 0xd0, 0x00, 0x12, 0x06];
+        rom.extend([0xff; 32]);
+        let machine = schip_machine(&rom, 3);
+        assert_eq!((machine.frame_buffer().width(), machine.frame_buffer().height()), (128, 64));
+        assert_eq!(lit(&machine).len(), 16 * 16);
+        assert_eq!(machine.frame_buffer().get(15, 15), 1);
+        assert_eq!(machine.frame_buffer().get(16, 0), 0);
+    }
 
-fn get_y(opcode: u16) -> usize { ((opcode & 0x00f0) >> 4) as usize }
+    #[test]
+    fn switching_resolution_clears_the_screen() {
+        // HIGH; LD I, 0x20a; DRW V0, V0, 1; LOW; JP 0x208; then a sprite row
+        let machine = schip_machine(&[0x00, 0xff, 0xa2, 0x0a, 0xd0, 0x01, 0x00, 0xfe, 0x12, 0x08, 0x80], 4);
+        assert_eq!((machine.frame_buffer().width(), machine.frame_buffer().height()), (64, 32));
+        assert!(lit(&machine).is_empty());
+    }
 
-fn get_n(opcode: u16) -> u8 { (opcode & 0x000f) as u8 }
+    #[test]
+    fn scrolls_the_screen() {
+        // HIGH; V0 := 10; LD I, 0x212; DRW V0, V0, 1; SCD 3; SCR; SCR; SCL;
+        // JP 0x210; then a one pixel sprite row
+        let rom = [
+            0x00, 0xff, 0x60, 0x0a, 0xa2, 0x12, 0xd0, 0x01, 0x00, 0xc3,
+            0x00, 0xfb, 0x00, 0xfb, 0x00, 0xfc, 0x12, 0x10, 0x80,
+        ];
+        assert_eq!(lit(&schip_machine(&rom, 4)), [(10, 10)]);
+        assert_eq!(lit(&schip_machine(&rom, 5)), [(10, 13)]);
+        assert_eq!(lit(&schip_machine(&rom, 7)), [(18, 13)]);
+        assert_eq!(lit(&schip_machine(&rom, 8)), [(14, 13)]);
+    }
 
-fn get_kk(opcode: u16) -> u8 { (opcode & 0x00ff) as u8 }
+    #[test]
+    fn big_font_digits() {
+        // V0 := 9; LD HF, V0
+        let machine = schip_machine(&[0x60, 0x09, 0xf0, 0x30], 2);
+        let i = machine.registers().i;
+        assert_eq!(i as usize, BIG_FONT_RAM_START + 9 * BIG_FONT_HEIGHT);
+        assert!((0..BIG_FONT_HEIGHT as u16).any(|row| machine.bus().read_byte(i + row) != 0));
+    }
 
-fn get_nnn(opcode: u16) -> u16 { opcode & 0x0fff }
\ No newline at end of file
+    #[test]
+    fn flags_keep_registers() {
+        // V0 := 1; V1 := 2; V2 := 3; LD R, V1; V0 := 0; V1 := 0; V2 := 0; LD V2, R
+        let rom = [
+            0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0xf1, 0x75,
+            0x60, 0x00, 0x61, 0x00, 0x62, 0x00, 0xf2, 0x85,
+        ];
+        let machine = schip_machine(&rom, 8);
+        assert_eq!(machine.registers().v[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn exit_stops_the_program() {
+        // EXIT; V0 := 1
+        let mut machine = schip_machine(&[0x00, 0xfd, 0x60, 0x01], 1);
+        assert!(machine.has_exited());
+        machine.step().unwrap();
+        assert_eq!(machine.registers().pc, 0x202);
+        assert_eq!(machine.registers().v[0], 0);
+    }
+}
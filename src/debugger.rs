@@ -0,0 +1,511 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::constants::REGISTER_COUNT;
+use crate::disasm;
+use crate::error::Error;
+use crate::instruction::{decode_for, Instruction};
+use crate::machine::Machine;
+
+// Instructions shown by `list`
+const LISTING_LENGTH: u16 = 10;
+
+const HELP: &str = "\
+break <addr>     b     pause before the instruction at <addr>
+delete [<addr>]  d     remove a breakpoint, or all of them
+breakpoints      bl    list breakpoints
+step [<n>]       s     run <n> instructions (default 1)
+next             n     step, running through CALLs
+continue         c     run until a breakpoint
+finish           fin   run until the current subroutine returns
+pause            p     stop a running program
+regs             r     show registers
+stack            bt    show the call stack
+mem <addr> [<n>] x     show <n> bytes of memory (default 16)
+list [<addr>]    l     disassemble from <addr> (default PC)
+help             h     show this help
+quit             q     exit
+
+Addresses are hex with a 0x or $ prefix, decimal, or a label from the
+program's source. An empty line repeats the last command.";
+
+// A range of memory printed whenever the debugger stops
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Monitor {
+    pub name: String,
+    pub addr: u16,
+    pub len: u16,
+}
+
+// Names from a program's source: labels, plus breakpoints and monitors to
+// set up when debugging starts
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    pub labels: HashMap<String, u16>,
+    pub breakpoints: Vec<(String, u16)>,
+    pub monitors: Vec<Monitor>,
+}
+
+impl Symbols {
+    // Label at `addr`, the first alphabetically when there are several
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels.iter()
+            .filter(|(_, label_addr)| **label_addr == addr)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    fn breakpoint_at(&self, addr: u16) -> Option<&str> {
+        self.breakpoints.iter()
+            .find(|(_, breakpoint_addr)| *breakpoint_addr == addr)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Break(u16),
+    Delete(Option<u16>),
+    Breakpoints,
+    Step(u32),
+    Next,
+    Continue,
+    Finish,
+    Pause,
+    Registers,
+    Stack,
+    Memory { addr: u16, len: u16 },
+    List(Option<u16>),
+    Help,
+    Quit,
+}
+
+// Why the machine stopped running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    Step(u16),
+    Error(Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    Running,
+    // Instructions left to step
+    Stepping(u32),
+    // Run until the CALL being stepped over returns to `pc`
+    StepOver { pc: u16, sp: usize },
+    // Run until the stack is shallower than `sp`
+    Finish { sp: usize },
+}
+
+// Breakpoints and stepping for a `Machine`. Commands come in as text and
+// output goes out as text, so any frontend can put it on a terminal.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    mode: Mode,
+    last_command: Option<Command>,
+    // Resuming from a breakpoint must not hit it again straight away
+    resuming: bool,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    // Starts paused, before the first instruction
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Paused,
+            last_command: None,
+            resuming: true,
+            symbols: Symbols::default(),
+        }
+    }
+
+    // Uses the names from a program's source, setting its breakpoints
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.breakpoints.extend(symbols.breakpoints.iter().map(|(_, addr)| *addr));
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols { &self.symbols }
+
+    pub fn is_paused(&self) -> bool { self.mode == Mode::Paused }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+        self.resuming = true;
+    }
+
+    pub fn parse_command(&self, line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return self.last_command.clone().ok_or_else(|| "no command to repeat".into()),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let command = match name {
+            "break" | "b" => Command::Break(self.parse_address(arg(&args, 0)?)?),
+            "delete" | "d" => match args.first() {
+                Some(addr) => Command::Delete(Some(self.parse_address(addr)?)),
+                None => Command::Delete(None),
+            },
+            "breakpoints" | "bl" => Command::Breakpoints,
+            "step" | "s" => match args.first() {
+                Some(count) => Command::Step(parse_number(count)?),
+                None => Command::Step(1),
+            },
+            "next" | "n" => Command::Next,
+            "continue" | "c" => Command::Continue,
+            "finish" | "fin" => Command::Finish,
+            "pause" | "p" => Command::Pause,
+            "regs" | "r" => Command::Registers,
+            "stack" | "bt" => Command::Stack,
+            "mem" | "x" => Command::Memory {
+                addr: self.parse_address(arg(&args, 0)?)?,
+                len: match args.get(1) {
+                    Some(len) => parse_number(len)?,
+                    None => 16,
+                },
+            },
+            "list" | "l" => match args.first() {
+                Some(addr) => Command::List(Some(self.parse_address(addr)?)),
+                None => Command::List(None),
+            },
+            "help" | "h" | "?" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => return Err(format!("unknown command `{}`, try `help`", name)),
+        };
+
+        Ok(command)
+    }
+
+    pub fn parse_address(&self, text: &str) -> Result<u16, String> {
+        match self.symbols.labels.get(text) {
+            Some(addr) => Ok(*addr),
+            None => parse_number(text),
+        }
+    }
+
+    // Runs a command and returns its output. Commands that resume the
+    // machine only change the mode; `run_frame` does the running.
+    pub fn execute(&mut self, command: Command, machine: &Machine) -> String {
+        self.last_command = Some(command.clone());
+        let pc = machine.registers().pc;
+
+        match command {
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+                format!("Breakpoint at {:#06x}", addr)
+            },
+            Command::Delete(Some(addr)) => {
+                if self.breakpoints.remove(&addr) {
+                    format!("Deleted breakpoint at {:#06x}", addr)
+                } else {
+                    format!("No breakpoint at {:#06x}", addr)
+                }
+            },
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                "Deleted all breakpoints".into()
+            },
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() { return "No breakpoints".into(); }
+                self.breakpoints.iter()
+                    .map(|addr| match self.symbols.breakpoint_at(*addr) {
+                        Some(name) => format!("{:#06x} {}", addr, name),
+                        None => format!("{:#06x}", addr),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            },
+            Command::Step(count) => {
+                self.mode = Mode::Stepping(count.max(1));
+                String::new()
+            },
+            Command::Next => {
+                let bus = machine.bus();
+                let call = (pc as usize + 1) < bus.size()
+                    && matches!(decode_for(bus.read_word(pc), machine.variant()), Ok(Instruction::Call(_)));
+                if call {
+                    self.mode = Mode::StepOver { pc: pc.wrapping_add(2), sp: machine.registers().sp };
+                } else {
+                    self.mode = Mode::Stepping(1);
+                }
+                String::new()
+            },
+            Command::Continue => {
+                self.mode = Mode::Running;
+                String::new()
+            },
+            Command::Finish => {
+                let sp = machine.registers().sp;
+                if sp == 0 { return "Not in a subroutine".into(); }
+                self.mode = Mode::Finish { sp };
+                String::new()
+            },
+            Command::Pause => {
+                self.pause();
+                format_instruction(machine, pc)
+            },
+            Command::Registers => format_registers(machine),
+            Command::Stack => format_stack(machine),
+            Command::Memory { addr, len } => format_memory(machine, addr, len),
+            Command::List(addr) => self.format_listing(machine, addr.unwrap_or(pc)),
+            Command::Help => HELP.into(),
+            Command::Quit => String::new(),
+        }
+    }
+
+    // Runs the machine to the end of the frame, unless something stops it
+    // first. Returns why it stopped, after which the debugger is paused.
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Option<Stop> {
+        if self.is_paused() { return None; }
+
+        loop {
+            let pc = machine.registers().pc;
+            if !self.resuming && self.breakpoints.contains(&pc) {
+                return self.stop(Stop::Breakpoint(pc));
+            }
+            self.resuming = false;
+
+            let end_of_frame = match machine.step() {
+                Ok(end_of_frame) => end_of_frame,
+                Err(e) => return self.stop(Stop::Error(e)),
+            };
+
+            let registers = machine.registers();
+            let done = match &mut self.mode {
+                Mode::Stepping(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                Mode::StepOver { pc, sp } => registers.pc == *pc && registers.sp == *sp,
+                Mode::Finish { sp } => registers.sp < *sp,
+                Mode::Running | Mode::Paused => false,
+            };
+            if done {
+                return self.stop(Stop::Step(registers.pc));
+            }
+
+            if end_of_frame { return None; }
+        }
+    }
+
+    fn stop(&mut self, stop: Stop) -> Option<Stop> {
+        self.mode = Mode::Paused;
+        self.resuming = true;
+        Some(stop)
+    }
+
+    // What stopped the machine, followed by the monitored memory
+    pub fn describe_stop(&self, stop: Stop, machine: &Machine) -> String {
+        let mut out = match stop {
+            Stop::Breakpoint(addr) => match self.symbols.breakpoint_at(addr) {
+                Some(name) => format!("Breakpoint {} at {}", name, format_instruction(machine, addr)),
+                None => format!("Breakpoint at {}", format_instruction(machine, addr)),
+            },
+            Stop::Step(addr) => format_instruction(machine, addr),
+            Stop::Error(e) => format!("Stopped on error: {}", e),
+        };
+
+        let bus = machine.bus();
+        for monitor in &self.symbols.monitors {
+            let end = (monitor.addr as usize + monitor.len as usize).min(bus.size());
+            let bytes: Vec<String> = (monitor.addr as usize..end)
+                .map(|addr| format!("{:02x}", bus.read_byte(addr as u16)))
+                .collect();
+            write!(out, "\n{}: {}", monitor.name, bytes.join(" ")).unwrap();
+        }
+
+        out
+    }
+
+    // Disassembly with a `name:` line before each labelled address
+    fn format_listing(&self, machine: &Machine, addr: u16) -> String {
+        let mut out = Vec::new();
+        let lines = disasm::disassemble_range(machine.bus(), addr, LISTING_LENGTH * 4, machine.variant());
+        for line in lines.iter().take(LISTING_LENGTH as usize) {
+            if let Some(label) = self.symbols.label_at(line.addr) {
+                out.push(format!("{}:", label));
+            }
+            out.push(line.to_string());
+        }
+        out.join("\n")
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self { Debugger::new() }
+}
+
+// Address, raw bytes and disassembly of an instruction
+pub fn format_instruction(machine: &Machine, addr: u16) -> String {
+    disasm::disassemble_range(machine.bus(), addr, 4, machine.variant())
+        .first()
+        .map_or_else(|| format!("{:#06x}: out of memory", addr), |line| line.to_string())
+}
+
+fn format_registers(machine: &Machine) -> String {
+    let r = machine.registers();
+    let mut out = format!(
+        "PC {:#06x}  I {:#06x}  SP {}  DT {}  ST {}\n",
+        r.pc, r.i, r.sp, r.dt, r.st
+    );
+    for reg in 0..REGISTER_COUNT as usize {
+        let separator = if reg % 8 == 7 { "\n" } else { "  " };
+        write!(out, "V{:X} {:02x}{}", reg, r.v[reg], separator).unwrap();
+    }
+    out.trim_end().into()
+}
+
+fn format_stack(machine: &Machine) -> String {
+    let r = machine.registers();
+    if r.sp == 0 { return "Stack is empty".into(); }
+
+    // Innermost call first
+    (0..r.sp).rev()
+        .map(|level| format!("#{} return to {:#06x}", r.sp - 1 - level, r.stack[level]))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_memory(machine: &Machine, addr: u16, len: u16) -> String {
+    let bus = machine.bus();
+    let end = (addr as usize + len as usize).min(bus.size());
+    let mut out = String::new();
+
+    for row in (addr as usize..end).step_by(16) {
+        write!(out, "{:#06x}:", row).unwrap();
+        for byte_addr in row..(row + 16).min(end) {
+            write!(out, " {:02x}", bus.read_byte(byte_addr as u16)).unwrap();
+        }
+        out.push('\n');
+    }
+    out.trim_end().into()
+}
+
+fn arg<'a>(args: &[&'a str], index: usize) -> Result<&'a str, String> {
+    args.get(index).copied().ok_or_else(|| "missing argument".into())
+}
+
+// Numbers too big for the type they're parsed into are errors, rather
+// than being cut down to an unrelated value
+fn parse_number<T: TryFrom<u64>>(text: &str) -> Result<T, String> {
+    let hex = text.strip_prefix("0x").or_else(|| text.strip_prefix('$'));
+    let result = match hex {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => text.parse::<u64>(),
+    };
+    let value = result.map_err(|_| format!("invalid number `{}`", text))?;
+    T::try_from(value).map_err(|_| format!("`{}` is out of range", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    // CALL 0x208; LD V0, 2; JP 0x204; then at 0x208: LD V1, 1; LD V2, 1; RET
+    const PROGRAM: [u8; 14] = [
+        0x22, 0x08, 0x60, 0x02, 0x12, 0x04, 0x00, 0x00,
+        0x61, 0x01, 0x62, 0x01, 0x00, 0xee,
+    ];
+
+    fn machine() -> Machine {
+        let mut machine = Machine::default();
+        machine.load_rom(&PROGRAM).unwrap();
+        machine
+    }
+
+    // Runs `command` and then frames until the debugger stops the machine
+    fn run(debugger: &mut Debugger, machine: &mut Machine, command: Command) -> Stop {
+        debugger.execute(command, machine);
+        (0..10).find_map(|_| debugger.run_frame(machine)).expect("machine stops")
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let debugger = Debugger::new();
+        assert_eq!(debugger.parse_command("b 0x2a4"), Ok(Command::Break(0x2a4)));
+        assert_eq!(debugger.parse_command("b $2a4"), Ok(Command::Break(0x2a4)));
+        assert_eq!(debugger.parse_command("b 512"), Ok(Command::Break(0x200)));
+    }
+
+    #[test]
+    fn rejects_out_of_range_addresses() {
+        let debugger = Debugger::new();
+        assert_eq!(debugger.parse_command("b 0x10200"), Err("`0x10200` is out of range".into()));
+        assert!(debugger.parse_command("x 0x200 0x10000").is_err());
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let mut debugger = Debugger::new();
+        let mut symbols = Symbols::default();
+        symbols.labels.insert("main".into(), 0x202);
+        debugger.set_symbols(symbols);
+        assert_eq!(debugger.parse_command("b main"), Ok(Command::Break(0x202)));
+    }
+
+    #[test]
+    fn steps_into_calls() {
+        let (mut debugger, mut machine) = (Debugger::new(), machine());
+        assert_eq!(debugger.run_frame(&mut machine), None);
+        assert_eq!(run(&mut debugger, &mut machine, Command::Step(1)), Stop::Step(0x208));
+        assert_eq!(run(&mut debugger, &mut machine, Command::Step(2)), Stop::Step(0x20c));
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn next_runs_through_calls() {
+        let (mut debugger, mut machine) = (Debugger::new(), machine());
+        assert_eq!(run(&mut debugger, &mut machine, Command::Next), Stop::Step(0x202));
+        assert_eq!(machine.registers().v[1], 1);
+        assert_eq!(run(&mut debugger, &mut machine, Command::Next), Stop::Step(0x204));
+    }
+
+    #[test]
+    fn next_over_a_call_at_the_top_of_ram() {
+        let mut machine = Machine::new(Variant::XoChip, Quirks::XO_CHIP);
+        machine.load_rom(&PROGRAM).unwrap();
+        machine.bus_mut().write_byte(0xfffe, 0x22);
+        machine.bus_mut().write_byte(0xffff, 0x08);
+        machine.registers_mut().pc = 0xfffe;
+
+        let mut debugger = Debugger::new();
+        assert_eq!(run(&mut debugger, &mut machine, Command::Next), Stop::Step(0x0000));
+    }
+
+    #[test]
+    fn finish_returns_from_the_subroutine() {
+        let (mut debugger, mut machine) = (Debugger::new(), machine());
+        assert_eq!(debugger.execute(Command::Finish, &machine), "Not in a subroutine");
+        run(&mut debugger, &mut machine, Command::Step(2));
+        assert_eq!(run(&mut debugger, &mut machine, Command::Finish), Stop::Step(0x202));
+        assert_eq!(machine.registers().sp, 0);
+    }
+
+    #[test]
+    fn continues_to_breakpoints() {
+        let (mut debugger, mut machine) = (Debugger::new(), machine());
+        debugger.execute(Command::Break(0x20a), &machine);
+        assert_eq!(run(&mut debugger, &mut machine, Command::Continue), Stop::Breakpoint(0x20a));
+        assert_eq!(machine.registers().v[1], 1);
+        assert_eq!(machine.registers().v[2], 0);
+
+        // Resuming doesn't stop at the same breakpoint again
+        debugger.execute(Command::Continue, &machine);
+        assert_eq!(debugger.run_frame(&mut machine), None);
+        assert_eq!(machine.registers().pc, 0x204);
+
+        debugger.execute(Command::Break(0x204), &machine);
+        assert_eq!(debugger.run_frame(&mut machine), Some(Stop::Breakpoint(0x204)));
+        debugger.execute(Command::Delete(None), &machine);
+        debugger.execute(Command::Continue, &machine);
+        assert_eq!(debugger.run_frame(&mut machine), None);
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::constants::PROGRAM_RAM_START;
+use crate::disasm;
+use crate::instruction::{decode_for, Instruction};
+use crate::variant::Variant;
+
+// Bytes of plain data per line
+const DATA_PER_LINE: usize = 8;
+
+// Bytes in the 16x16 sprite drawn by `sprite vx vy 0`
+const BIG_SPRITE_SIZE: usize = 32;
+
+// Addresses spanned by a loop or block, and the one or two ranges of code
+// inside it that other blocks can go in
+struct Nest {
+    range: Range<usize>,
+    parts: [Range<usize>; 2],
+}
+
+impl Nest {
+    fn new(range: Range<usize>, first: Range<usize>, second: Range<usize>) -> Nest {
+        Nest { range, parts: [first, second] }
+    }
+
+    // Whether this fits within one of the parts of `outer`, or doesn't
+    // overlap it at all
+    fn nests_in(&self, outer: &Nest) -> bool {
+        let inner = &self.range;
+        inner.end <= outer.range.start || outer.range.end <= inner.start
+            || outer.parts.iter().any(|part| part.start <= inner.start && inner.end <= part.end)
+    }
+}
+
+// What a label points at, in order of preference when an address is used in
+// several ways
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Data,
+    Sprite,
+    Table,
+    Label,
+    Sub,
+    Main,
+}
+
+// Decompiles a ROM loaded at 0x200 into Octo source. Code is found the same
+// way the disassembler finds it; bytes drawn by `sprite` are shown in binary,
+// backward jumps become `loop ... again` and skips over forward jumps become
+// `if ... begin ... else ... end`, where the blocks nest.
+pub fn decompile(rom: &[u8], variant: Variant) -> String {
+    Decompiler::new(rom, variant).source()
+}
+
+struct Decompiler<'a> {
+    rom: &'a [u8],
+    // Instructions by address, with the address loaded by `i := long`
+    code: BTreeMap<usize, (Instruction, u16)>,
+    // Bytes that belong to instructions
+    covered: BTreeSet<usize>,
+    labels: BTreeMap<usize, Kind>,
+    sprites: BTreeSet<usize>,
+    // Backward jumps turned into `again`, and where their loop starts
+    loops: BTreeMap<usize, usize>,
+    // Skips followed by a jump out of the loop, shown as `while`
+    whiles: BTreeSet<usize>,
+    // Skips followed by a jump past a block, shown as `if ... begin`; the
+    // jumps over an `else` part, and how many blocks end at each address
+    begins: BTreeSet<usize>,
+    elses: BTreeSet<usize>,
+    ends: BTreeMap<usize, usize>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(rom: &'a [u8], variant: Variant) -> Decompiler<'a> {
+        let base = PROGRAM_RAM_START;
+        let mut code = BTreeMap::new();
+        let mut covered = BTreeSet::new();
+        for addr in disasm::find_code(rom, base, variant) {
+            let word = word_at(rom, addr).expect("reachable code is in the ROM");
+            let instruction = decode_for(word, variant).expect("reachable code decodes");
+            let long = word_at(rom, addr + 2).unwrap_or(0);
+            covered.extend(addr..addr + instruction.size() as usize);
+            code.insert(addr, (instruction, long));
+        }
+
+        let mut decompiler = Decompiler {
+            rom,
+            code,
+            covered,
+            labels: BTreeMap::new(),
+            sprites: BTreeSet::new(),
+            loops: BTreeMap::new(),
+            whiles: BTreeSet::new(),
+            begins: BTreeSet::new(),
+            elses: BTreeSet::new(),
+            ends: BTreeMap::new(),
+        };
+        decompiler.find_sprites();
+        decompiler.find_labels();
+        decompiler.find_loops();
+        decompiler.find_whiles();
+        decompiler.find_blocks();
+        decompiler
+    }
+
+    // Follows I through straight-line code to see which bytes `sprite` draws
+    fn find_sprites(&mut self) {
+        // Code reached from elsewhere may start with any I. Loops jumping
+        // back usually keep it, so backward jumps are not counted.
+        let entries: BTreeSet<usize> = self.code.iter()
+            .filter_map(|(&addr, (instruction, _))| match instruction {
+                Instruction::Jp(nnn) if *nnn as usize <= addr => None,
+                Instruction::Jp(nnn) | Instruction::Call(nnn) | Instruction::JpV0(nnn) => Some(*nnn as usize),
+                _ => None,
+            })
+            .collect();
+
+        let mut i = None;
+        let mut sprites = BTreeSet::new();
+        for (&addr, &(instruction, long)) in &self.code {
+            if entries.contains(&addr) { i = None; }
+
+            match instruction {
+                Instruction::LdI(nnn) => i = Some(nnn as usize),
+                Instruction::LdILong => i = Some(long as usize),
+                Instruction::Drw { n, .. } => if let Some(i) = i {
+                    let len = if n == 0 { BIG_SPRITE_SIZE } else { n as usize };
+                    sprites.extend(i..i + len);
+                },
+                // These leave I somewhere it can't be followed
+                Instruction::AddI(_) | Instruction::LdF(_) | Instruction::LdHf(_)
+                    | Instruction::LdMemVx(_) | Instruction::LdVxMem(_)
+                    | Instruction::Load { .. } | Instruction::Save { .. } | Instruction::Call(_)
+                    | Instruction::Ret | Instruction::Jp(_) | Instruction::JpV0(_)
+                    | Instruction::Exit => i = None,
+                _ => {},
+            }
+        }
+
+        self.sprites = sprites.into_iter()
+            .filter(|addr| self.in_rom(*addr) && !self.covered.contains(addr))
+            .collect();
+    }
+
+    fn find_labels(&mut self) {
+        let mut labels = BTreeMap::new();
+        labels.insert(PROGRAM_RAM_START, Kind::Main);
+
+        for &(instruction, long) in self.code.values() {
+            let (target, kind) = match instruction {
+                Instruction::Jp(nnn) => (nnn, Kind::Label),
+                Instruction::Call(nnn) => (nnn, Kind::Sub),
+                Instruction::JpV0(nnn) => (nnn, Kind::Table),
+                Instruction::LdI(nnn) => (nnn, Kind::Data),
+                Instruction::LdILong => (long, Kind::Data),
+                _ => continue,
+            };
+            let target = target as usize;
+            if !self.is_line_start(target) { continue; }
+
+            let kind = if kind == Kind::Data && self.sprites.contains(&target) { Kind::Sprite } else { kind };
+            let best = labels.entry(target).or_insert(kind);
+            *best = (*best).max(kind);
+        }
+
+        self.labels = labels;
+    }
+
+    // A backward jump to a label nothing else uses is a loop, as long as it
+    // nests with the loops already found
+    fn find_loops(&mut self) {
+        let mut uses: HashMap<usize, usize> = HashMap::new();
+        for &(instruction, long) in self.code.values() {
+            if let Some(target) = reference(instruction, long) {
+                *uses.entry(target).or_default() += 1;
+            }
+        }
+
+        let mut loops: BTreeMap<usize, usize> = BTreeMap::new();
+        for (&end, &(instruction, _)) in &self.code {
+            let Instruction::Jp(start) = instruction else { continue };
+            let start = start as usize;
+            let candidate = start <= end
+                && self.labels.get(&start) == Some(&Kind::Label)
+                && self.code.contains_key(&start)
+                && uses.get(&start) == Some(&1);
+            let nests = loops.iter().all(|(&other_end, &other_start)| {
+                other_end < start || (start <= other_start && other_end <= end)
+            });
+
+            if candidate && nests {
+                loops.insert(end, start);
+            }
+        }
+
+        for start in loops.values() {
+            self.labels.remove(start);
+        }
+        self.loops = loops;
+    }
+
+    // A skip followed by a jump to just past the innermost loop's `again`
+    // is `while`. The label after the loop goes if only these jumps use it.
+    fn find_whiles(&mut self) {
+        let mut exits: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (&addr, &(instruction, _)) in &self.code {
+            if !instruction.is_skip() { continue; }
+            let jump = addr + 2;
+            let Some((Instruction::Jp(target), _)) = self.code.get(&jump) else { continue };
+            let innermost = self.loops.iter()
+                .filter(|(&end, &start)| start <= addr && jump < end)
+                .max_by_key(|(_, &start)| start);
+            let Some((&end, _)) = innermost else { continue };
+
+            if *target as usize == end + 2 && !self.labels.contains_key(&jump) {
+                exits.entry(end + 2).or_default().push(addr);
+            }
+        }
+
+        for (target, skips) in exits {
+            let uses = self.code.values()
+                .filter(|&&(instruction, long)| reference(instruction, long) == Some(target))
+                .count();
+            if uses == skips.len() {
+                self.labels.remove(&target);
+                self.whiles.extend(skips);
+            }
+        }
+    }
+
+    // A skip followed by a forward jump is `if ... begin`, the way Octo
+    // compiles it. When the jump lands just past another forward jump, that
+    // one skips an `else` part. Blocks must nest with each other and with
+    // loops, and their labels go if only these jumps use them.
+    fn find_blocks(&mut self) {
+        let mut nested: Vec<Nest> = self.loops.iter()
+            .map(|(&end, &start)| Nest::new(start..end + 2, start..end, end..end))
+            .collect();
+        let mut jumps: BTreeMap<usize, usize> = BTreeMap::new();
+
+        // Inner blocks first, so an `if` that ends at an `else` is found
+        // before the jump it ends at is checked
+        let skips: Vec<usize> = self.code.iter().rev()
+            .filter(|(addr, (instruction, _))| instruction.is_skip() && !self.whiles.contains(addr))
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in skips {
+            let jump = addr + 2;
+            let Some(target) = self.forward_jump(jump) else { continue };
+            if !self.is_block_end(target) { continue; }
+
+            let mut block = Nest::new(addr..target, addr + 4..target, target..target);
+            let mut else_jump = None;
+            let before = target - 2;
+            let is_else = before > jump
+                && !self.code.get(&(before - 2)).is_some_and(|(other, _)| other.is_skip());
+            if let Some(end) = self.forward_jump(before).filter(|_| is_else) {
+                if self.is_block_end(end) {
+                    block = Nest::new(addr..end, addr + 4..before, target..end);
+                    else_jump = Some(before);
+                }
+            }
+            if !nested.iter().all(|other| block.nests_in(other) || other.nests_in(&block)) {
+                continue;
+            }
+
+            self.begins.insert(addr);
+            let mut targets = vec![target];
+            if let Some(before) = else_jump {
+                self.elses.insert(before);
+                targets.push(block.range.end);
+            }
+            for target in targets {
+                let count = jumps.entry(target).or_default();
+                *count += 1;
+                let uses = self.code.values()
+                    .filter(|&&(instruction, long)| reference(instruction, long) == Some(target))
+                    .count();
+                if uses == *count { self.labels.remove(&target); }
+            }
+            *self.ends.entry(block.range.end).or_default() += 1;
+            nested.push(block);
+        }
+    }
+
+    // Where a jump at `addr` goes, if it goes forward and has no label, so
+    // it can be folded into the statement before it
+    fn forward_jump(&self, addr: usize) -> Option<usize> {
+        let Some(&(Instruction::Jp(target), _)) = self.code.get(&addr) else { return None };
+        let target = target as usize;
+        (target > addr && !self.labels.contains_key(&addr)).then_some(target)
+    }
+
+    fn is_block_end(&self, addr: usize) -> bool {
+        addr == PROGRAM_RAM_START + self.rom.len() || self.is_line_start(addr)
+    }
+
+    fn source(&self) -> String {
+        let loop_starts: BTreeSet<usize> = self.loops.values().copied().collect();
+        let mut out = String::new();
+        let mut depth = 1;
+
+        let mut addr = PROGRAM_RAM_START;
+        let end = PROGRAM_RAM_START + self.rom.len();
+        while addr < end {
+            for _ in 0..self.ends.get(&addr).copied().unwrap_or(0) {
+                depth -= 1;
+                indent(&mut out, depth, "end");
+            }
+            if let Some(&kind) = self.labels.get(&addr) {
+                if kind == Kind::Sub && !out.is_empty() { out.push('\n'); }
+                writeln!(out, ": {}", self.label_name(addr, kind)).unwrap();
+            }
+            if loop_starts.contains(&addr) {
+                indent(&mut out, depth, "loop");
+                depth += 1;
+            }
+
+            if let Some(&(instruction, long)) = self.code.get(&addr) {
+                if self.loops.contains_key(&addr) {
+                    depth -= 1;
+                    indent(&mut out, depth, "again");
+                } else if self.whiles.contains(&addr) {
+                    indent(&mut out, depth, &format!("while {}", condition(instruction, false)));
+                    // The jump out of the loop is part of the `while`
+                    addr += 2;
+                } else if self.begins.contains(&addr) {
+                    indent(&mut out, depth, &format!("if {} begin", condition(instruction, false)));
+                    depth += 1;
+                    addr += 2;
+                } else if self.elses.contains(&addr) {
+                    indent(&mut out, depth - 1, "else");
+                } else {
+                    indent(&mut out, depth, &self.statement(instruction, long));
+                }
+                addr += instruction.size() as usize;
+            } else {
+                let len = self.data_len(addr, end);
+                let bytes = &self.rom[addr - PROGRAM_RAM_START..addr - PROGRAM_RAM_START + len];
+                let text: Vec<String> = if self.sprites.contains(&addr) {
+                    bytes.iter().map(|byte| format!("0b{:08b}", byte)).collect()
+                } else {
+                    bytes.iter().map(|byte| format!("{:#04x}", byte)).collect()
+                };
+                indent(&mut out, depth, &text.join(" "));
+                addr += len;
+            }
+        }
+        for _ in 0..self.ends.get(&end).copied().unwrap_or(0) {
+            depth -= 1;
+            indent(&mut out, depth, "end");
+        }
+
+        out
+    }
+
+    // Data bytes for one line: one sprite row, or plain bytes up to the next
+    // label, instruction or sprite
+    fn data_len(&self, start: usize, end: usize) -> usize {
+        if self.sprites.contains(&start) { return 1; }
+
+        (start..end)
+            .take(DATA_PER_LINE)
+            .enumerate()
+            .take_while(|&(index, addr)| {
+                index == 0 || !(self.labels.contains_key(&addr)
+                    || self.code.contains_key(&addr)
+                    || self.sprites.contains(&addr)
+                    || self.ends.contains_key(&addr))
+            })
+            .count()
+    }
+
+    fn statement(&self, instruction: Instruction, long: u16) -> String {
+        use Instruction::*;
+
+        match instruction {
+            Sys(nnn) => format!("native {:#05x}", nnn),
+            Cls => "clear".into(),
+            Ret => "return".into(),
+            ScrollDown(n) => format!("scroll-down {}", n),
+            ScrollUp(n) => format!("scroll-up {}", n),
+            ScrollRight => "scroll-right".into(),
+            ScrollLeft => "scroll-left".into(),
+            Exit => "exit".into(),
+            Low => "lores".into(),
+            High => "hires".into(),
+            Jp(nnn) => format!("jump {}", self.target(nnn)),
+            // Octo calls by name, so unlabelled calls are written as bytes
+            Call(nnn) if !self.labels.contains_key(&(nnn as usize)) => {
+                let [high, low] = instruction.encode().to_be_bytes();
+                format!("{:#04x} {:#04x} # call {:#05x}", high, low, nnn)
+            },
+            Call(nnn) => self.target(nnn),
+            // Skips run the next statement only when the opposite holds
+            SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } | Skp(_) | Sknp(_) =>
+                format!("if {} then", condition(instruction, true)),
+            Save { x, y } => format!("save v{:x} - v{:x}", x, y),
+            Load { x, y } => format!("load v{:x} - v{:x}", x, y),
+            LdByte { x, kk } => format!("v{:x} := {:#04x}", x, kk),
+            AddByte { x, kk } => format!("v{:x} += {:#04x}", x, kk),
+            LdReg { x, y } => format!("v{:x} := v{:x}", x, y),
+            Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            AddReg { x, y } => format!("v{:x} += v{:x}", x, y),
+            Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            LdI(nnn) => format!("i := {}", self.target(nnn)),
+            JpV0(nnn) => format!("jump0 {}", self.target(nnn)),
+            Rnd { x, kk } => format!("v{:x} := random {:#04x}", x, kk),
+            Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            LdILong => format!("i := long {}", self.target(long)),
+            Audio => "audio".into(),
+            Plane(n) => format!("plane {}", n),
+            LdVxDt(x) => format!("v{:x} := delay", x),
+            LdVxK(x) => format!("v{:x} := key", x),
+            LdDtVx(x) => format!("delay := v{:x}", x),
+            LdStVx(x) => format!("buzzer := v{:x}", x),
+            AddI(x) => format!("i += v{:x}", x),
+            LdF(x) => format!("i := hex v{:x}", x),
+            LdHf(x) => format!("i := bighex v{:x}", x),
+            LdB(x) => format!("bcd v{:x}", x),
+            Pitch(x) => format!("pitch := v{:x}", x),
+            LdMemVx(x) => format!("save v{:x}", x),
+            LdVxMem(x) => format!("load v{:x}", x),
+            LdRVx(x) => format!("saveflags v{:x}", x),
+            LdVxR(x) => format!("loadflags v{:x}", x),
+        }
+    }
+
+    // Label for an address, or the number when there is none
+    fn target(&self, addr: u16) -> String {
+        match self.labels.get(&(addr as usize)) {
+            Some(&kind) => self.label_name(addr as usize, kind),
+            None => format!("{:#05x}", addr),
+        }
+    }
+
+    fn label_name(&self, addr: usize, kind: Kind) -> String {
+        let prefix = match kind {
+            Kind::Main => return "main".into(),
+            Kind::Sub => "sub",
+            Kind::Label => "label",
+            Kind::Table => "table",
+            Kind::Sprite => "sprite",
+            Kind::Data => "data",
+        };
+        format!("{}-{:x}", prefix, addr)
+    }
+
+    fn in_rom(&self, addr: usize) -> bool {
+        (PROGRAM_RAM_START..PROGRAM_RAM_START + self.rom.len()).contains(&addr)
+    }
+
+    // Labels can only go before an instruction or a data byte
+    fn is_line_start(&self, addr: usize) -> bool {
+        self.in_rom(addr) && (self.code.contains_key(&addr) || !self.covered.contains(&addr))
+    }
+}
+
+// Address an instruction refers to
+fn reference(instruction: Instruction, long: u16) -> Option<usize> {
+    match instruction {
+        Instruction::Jp(nnn) | Instruction::Call(nnn) | Instruction::JpV0(nnn)
+            | Instruction::LdI(nnn) => Some(nnn as usize),
+        Instruction::LdILong => Some(long as usize),
+        _ => None,
+    }
+}
+
+// The condition a skip instruction tests, or its opposite
+fn condition(instruction: Instruction, negate: bool) -> String {
+    let (equal, not_equal) = if negate { ("!=", "==") } else { ("==", "!=") };
+    let (pressed, released) = if negate { ("-key", "key") } else { ("key", "-key") };
+
+    match instruction {
+        Instruction::SeByte { x, kk } => format!("v{:x} {} {:#04x}", x, equal, kk),
+        Instruction::SneByte { x, kk } => format!("v{:x} {} {:#04x}", x, not_equal, kk),
+        Instruction::SeReg { x, y } => format!("v{:x} {} v{:x}", x, equal, y),
+        Instruction::SneReg { x, y } => format!("v{:x} {} v{:x}", x, not_equal, y),
+        Instruction::Skp(x) => format!("v{:x} {}", x, pressed),
+        Instruction::Sknp(x) => format!("v{:x} {}", x, released),
+        _ => unreachable!("{} is not a skip", instruction),
+    }
+}
+
+fn indent(out: &mut String, depth: usize, text: &str) {
+    writeln!(out, "{}{}", "  ".repeat(depth), text).unwrap();
+}
+
+fn word_at(rom: &[u8], addr: usize) -> Option<u16> {
+    let offset = addr - PROGRAM_RAM_START;
+    Some(u16::from_be_bytes([*rom.get(offset)?, *rom.get(offset + 1)?]))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octo;
+
+    fn round_trip(rom: &[u8]) -> String {
+        let source = decompile(rom, Variant::Chip8);
+        assert_eq!(octo::compile(&source).unwrap().rom, rom, "{}", source);
+        source
+    }
+
+    #[test]
+    fn recompiles_to_the_same_rom() {
+        round_trip(&[0x00, 0xe0, 0x63, 0x12, 0xd0, 0x15, 0xa2, 0x0e, 0x50, 0x10, 0x12, 0x00, 0xb2, 0x00, 0xff, 0x81]);
+    }
+
+    #[test]
+    fn recovers_blocks() {
+        let source = "\
+: main
+  v3 := 0x00
+  loop
+    v3 += 0x01
+    while v3 != 0x1e
+  again
+  if v3 == 0x1f begin
+    v5 := 0x01
+    if v4 == 0x00 begin
+      v6 := 0xff
+    end
+  else
+    v5 := 0x02
+  end
+  if v3 == 0x00 begin
+    v6 := 0xff
+  end
+";
+        let rom = octo::compile(source).unwrap().rom;
+        assert_eq!(round_trip(&rom), source);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::bus::Bus;
+use crate::constants::PROGRAM_RAM_START;
+use crate::instruction::{decode_for, Instruction};
+use crate::variant::Variant;
+
+// Data bytes shown per `db` line
+const DATA_PER_LINE: usize = 4;
+
+// One disassembled instruction, or a run of bytes that isn't code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "{:#06x}  {:<11}  {}", self.addr, bytes.join(" "), self.text)
+    }
+}
+
+// Disassembles a ROM loaded at 0x200. Only bytes reachable from the entry
+// point by following jumps, calls and skips are decoded as code; the rest is
+// shown as data.
+pub fn disassemble(rom: &[u8], variant: Variant) -> Vec<Line> {
+    let base = PROGRAM_RAM_START;
+    let code = find_code(rom, base, variant);
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let addr = base + offset;
+        if code.contains(&addr) {
+            let (instruction, text) = decode_at(rom, base, addr, variant)
+                .expect("reachable code decodes");
+            let len = instruction.size() as usize;
+            lines.push(Line { addr: addr as u16, bytes: rom[offset..offset + len].to_vec(), text });
+            offset += len;
+        } else {
+            // Data runs up to the next instruction
+            let len = (offset..rom.len())
+                .take(DATA_PER_LINE)
+                .take_while(|offset| !code.contains(&(base + offset)))
+                .count();
+            lines.push(data_line(addr as u16, &rom[offset..offset + len]));
+            offset += len;
+        }
+    }
+
+    lines
+}
+
+// Disassembles `len` bytes of memory from `start`, decoding every word in
+// turn as an instruction. Words that aren't valid instructions are data.
+pub fn disassemble_range(bus: &Bus, start: u16, len: u16, variant: Variant) -> Vec<Line> {
+    let start = start as usize;
+    let end = (start + len as usize).min(bus.size());
+    let memory: Vec<u8> = (start..end).map(|addr| bus.read_byte(addr as u16)).collect();
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < memory.len() {
+        let addr = start + offset;
+        match decode_at(&memory, start, addr, variant) {
+            Some((instruction, text)) => {
+                let len = instruction.size() as usize;
+                lines.push(Line { addr: addr as u16, bytes: memory[offset..offset + len].to_vec(), text });
+                offset += len;
+            },
+            None => {
+                let len = (memory.len() - offset).min(2);
+                lines.push(data_line(addr as u16, &memory[offset..offset + len]));
+                offset += len;
+            },
+        }
+    }
+
+    lines
+}
+
+// Instruction at `addr` of `memory`, which starts at address `base`, with
+// its assembly text
+fn decode_at(memory: &[u8], base: usize, addr: usize, variant: Variant) -> Option<(Instruction, String)> {
+    let instruction = decode_for(word_at(memory, base, addr)?, variant).ok()?;
+    let text = match instruction {
+        Instruction::LdILong => format!("LD I, long {:#06x}", word_at(memory, base, addr + 2)?),
+        _ => instruction.to_string(),
+    };
+
+    Some((instruction, text))
+}
+
+fn word_at(memory: &[u8], base: usize, addr: usize) -> Option<u16> {
+    let offset = addr.checked_sub(base)?;
+    let high = *memory.get(offset)?;
+    let low = *memory.get(offset + 1)?;
+    Some((high as u16) << 8 | low as u16)
+}
+
+// Start addresses of every instruction reachable from `base`
+pub(crate) fn find_code(memory: &[u8], base: usize, variant: Variant) -> BTreeSet<usize> {
+    let mut code = BTreeSet::new();
+    // Bytes covered by the instructions found so far
+    let mut covered = BTreeSet::new();
+    let mut pending = vec![base];
+
+    while let Some(addr) = pending.pop() {
+        let Some((instruction, _)) = decode_at(memory, base, addr, variant) else { continue };
+        let next = addr + instruction.size() as usize;
+        if (addr..next).any(|byte| covered.contains(&byte)) { continue; }
+
+        code.insert(addr);
+        covered.extend(addr..next);
+
+        match instruction {
+            Instruction::Ret | Instruction::Exit => {},
+            Instruction::Jp(nnn) => pending.push(nnn as usize),
+            Instruction::Call(nnn) => pending.extend([nnn as usize, next]),
+            // The target of a computed jump is usually a table of jumps
+            Instruction::JpV0(nnn) => pending.push(nnn as usize),
+            // Skips may land after the next instruction, which can be long
+            _ if instruction.is_skip() => {
+                let skipped = match decode_at(memory, base, next, variant) {
+                    Some((next_instruction, _)) => next + next_instruction.size() as usize,
+                    None => next + 2,
+                };
+                pending.extend([next, skipped]);
+            },
+            _ => pending.push(next),
+        }
+    }
+
+    code
+}
+
+fn data_line(addr: u16, bytes: &[u8]) -> Line {
+    let values: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+    Line { addr, bytes: bytes.to_vec(), text: format!("db {}", values.join(", ")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::RAM_SIZE;
+
+    // 0x200 calls a subroutine, skips over a load, jumps over a data word and
+    // ends in a loop; the bytes after the loop are never reached
+    const ROM: [u8; 19] = [
+        0x22, 0x08, // 0x200: CALL 0x208
+        0x30, 0x01, // 0x202: SE V0, 1
+        0x12, 0x0c, // 0x204: JP 0x20c
+        0x60, 0x05, // 0x206: LD V0, 5
+        0x00, 0xee, // 0x208: RET
+        0xab, 0xcd, // 0x20a: data
+        0x12, 0x0c, // 0x20c: JP 0x20c
+        0xde, 0xad, 0xbe, 0xef, 0x01, // 0x20e: data
+    ];
+
+    #[test]
+    fn follows_jumps_calls_and_skips() {
+        let code = find_code(&ROM, PROGRAM_RAM_START, Variant::Chip8);
+        let expected = BTreeSet::from([0x200, 0x202, 0x204, 0x206, 0x208, 0x20c]);
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn unreachable_bytes_are_data() {
+        let lines = disassemble(&ROM, Variant::Chip8);
+        let listing: Vec<(u16, &str)> = lines.iter().map(|line| (line.addr, line.text.as_str())).collect();
+        assert_eq!(listing, [
+            (0x200, "CALL 0x208"),
+            (0x202, "SE V0, 0x01"),
+            (0x204, "JP 0x20c"),
+            (0x206, "LD V0, 0x05"),
+            (0x208, "RET"),
+            (0x20a, "db 0xab, 0xcd"),
+            (0x20c, "JP 0x20c"),
+            (0x20e, "db 0xde, 0xad, 0xbe, 0xef"),
+            (0x212, "db 0x01"),
+        ]);
+        assert_eq!(lines[5].bytes, [0xab, 0xcd]);
+        assert_eq!(lines[0].to_string(), "0x0200  22 08        CALL 0x208");
+    }
+
+    #[test]
+    fn skips_step_over_long_loads() {
+        let rom = [
+            0x30, 0x00, // 0x200: SE V0, 0
+            0xf0, 0x00, 0x12, 0x34, // 0x202: LD I, long 0x1234
+            0x00, 0xfd, // 0x206: EXIT
+            0x00, 0xfd, // 0x208: data
+        ];
+        let lines = disassemble(&rom, Variant::XoChip);
+        let listing: Vec<(u16, &str)> = lines.iter().map(|line| (line.addr, line.text.as_str())).collect();
+        assert_eq!(listing, [
+            (0x200, "SE V0, 0x00"),
+            (0x202, "LD I, long 0x1234"),
+            (0x206, "EXIT"),
+            (0x208, "db 0x00, 0xfd"),
+        ]);
+    }
+
+    #[test]
+    fn range_decodes_every_word() {
+        let mut bus = Bus::new(RAM_SIZE);
+        for (offset, byte) in ROM.iter().enumerate() {
+            bus.write_byte(PROGRAM_RAM_START + offset, *byte);
+        }
+        let lines = disassemble_range(&bus, 0x208, 6, Variant::Chip8);
+        let listing: Vec<(u16, &str)> = lines.iter().map(|line| (line.addr, line.text.as_str())).collect();
+        // Without tracing, the data word at 0x20a is decoded too
+        assert_eq!(listing, [(0x208, "RET"), (0x20a, "LD I, 0xbcd"), (0x20c, "JP 0x20c")]);
+    }
+}
@@ -6,26 +6,27 @@ use sdl2::render::Canvas;
 use sdl2::Sdl;
 use sdl2::video::Window;
 
-use crate::cpu::FrameBuffer;
-use crate::constants::{
+use chip_8r::framebuffer::FrameBuffer;
+use chip_8r::constants::{
+    DISPLAY_HEIGHT,
     DISPLAY_WIDTH,
-    WINDOW_WIDTH,
-    WINDOW_HEIGHT,
     WINDOW_TITLE,
-    PIXEL_SIZE, FG_RED, FG_GREEN, FG_BLUE, BG_RED, BG_GREEN, BG_BLUE
 };
+use chip_8r::palette::{Palette, Rgb};
 
 pub struct Display {
     canvas: Canvas<Window>,
+    pixel_size: u32,
+    palette: Palette,
 }
 impl Display {
-    pub fn new(sdl_context: &Sdl) -> Result<Display, String> {
+    pub fn new(sdl_context: &Sdl, pixel_size: u32, palette: Palette) -> Result<Display, String> {
         let video_subsystem = sdl_context.video()?;
 
         let window = video_subsystem.window(
             WINDOW_TITLE,
-            WINDOW_WIDTH,
-            WINDOW_HEIGHT)
+            DISPLAY_WIDTH * pixel_size,
+            DISPLAY_HEIGHT * pixel_size)
             .position_centered()
             .build()
             .map_err(|e| e.to_string())?;
@@ -35,26 +36,38 @@ impl Display {
             .build()
             .map_err(|e| e.to_string())?;
 
-        canvas.set_draw_color(Color::RGB(BG_RED, BG_GREEN, BG_BLUE));
+        canvas.set_draw_color(to_color(palette.color(0)));
         canvas.clear();
         canvas.present();
 
-        Ok(Display { canvas })
+        Ok(Display { canvas, pixel_size, palette })
     }
 
     pub fn render(&mut self, buffer: &FrameBuffer) {
         self.clear_canvas();
 
-        self.canvas.set_draw_color(Color::RGB(FG_RED, FG_GREEN, FG_BLUE));
-        for (i, pixel) in buffer.iter().enumerate() {
-            if *pixel == 1 {
-                let x = (i as i32 % DISPLAY_WIDTH as i32) * PIXEL_SIZE as i32;
-                let y = (i as i32 / DISPLAY_WIDTH as i32) * PIXEL_SIZE as i32;
+        // The window keeps its lores size, so hires pixels are drawn at
+        // half the size. Edges are computed per pixel so any scale fits.
+        let window_width = DISPLAY_WIDTH * self.pixel_size;
+        let window_height = DISPLAY_HEIGHT * self.pixel_size;
+        let width = buffer.width() as u32;
+        let height = buffer.height() as u32;
+
+        // One pass per color: plane 1, plane 2, both planes
+        for value in 1..=3 {
+            self.canvas.set_draw_color(to_color(self.palette.color(value)));
+            for (i, pixel) in buffer.pixels().iter().enumerate() {
+                if *pixel != value { continue; }
+
+                let col = i as u32 % width;
+                let row = i as u32 / width;
+                let x = col * window_width / width;
+                let y = row * window_height / height;
                 let rect = Rect::new(
-                    x,
-                    y,
-                    PIXEL_SIZE,
-                    PIXEL_SIZE,
+                    x as i32,
+                    y as i32,
+                    (col + 1) * window_width / width - x,
+                    (row + 1) * window_height / height - y,
                 );
                 self.canvas.fill_rect(rect).unwrap();
             }
@@ -63,7 +76,11 @@ impl Display {
     }
 
     fn clear_canvas(&mut self) {
-        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.set_draw_color(to_color(self.palette.color(0)));
         self.canvas.clear();
     }
-}
\ No newline at end of file
+}
+
+fn to_color(rgb: Rgb) -> Color {
+    Color::RGB(rgb.r, rgb.g, rgb.b)
+}
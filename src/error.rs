@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Error {
     error: ErrorType,
 }
@@ -9,16 +9,38 @@ impl Error {
     pub fn new(e: ErrorType) -> Error {
         Error { error: e }
     }
+
+    pub fn kind(&self) -> ErrorType { self.error }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error: {:#?}", self.error)
+        match self.error {
+            ErrorType::PcOutOfRange { pc } =>
+                write!(f, "program counter {:#06x} is outside program memory", pc),
+            ErrorType::IOutOfRange { i, addr } =>
+                write!(f, "I register {:#06x} points outside memory at {:#06x}", i, addr),
+            ErrorType::StackOverflow { addr } =>
+                write!(f, "stack overflow on CALL at {:#06x}", addr),
+            ErrorType::StackUnderflow { addr } =>
+                write!(f, "stack underflow on RET at {:#06x}", addr),
+            ErrorType::InvalidOpcode { opcode, addr } =>
+                write!(f, "invalid opcode {:#06x} at {:#06x}", opcode, addr),
+            ErrorType::RomTooLarge { size, max } =>
+                write!(f, "ROM is {} bytes, larger than the {} bytes of program memory", size, max),
+        }
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for Error {}
+
+// `addr` is always the address of the instruction that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorType {
-    InaccessibleMemoryAddress,
-    InvalidOpcode,
-}
\ No newline at end of file
+    PcOutOfRange { pc: u16 },
+    IOutOfRange { i: u16, addr: u16 },
+    StackOverflow { addr: u16 },
+    StackUnderflow { addr: u16 },
+    InvalidOpcode { opcode: u16, addr: u16 },
+    RomTooLarge { size: usize, max: usize },
+}
@@ -0,0 +1,115 @@
+use crate::constants::{DISPLAY_HEIGHT, DISPLAY_WIDTH, HIRES_HEIGHT, HIRES_WIDTH};
+
+type PixelsType = [u8; (HIRES_WIDTH * HIRES_HEIGHT) as usize];
+
+// Screen contents, one byte per pixel. Starts in the 64x32 CHIP-8
+// resolution and can be switched to the 128x64 SUPER-CHIP resolution.
+//
+// Each bit of a pixel is an XO-CHIP bitplane, so pixels range from 0 to 3.
+// Drawing, scrolling and clearing only touch the selected planes; CHIP-8
+// and SUPER-CHIP programs only ever use plane 1.
+#[derive(Clone)]
+pub struct FrameBuffer {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) planes: u8,
+    pub(crate) pixels: PixelsType,
+}
+
+impl FrameBuffer {
+    pub fn new() -> FrameBuffer {
+        FrameBuffer {
+            width: DISPLAY_WIDTH as usize,
+            height: DISPLAY_HEIGHT as usize,
+            planes: 1,
+            pixels: [0; (HIRES_WIDTH * HIRES_HEIGHT) as usize],
+        }
+    }
+
+    // Switching resolution clears every plane
+    pub fn set_hires(&mut self, hires: bool) {
+        if hires {
+            self.width = HIRES_WIDTH as usize;
+            self.height = HIRES_HEIGHT as usize;
+        } else {
+            self.width = DISPLAY_WIDTH as usize;
+            self.height = DISPLAY_HEIGHT as usize;
+        }
+        self.pixels = [0; (HIRES_WIDTH * HIRES_HEIGHT) as usize];
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    pub fn clear(&mut self) {
+        let mask = !self.planes;
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= mask;
+        }
+    }
+
+    // XORs a pixel of one plane on, returning true if it was already set
+    pub fn toggle(&mut self, x: usize, y: usize, plane: u8) -> bool {
+        let idx = y * self.width + x;
+        let collision = self.pixels[idx] & plane != 0;
+        self.pixels[idx] ^= plane;
+        collision
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll(0, -(rows as isize));
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll(0, rows as isize);
+    }
+
+    pub fn scroll_right(&mut self, cols: usize) {
+        self.scroll(cols as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, cols: usize) {
+        self.scroll(-(cols as isize), 0);
+    }
+
+    // Moves the selected planes, filling the uncovered edge with 0
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let source = self.pixels;
+        let mask = self.planes;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let src_x = x as isize - dx;
+                let src_y = y as isize - dy;
+                let moved = if src_x >= 0 && src_y >= 0
+                    && (src_x as usize) < self.width
+                    && (src_y as usize) < self.height {
+                        source[src_y as usize * self.width + src_x as usize] & mask
+                    } else {
+                        0
+                    };
+
+                let idx = y * self.width + x;
+                self.pixels[idx] = (self.pixels[idx] & !mask) | moved;
+            }
+        }
+    }
+
+    // Getters
+    pub fn width(&self) -> usize { self.width }
+
+    pub fn height(&self) -> usize { self.height }
+
+    pub fn is_hires(&self) -> bool { self.width == HIRES_WIDTH as usize }
+
+    pub fn planes(&self) -> u8 { self.planes }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 { self.pixels[y * self.width + x] }
+
+    // Visible pixels, row by row
+    pub fn pixels(&self) -> &[u8] { &self.pixels[..self.width * self.height] }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self { FrameBuffer::new() }
+}
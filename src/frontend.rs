@@ -0,0 +1,321 @@
+extern crate native_dialog;
+extern crate sdl2;
+
+use native_dialog::{FileDialog, MessageDialog, MessageType};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chip_8r::constants::{FRAME_RATE, MICROS_PER_FRAME};
+use chip_8r::error::Error as MachineError;
+use chip_8r::gdb::{GdbStub, Session};
+use chip_8r::image::{Image, ImageFormat};
+use chip_8r::machine::Machine;
+use chip_8r::movie::Movie;
+use chip_8r::palette::Palette;
+use chip_8r::rewind::Rewind;
+
+use crate::audio::Audio;
+use crate::capture::{self, AudioRecording, InputRecording, Recording};
+use crate::config::Options;
+use crate::console::Console;
+use crate::display::Display;
+use crate::keyboard::{Hotkey, Keyboard};
+
+const MAX_FRAMES_BEHIND: u32 = 5;
+
+// SDL frontend: owns the window, audio device and keyboard, and drives a
+// headless `Machine` in real time.
+pub struct Frontend {
+    audio: Audio,
+    display: Display,
+    keyboard: Keyboard,
+    mute: bool,
+    // Save state slot used by the save and load hotkeys
+    slot: u8,
+    rewind: Rewind,
+    // Debugger console, when running under `debug`
+    console: Option<Console>,
+    // GDB connection, when running under `gdb`
+    gdb: Option<GdbStub>,
+    palette: Palette,
+    screenshot_scale: u32,
+    screenshot_format: ImageFormat,
+    recording: Option<Recording>,
+    audio_recording: Option<AudioRecording>,
+    input_recording: Option<InputRecording>,
+    // Movie played back instead of the keyboard until it ends
+    replay: Option<Movie>,
+}
+
+impl Frontend {
+    pub fn new(options: &Options) -> Result<Frontend, String> {
+        let sdl_context = sdl2::init()?;
+
+        let audio = Audio::new(&sdl_context)?;
+        let display = Display::new(&sdl_context, options.scale, options.palette)?;
+        let keyboard = Keyboard::new(&sdl_context)?;
+        let recording = match &options.record {
+            Some(path) => Some(Recording::start(path, &options.palette, options.screenshot_scale)?),
+            None => None,
+        };
+        let audio_recording = match &options.record_audio {
+            Some(path) => Some(AudioRecording::start(path)?),
+            None => None,
+        };
+
+        Ok(Frontend {
+            audio,
+            display,
+            keyboard,
+            mute: options.mute,
+            slot: 1,
+            rewind: Rewind::new(options.rewind as usize * FRAME_RATE as usize),
+            console: None,
+            gdb: None,
+            palette: options.palette,
+            screenshot_scale: options.screenshot_scale,
+            screenshot_format: options.screenshot_format,
+            recording,
+            audio_recording,
+            input_recording: None,
+            replay: None,
+        })
+    }
+
+    pub fn set_console(&mut self, console: Console) {
+        self.console = Some(console);
+    }
+
+    pub fn set_gdb(&mut self, gdb: GdbStub) {
+        self.gdb = Some(gdb);
+    }
+
+    pub fn set_input_recording(&mut self, recording: Option<InputRecording>) {
+        self.input_recording = recording;
+    }
+
+    pub fn set_replay(&mut self, movie: Option<Movie>) {
+        self.replay = movie;
+    }
+
+    // Runs one machine frame per 60th of a second. The machine itself is
+    // deterministic; this loop only paces it against the wall clock.
+    pub fn run(&mut self, machine: &mut Machine, rom_path: &Path) -> Result<(), MachineError> {
+        let result = self.run_frames(machine, rom_path);
+        // A crash is worth keeping a recording of
+        self.stop_recording();
+        self.stop_audio_recording();
+        self.stop_input_recording();
+        result
+    }
+
+    fn run_frames(&mut self, machine: &mut Machine, rom_path: &Path) -> Result<(), MachineError> {
+        let frame_duration = Duration::from_micros(MICROS_PER_FRAME as u64);
+        let mut next_frame = Instant::now();
+
+        loop {
+            for hotkey in self.keyboard.handle_input() {
+                match hotkey {
+                    Hotkey::Quit => return Ok(()),
+                    Hotkey::SelectSlot(slot) => {
+                        self.slot = slot;
+                        println!("Selected save state slot {}", slot);
+                    },
+                    Hotkey::SaveState => self.save_state(machine, rom_path),
+                    Hotkey::LoadState => self.load_state(machine, rom_path),
+                    Hotkey::Screenshot => self.screenshot(machine, rom_path),
+                    Hotkey::ToggleRecording => self.toggle_recording(rom_path),
+                }
+            }
+
+            let keys = self.keys(machine);
+            machine.keypad_mut().set_state(keys);
+
+            if let Some(console) = &mut self.console {
+                // Machine errors stop in the debugger instead of ending the run
+                if !console.update(machine, false) || machine.has_exited() { break; }
+            } else if let Some(gdb) = &mut self.gdb {
+                match gdb.update(machine, false) {
+                    Ok(Session::Attached) => {},
+                    Ok(Session::Detached) => self.gdb = None,
+                    Ok(Session::Ended) => break,
+                    Err(e) => {
+                        eprintln!("chip_8r: GDB connection failed: {}", e);
+                        self.gdb = None;
+                    },
+                }
+                if machine.has_exited() { break; }
+            } else if self.keyboard.is_rewinding() {
+                self.rewind.rewind(machine);
+            } else {
+                machine.run_frame()?;
+                if machine.has_exited() { break; }
+                self.rewind.push(machine);
+            }
+
+            if let Some(recording) = &mut self.input_recording { recording.push(machine, keys); }
+            self.display.render(machine.frame_buffer());
+            self.record(machine);
+            // Rewound frames replay old sound, so they aren't recorded
+            if !self.keyboard.is_rewinding() { self.record_audio(machine); }
+            self.audio.set_sound(machine.audio_pattern(), machine.pitch());
+            let paused = self.console.as_ref().is_some_and(|console| console.is_paused())
+                || self.gdb.as_ref().is_some_and(|gdb| gdb.is_paused());
+            self.handle_audio(machine.is_sound_playing() && !self.mute && !paused);
+
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else if now - next_frame > frame_duration * MAX_FRAMES_BEHIND {
+                // Don't try to catch up after a long stall, e.g. a dragged window
+                next_frame = now;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Save state failures are reported but never stop the game
+    fn save_state(&self, machine: &Machine, rom_path: &Path) {
+        let path = state_path(rom_path, self.slot);
+        match fs::write(&path, machine.save_state()) {
+            Ok(()) => println!("Saved state to slot {}", self.slot),
+            Err(e) => eprintln!("chip_8r: failed to write {}: {}", path.display(), e),
+        }
+    }
+
+    fn load_state(&mut self, machine: &mut Machine, rom_path: &Path) {
+        let path = state_path(rom_path, self.slot);
+        let result = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| machine.load_state(&data).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => {
+                // Frames recorded before the load are from another timeline
+                self.rewind.clear();
+                println!("Loaded state from slot {}", self.slot);
+                if self.input_recording.is_some() {
+                    println!("Stopped recording input, since movies can't replay a loaded state");
+                    self.stop_input_recording();
+                }
+            },
+            Err(e) => eprintln!("chip_8r: failed to load {}: {}", path.display(), e),
+        }
+    }
+
+    fn screenshot(&self, machine: &Machine, rom_path: &Path) {
+        let path = capture::timestamped_path(rom_path, self.screenshot_format.extension());
+        let image = Image::from_frame_buffer(machine.frame_buffer()).scaled(self.screenshot_scale as usize);
+        match fs::write(&path, image.encode(self.screenshot_format, &self.palette)) {
+            Ok(()) => println!("Saved screenshot to {}", path.display()),
+            Err(e) => eprintln!("chip_8r: failed to write {}: {}", path.display(), e),
+        }
+    }
+
+    fn toggle_recording(&mut self, rom_path: &Path) {
+        if self.recording.is_some() {
+            self.stop_recording();
+            return;
+        }
+
+        let path = capture::timestamped_path(rom_path, "gif");
+        match Recording::start(&path, &self.palette, self.screenshot_scale) {
+            Ok(recording) => self.recording = Some(recording),
+            Err(e) => eprintln!("chip_8r: {}", e),
+        }
+    }
+
+    // Recording errors stop the recording but never the game
+    fn record(&mut self, machine: &Machine) {
+        let Some(recording) = &mut self.recording else { return };
+        if let Err(e) = recording.push(machine.frame_buffer()) {
+            eprintln!("chip_8r: {}", e);
+            self.recording = None;
+        }
+    }
+
+    fn record_audio(&mut self, machine: &Machine) {
+        let Some(recording) = &mut self.audio_recording else { return };
+        if let Err(e) = recording.push(machine) {
+            eprintln!("chip_8r: {}", e);
+            self.audio_recording = None;
+        }
+    }
+
+    // Keys held on the next frame, from the movie being replayed if any
+    fn keys(&mut self, machine: &Machine) -> u16 {
+        if let Some(movie) = &self.replay {
+            match movie.keys(machine.frame_count()) {
+                Some(keys) => return keys,
+                None => {
+                    println!("Replay finished");
+                    self.replay = None;
+                },
+            }
+        }
+
+        self.keyboard.keys()
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            if let Err(e) = recording.finish() { eprintln!("chip_8r: {}", e); }
+        }
+    }
+
+    fn stop_audio_recording(&mut self) {
+        if let Some(recording) = self.audio_recording.take() {
+            if let Err(e) = recording.finish() { eprintln!("chip_8r: {}", e); }
+        }
+    }
+
+    fn stop_input_recording(&mut self) {
+        if let Some(recording) = self.input_recording.take() {
+            if let Err(e) = recording.finish() { eprintln!("chip_8r: {}", e); }
+        }
+    }
+
+    fn handle_audio(&mut self, playing: bool) {
+        if playing && !self.audio.is_playing() { self.audio.play(); }
+        else if !playing && self.audio.is_playing() { self.audio.stop(); }
+    }
+}
+
+// Save states live next to the ROM: `game.ch8` slot 2 is `game.state2`
+fn state_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("state{}", slot))
+}
+
+pub fn pick_rom() -> Result<PathBuf, Box<dyn Error>> {
+    show_intro();
+
+    match FileDialog::new()
+        .set_location("./")
+        .add_filter("name", &["ch8", "8o"])
+        .show_open_single_file()? {
+            Some(path) => Ok(path),
+            None => Err("no ROM selected".into()),
+        }
+}
+
+fn show_intro() {
+    MessageDialog::new()
+        .set_type(MessageType::Info)
+        .set_title("Welcome to Chip-8!")
+        .set_text("This emulator was written in Rust, by Jon Choukroun.")
+        .show_alert()
+        .unwrap();
+
+    MessageDialog::new()
+        .set_type(MessageType::Info)
+        .set_title("Welcome to Chip-8!")
+        .set_text("Click `OK` to load a ROM. Press `Esc` at any time to quit.")
+        .show_alert()
+        .unwrap();
+}
@@ -0,0 +1,485 @@
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::constants::{REGISTER_COUNT, STACK_SIZE};
+use crate::debugger::{Command, Debugger, Stop};
+use crate::error::ErrorType;
+use crate::machine::Machine;
+
+// Register numbers as seen by GDB: V0-VF are 0-15, then I, PC, SP, DT and
+// ST. Sizes in bytes; 16-bit registers are sent little-endian.
+const REGISTER_SIZES: [usize; REGISTER_COUNT as usize + 5] =
+    [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// State of the connection after an update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Session {
+    Attached,
+    // GDB detached or hung up; the program should keep running on its own
+    Detached,
+    // GDB killed the program, or it exited
+    Ended,
+}
+
+enum Input {
+    Packet(String),
+    Interrupt,
+    BadChecksum,
+}
+
+// GDB Remote Serial Protocol stub over TCP. Breakpoints and stepping are
+// handled by a `Debugger`; the stub translates packets to its commands.
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    buffer: Vec<u8>,
+    no_ack: bool,
+    last_signal: u8,
+}
+
+impl GdbStub {
+    // Blocks until GDB connects
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+
+        GdbStub::new(stream)
+    }
+
+    // The program starts paused, as if it had just hit a breakpoint
+    pub fn new(stream: TcpStream) -> io::Result<GdbStub> {
+        stream.set_nodelay(true)?;
+
+        Ok(GdbStub {
+            stream,
+            debugger: Debugger::new(),
+            buffer: Vec::new(),
+            no_ack: false,
+            last_signal: SIGTRAP,
+        })
+    }
+
+    pub fn is_paused(&self) -> bool { self.debugger.is_paused() }
+
+    // Handles pending packets, then runs a frame unless paused. With `wait`
+    // set, blocks for packets while paused instead of returning.
+    pub fn update(&mut self, machine: &mut Machine, wait: bool) -> io::Result<Session> {
+        loop {
+            while let Some(input) = self.next_input() {
+                let session = self.handle(input, machine)?;
+                if session != Session::Attached { return Ok(session); }
+            }
+
+            match self.receive(wait && self.debugger.is_paused())? {
+                Some(0) => return Ok(Session::Detached),
+                Some(_) => {},
+                None => break,
+            }
+        }
+
+        if let Some(stop) = self.debugger.run_frame(machine) {
+            self.last_signal = signal(stop);
+            self.send(&format!("S{:02x}", self.last_signal))?;
+        }
+        if machine.has_exited() {
+            self.send("W00")?;
+            return Ok(Session::Ended);
+        }
+
+        Ok(Session::Attached)
+    }
+
+    // Reads whatever has arrived. Returns the number of bytes read, 0 once
+    // the connection is closed, or None if nothing was waiting.
+    fn receive(&mut self, block: bool) -> io::Result<Option<usize>> {
+        self.stream.set_nonblocking(!block)?;
+
+        let mut chunk = [0; 1024];
+        match self.stream.read(&mut chunk) {
+            Ok(len) => {
+                self.buffer.extend_from_slice(&chunk[..len]);
+                Ok(Some(len))
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Takes the next complete packet or interrupt off the buffer
+    fn next_input(&mut self) -> Option<Input> {
+        loop {
+            match *self.buffer.first()? {
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Some(Input::Interrupt);
+                },
+                b'$' => {
+                    let end = self.buffer.iter().position(|byte| *byte == b'#')?;
+                    if self.buffer.len() < end + 3 { return None; }
+
+                    let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..]).ok()
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                    if checksum != Some(checksum_of(data)) {
+                        return Some(Input::BadChecksum);
+                    }
+                    return Some(Input::Packet(String::from_utf8_lossy(data).into_owned()));
+                },
+                // Acks and line noise
+                _ => { self.buffer.remove(0); },
+            }
+        }
+    }
+
+    fn handle(&mut self, input: Input, machine: &mut Machine) -> io::Result<Session> {
+        let packet = match input {
+            Input::Packet(packet) => packet,
+            Input::Interrupt => {
+                if !self.debugger.is_paused() {
+                    self.debugger.pause();
+                    self.last_signal = SIGTRAP;
+                    self.send(&format!("S{:02x}", SIGTRAP))?;
+                }
+                return Ok(Session::Attached);
+            },
+            Input::BadChecksum => {
+                self.ack(b"-")?;
+                return Ok(Session::Attached);
+            },
+        };
+        self.ack(b"+")?;
+
+        match packet.chars().next() {
+            Some('D') => {
+                self.send("OK")?;
+                return Ok(Session::Detached);
+            },
+            Some('k') => return Ok(Session::Ended),
+            // Resuming replies once the machine stops again
+            Some(kind @ ('c' | 's')) => {
+                if packet.len() > 1 {
+                    match u16::from_str_radix(&packet[1..], 16) {
+                        Ok(addr) => machine.registers_mut().pc = addr,
+                        Err(_) => return self.send("E01").map(|_| Session::Attached),
+                    }
+                }
+                let command = if kind == 'c' { Command::Continue } else { Command::Step(1) };
+                self.debugger.execute(command, machine);
+            },
+            _ => {
+                let reply = self.reply(&packet, machine).unwrap_or_else(|| "E01".into());
+                self.send(&reply)?;
+            },
+        }
+
+        Ok(Session::Attached)
+    }
+
+    // Reply to a packet that doesn't resume the machine. None if the packet
+    // is malformed or out of range; unsupported packets get an empty reply.
+    fn reply(&mut self, packet: &str, machine: &mut Machine) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".into());
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = parse_pair(range)?;
+            return Some(read_chunk(&target_xml(), offset, len));
+        }
+
+        let reply = match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".into()
+            },
+            "?" => format!("S{:02x}", self.last_signal),
+            "qAttached" => "1".into(),
+            "qC" => "QC1".into(),
+            "qfThreadInfo" => "m1".into(),
+            "qsThreadInfo" => "l".into(),
+            "g" => (0..REGISTER_SIZES.len())
+                .map(|reg| encode(&read_register(machine, reg)))
+                .collect(),
+            _ if packet.starts_with('H') => "OK".into(),
+            _ if packet.starts_with('G') => {
+                let mut bytes = decode(&packet[1..])?;
+                if bytes.len() != REGISTER_SIZES.iter().sum() { return None; }
+                for (reg, size) in REGISTER_SIZES.iter().enumerate() {
+                    let rest = bytes.split_off(*size);
+                    write_register(machine, reg, &bytes)?;
+                    bytes = rest;
+                }
+                "OK".into()
+            },
+            _ if packet.starts_with('p') => {
+                let reg = usize::from_str_radix(&packet[1..], 16).ok()?;
+                if reg >= REGISTER_SIZES.len() { return None; }
+                encode(&read_register(machine, reg))
+            },
+            _ if packet.starts_with('P') => {
+                let (reg, value) = packet[1..].split_once('=')?;
+                let reg = usize::from_str_radix(reg, 16).ok()?;
+                write_register(machine, reg, &decode(value)?)?;
+                "OK".into()
+            },
+            _ if packet.starts_with('m') => {
+                let (addr, len) = parse_pair(&packet[1..])?;
+                let bus = machine.bus();
+                let end = addr.checked_add(len)?;
+                if addr >= bus.size() { return None; }
+                let bytes: Vec<u8> = (addr..end.min(bus.size()))
+                    .map(|addr| bus.read_byte(addr as u16))
+                    .collect();
+                encode(&bytes)
+            },
+            _ if packet.starts_with('M') => {
+                let (range, data) = packet[1..].split_once(':')?;
+                let (addr, len) = parse_pair(range)?;
+                let bytes = decode(data)?;
+                let end = addr.checked_add(len)?;
+                if bytes.len() != len || end > machine.bus().size() { return None; }
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    machine.bus_mut().write_byte(addr + offset, byte);
+                }
+                "OK".into()
+            },
+            // Software and hardware breakpoints are the same thing here
+            _ if packet.starts_with("Z0,") || packet.starts_with("Z1,") => {
+                self.debugger.execute(Command::Break(parse_breakpoint(packet)?), machine);
+                "OK".into()
+            },
+            _ if packet.starts_with("z0,") || packet.starts_with("z1,") => {
+                self.debugger.execute(Command::Delete(Some(parse_breakpoint(packet)?)), machine);
+                "OK".into()
+            },
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    fn ack(&mut self, ack: &[u8]) -> io::Result<()> {
+        if self.no_ack { return Ok(()); }
+        self.stream.set_nonblocking(false)?;
+        self.stream.write_all(ack)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+}
+
+fn signal(stop: Stop) -> u8 {
+    match stop {
+        Stop::Breakpoint(_) | Stop::Step(_) => SIGTRAP,
+        Stop::Error(e) => match e.kind() {
+            ErrorType::InvalidOpcode { .. } => SIGILL,
+            _ => SIGSEGV,
+        },
+    }
+}
+
+fn read_register(machine: &Machine, reg: usize) -> Vec<u8> {
+    let r = machine.registers();
+    match reg {
+        0..=15 => vec![r.v[reg]],
+        16 => r.i.to_le_bytes().to_vec(),
+        17 => r.pc.to_le_bytes().to_vec(),
+        18 => vec![r.sp as u8],
+        19 => vec![r.dt],
+        _ => vec![r.st],
+    }
+}
+
+fn write_register(machine: &mut Machine, reg: usize, bytes: &[u8]) -> Option<()> {
+    if bytes.len() != *REGISTER_SIZES.get(reg)? { return None; }
+
+    let r = machine.registers_mut();
+    match reg {
+        0..=15 => r.v[reg] = bytes[0],
+        16 => r.i = u16::from_le_bytes([bytes[0], bytes[1]]),
+        17 => r.pc = u16::from_le_bytes([bytes[0], bytes[1]]),
+        18 => r.sp = (bytes[0] as usize).min(STACK_SIZE as usize),
+        19 => r.dt = bytes[0],
+        _ => r.st = bytes[0],
+    }
+
+    Some(())
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\"><feature name=\"org.chip8.cpu\">",
+    ));
+    for reg in 0..REGISTER_COUNT {
+        write!(xml, "<reg name=\"v{:x}\" bitsize=\"8\" regnum=\"{}\"/>", reg, reg).unwrap();
+    }
+    xml.push_str(concat!(
+        "<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>",
+        "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>",
+        "<reg name=\"sp\" bitsize=\"8\"/>",
+        "<reg name=\"dt\" bitsize=\"8\"/>",
+        "<reg name=\"st\" bitsize=\"8\"/>",
+        "</feature></target>",
+    ));
+    xml
+}
+
+// qXfer reply: `m` if more data follows, `l` for the last chunk
+fn read_chunk(data: &str, offset: usize, len: usize) -> String {
+    let start = offset.min(data.len());
+    let end = start.saturating_add(len).min(data.len());
+    let marker = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &data[start..end])
+}
+
+// `Z0,addr,kind`
+fn parse_breakpoint(packet: &str) -> Option<u16> {
+    let addr = packet[3..].split(',').next()?;
+    u16::from_str_radix(addr, 16).ok()
+}
+
+// `addr,len` in hex
+fn parse_pair(text: &str) -> Option<(usize, usize)> {
+    let (first, second) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(first, 16).ok()?,
+        usize::from_str_radix(second, 16).ok()?,
+    ))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) { return None; }
+    (0..hex.len()).step_by(2)
+        .map(|pos| u8::from_str_radix(hex.get(pos..pos + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    // A stub attached to a machine, and the GDB end of its connection
+    fn connect() -> (GdbStub, Machine, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&[0x12, 0x00]).unwrap();
+
+        (GdbStub::new(stream).unwrap(), machine, client)
+    }
+
+    // Sends a packet and returns the reply, without its ack and framing
+    fn request(stub: &mut GdbStub, machine: &mut Machine, client: &mut TcpStream, data: &str)
+        -> String
+    {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        client.write_all(packet.as_bytes()).unwrap();
+        reply(stub, machine, client)
+    }
+
+    // Runs the stub until a whole reply has arrived
+    fn reply(stub: &mut GdbStub, machine: &mut Machine, client: &mut TcpStream) -> String {
+        client.set_nonblocking(true).unwrap();
+
+        let mut reply = Vec::new();
+        while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+            assert_eq!(stub.update(machine, false).unwrap(), Session::Attached);
+            let mut chunk = [0; 64];
+            match client.read(&mut chunk) {
+                Ok(len) => reply.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let reply = String::from_utf8(reply).unwrap();
+        let data = reply.trim_start_matches('+').trim_start_matches('$');
+        data.split('#').next().unwrap().into()
+    }
+
+    // LD V0, 1; ADD V0, 1; JP 0x202
+    const COUNTER: [u8; 6] = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let (mut stub, mut machine, mut client) = connect();
+        machine.registers_mut().v[0xf] = 0x12;
+        machine.registers_mut().i = 0x345;
+
+        // V0-VF, then I, PC, SP, DT and ST
+        let expected = format!("{}12{}{}", "00".repeat(15), "4503", "0002000000");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "g"), expected);
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "p11"), "0002");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "P3=2a"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "p3"), "2a");
+        assert_eq!(machine.registers().v[3], 0x2a);
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "p15"), "E01");
+    }
+
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let (mut stub, mut machine, mut client) = connect();
+        machine.load_rom(&COUNTER).unwrap();
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "?"), "S05");
+
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "s"), "S05");
+        assert_eq!(machine.registers().pc, 0x202);
+        assert_eq!(machine.registers().v[0], 1);
+
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "Z0,204,2"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "c"), "S05");
+        assert_eq!(machine.registers().pc, 0x204);
+        assert_eq!(machine.registers().v[0], 2);
+
+        // Without the breakpoint, only an interrupt stops it
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "z0,204,2"), "OK");
+        client.write_all(b"$c#63").unwrap();
+        while stub.is_paused() {
+            assert_eq!(stub.update(&mut machine, false).unwrap(), Session::Attached);
+        }
+        assert_eq!(stub.update(&mut machine, false).unwrap(), Session::Attached);
+        assert!(machine.registers().v[0] > 2);
+
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(reply(&mut stub, &mut machine, &mut client), "S05");
+        assert!(stub.is_paused());
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let (mut stub, mut machine, mut client) = connect();
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "m200,2"), "1200");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "M300,2:abcd"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, "m300,2"), "abcd");
+    }
+
+    #[test]
+    fn rejects_ranges_that_overflow() {
+        let (mut stub, mut machine, mut client) = connect();
+        let max = format!("{:x}", usize::MAX);
+        let read = format!("m200,{}", max);
+        let write = format!("M{},2:abcd", max);
+        assert_eq!(request(&mut stub, &mut machine, &mut client, &read), "E01");
+        assert_eq!(request(&mut stub, &mut machine, &mut client, &write), "E01");
+    }
+}
@@ -0,0 +1,9 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64-bit FNV-1a, used to tell ROMs apart in save states and movies
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
@@ -0,0 +1,343 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::framebuffer::FrameBuffer;
+use crate::palette::{Palette, Rgb};
+
+// Characters for pixel values 0 to 3 in text images
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '*'];
+
+// File formats images can be written in. Text and PBM can be read back.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ImageFormat {
+    Text,
+    Pbm,
+    Pgm,
+    #[default]
+    Png,
+}
+
+impl ImageFormat {
+    // By file extension; anything unknown is text
+    pub fn from_path(path: &Path) -> ImageFormat {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        extension.parse().unwrap_or(ImageFormat::Text)
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Text => "txt",
+            ImageFormat::Pbm => "pbm",
+            ImageFormat::Pgm => "pgm",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImageFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "txt" | "text" => Ok(ImageFormat::Text),
+            "pbm" => Ok(ImageFormat::Pbm),
+            "pgm" => Ok(ImageFormat::Pgm),
+            "png" => Ok(ImageFormat::Png),
+            _ => Err(format!("unknown image format `{}` (expected one of png, pbm, pgm, txt)", s)),
+        }
+    }
+}
+
+// A copy of the screen, one byte per pixel holding its plane bits like
+// `FrameBuffer`. Images can be written and read as text, one character per
+// pixel, or as PBM files, which only tell lit pixels from unlit ones. They
+// can also be written in the colors of a palette as PNG, or in gray as PGM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Image {
+        assert_eq!(pixels.len(), width * height, "pixel count doesn't match the size");
+        Image { width, height, pixels }
+    }
+
+    pub fn from_frame_buffer(frame_buffer: &FrameBuffer) -> Image {
+        Image::new(frame_buffer.width(), frame_buffer.height(), frame_buffer.pixels().to_vec())
+    }
+
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::new();
+        for row in self.pixels.chunks(self.width) {
+            out.extend(row.iter().map(|pixel| ASCII_PIXELS[(*pixel & 0b11) as usize]));
+            out.push('\n');
+        }
+        out
+    }
+
+    // Blank lines and trailing whitespace are ignored
+    pub fn from_ascii(text: &str) -> Result<Image, String> {
+        let rows: Vec<&str> = text.lines().map(str::trim_end).filter(|row| !row.is_empty()).collect();
+        let width = rows.first().map_or(0, |row| row.chars().count());
+
+        let mut pixels = Vec::new();
+        for (index, row) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(format!("row {} is not {} pixels wide", index + 1, width));
+            }
+            for c in row.chars() {
+                let pixel = ASCII_PIXELS.iter().position(|p| *p == c)
+                    .ok_or_else(|| format!("unexpected `{}` in row {}", c, index + 1))?;
+                pixels.push(pixel as u8);
+            }
+        }
+
+        Ok(Image::new(width, rows.len(), pixels))
+    }
+
+    // Plain PBM, which diffs well as text
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut out = format!("P1\n{} {}\n", self.width, self.height);
+        for row in self.pixels.chunks(self.width) {
+            let bits: Vec<&str> = row.iter().map(|pixel| if *pixel != 0 { "1" } else { "0" }).collect();
+            writeln!(out, "{}", bits.join(" ")).unwrap();
+        }
+        out.into_bytes()
+    }
+
+    // Reads plain (P1) or binary (P4) PBM
+    pub fn from_pbm(data: &[u8]) -> Result<Image, String> {
+        let mut pos = 0;
+        let magic = pbm_token(data, &mut pos).ok_or("not a PBM file")?;
+        let binary = match magic.as_str() {
+            "P1" => false,
+            "P4" => true,
+            _ => return Err("not a PBM file".into()),
+        };
+        let mut size = || pbm_token(data, &mut pos)
+            .and_then(|token| token.parse::<usize>().ok())
+            .ok_or("invalid PBM size");
+        let width = size()?;
+        let height = size()?;
+
+        let pixels = if binary {
+            // A single whitespace byte separates the header from the rows,
+            // which are padded to whole bytes
+            let bytes = &data[(pos + 1).min(data.len())..];
+            let stride = width.div_ceil(8);
+            if bytes.len() < stride * height { return Err("PBM file is truncated".into()); }
+            (0..width * height)
+                .map(|index| {
+                    let (y, x) = (index / width, index % width);
+                    (bytes[y * stride + x / 8] >> (7 - x % 8)) & 1
+                })
+                .collect()
+        } else {
+            // Plain pixels may be written without spaces between them
+            data[pos..].iter()
+                .filter(|byte| **byte == b'0' || **byte == b'1')
+                .take(width * height)
+                .map(|byte| byte - b'0')
+                .collect::<Vec<u8>>()
+        };
+        if pixels.len() != width * height { return Err("PBM file is truncated".into()); }
+
+        Ok(Image::new(width, height, pixels))
+    }
+
+    // Every pixel blown up to a `scale` x `scale` square
+    pub fn scaled(&self, scale: usize) -> Image {
+        let scale = scale.max(1);
+        let width = self.width * scale;
+        let mut pixels = Vec::with_capacity(width * self.height * scale);
+        for row in self.pixels.chunks(self.width) {
+            let scaled_row: Vec<u8> = row.iter()
+                .flat_map(|pixel| std::iter::repeat_n(*pixel, scale))
+                .collect();
+            for _ in 0..scale {
+                pixels.extend_from_slice(&scaled_row);
+            }
+        }
+
+        Image::new(width, self.height * scale, pixels)
+    }
+
+    // Binary PGM, with the palette colors turned to gray
+    pub fn to_pgm(&self, palette: &Palette) -> Vec<u8> {
+        let mut out = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend(self.pixels.iter().map(|pixel| luma(palette.color(*pixel))));
+        out
+    }
+
+    // Indexed PNG using the palette, two bits per pixel
+    pub fn to_png(&self, palette: &Palette) -> Vec<u8> {
+        let colors: Vec<u8> = palette.colors.iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+        let data: Vec<u8> = self.pixels.chunks(self.width)
+            .flat_map(|row| row.chunks(4).map(|group| {
+                group.iter().enumerate()
+                    .fold(0, |byte, (index, pixel)| byte | (pixel & 0b11) << (6 - 2 * index))
+            }))
+            .collect();
+
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Two);
+        encoder.set_palette(colors);
+        // Writing to memory only fails on invalid image parameters
+        let mut writer = encoder.write_header().expect("valid PNG header");
+        writer.write_image_data(&data).expect("PNG data matches the header");
+        writer.finish().expect("PNG is complete");
+        out
+    }
+
+    pub fn encode(&self, format: ImageFormat, palette: &Palette) -> Vec<u8> {
+        match format {
+            ImageFormat::Text => self.to_ascii().into_bytes(),
+            ImageFormat::Pbm => self.to_pbm(),
+            ImageFormat::Pgm => self.to_pgm(palette),
+            ImageFormat::Png => self.to_png(palette),
+        }
+    }
+
+    // Number of pixels that differ, or None when the sizes don't match. PBM
+    // images only have one plane, so any lit pixel matches any other.
+    pub fn differences(&self, other: &Image, planes: bool) -> Option<usize> {
+        if self.width != other.width || self.height != other.height { return None; }
+
+        let count = self.pixels.iter().zip(&other.pixels)
+            .filter(|(a, b)| if planes { a != b } else { (**a != 0) != (**b != 0) })
+            .count();
+        Some(count)
+    }
+
+    // Getters
+    pub fn width(&self) -> usize { self.width }
+
+    pub fn height(&self) -> usize { self.height }
+
+    pub fn pixels(&self) -> &[u8] { &self.pixels }
+}
+
+// Perceived brightness of a color, from 0 to 255
+fn luma(rgb: Rgb) -> u8 {
+    ((rgb.r as u32 * 299 + rgb.g as u32 * 587 + rgb.b as u32 * 114) / 1000) as u8
+}
+
+// Next whitespace separated header field, skipping `#` comments
+fn pbm_token(data: &[u8], pos: &mut usize) -> Option<String> {
+    loop {
+        while data.get(*pos)?.is_ascii_whitespace() { *pos += 1; }
+        if data[*pos] != b'#' { break; }
+        while *data.get(*pos)? != b'\n' { *pos += 1; }
+    }
+
+    let start = *pos;
+    while data.get(*pos).is_some_and(|byte| !byte.is_ascii_whitespace()) { *pos += 1; }
+    Some(String::from_utf8_lossy(&data[start..*pos]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    // The font's 0 in the top left corner of a lores screen
+    fn golden() -> Image {
+        let glyph = ["####", "#..#", "#..#", "#..#", "####"];
+        let text: String = (0..32)
+            .map(|y| format!("{:.<64}\n", glyph.get(y).copied().unwrap_or("")))
+            .collect();
+        Image::from_ascii(&text).unwrap()
+    }
+
+    #[test]
+    fn headless_run_matches_golden_image() {
+        // v0 := 0, i := hex v0, sprite v0 v0 5, loop
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&[0x60, 0x00, 0xf0, 0x29, 0xd0, 0x05, 0x12, 0x06]).unwrap();
+        for _ in 0..3 { machine.run_frame().unwrap(); }
+
+        let image = Image::from_frame_buffer(machine.frame_buffer());
+        assert_eq!(image.differences(&golden(), true), Some(0));
+    }
+
+    #[test]
+    fn ascii_round_trips() {
+        let image = Image::new(3, 2, vec![0, 1, 2, 3, 0, 1]);
+        assert_eq!(image.to_ascii(), ".#+\n*.#\n");
+        assert_eq!(Image::from_ascii(&image.to_ascii()), Ok(image));
+        assert!(Image::from_ascii("..\n.\n").is_err());
+    }
+
+    #[test]
+    fn pbm_round_trips() {
+        let image = golden();
+        assert_eq!(Image::from_pbm(&image.to_pbm()), Ok(image));
+    }
+
+    #[test]
+    fn reads_binary_pbm() {
+        // Rows are padded to whole bytes
+        let image = Image::from_pbm(b"P4\n10 2\n\xc0\x40\x00\x80").unwrap();
+        assert_eq!(image.pixels(), [1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(Image::from_pbm(b"P4\n10 2\n\xc0"), Err("PBM file is truncated".into()));
+    }
+
+    #[test]
+    fn counts_differences() {
+        let image = Image::new(2, 1, vec![1, 2]);
+        assert_eq!(image.differences(&Image::new(2, 1, vec![1, 1]), true), Some(1));
+        assert_eq!(image.differences(&Image::new(2, 1, vec![1, 1]), false), Some(0));
+        assert_eq!(image.differences(&Image::new(1, 2, vec![1, 2]), true), None);
+    }
+
+    #[test]
+    fn parses_palettes() {
+        let colors = vec!["#102030".to_string(), "a0b0c0".to_string()];
+        let palette = Palette::from_colors(&colors).unwrap();
+        assert_eq!(palette.colors[0], Rgb::new(0x10, 0x20, 0x30));
+        assert_eq!(palette.colors[1], Rgb::new(0xa0, 0xb0, 0xc0));
+        assert_eq!(palette.colors[2..], Palette::default().colors[2..]);
+    }
+
+    #[test]
+    fn rejects_bad_palettes() {
+        for color in ["#12345", "#1234567", "#12345g", "red", ""] {
+            let error = Palette::from_colors(&[color.to_string()]).unwrap_err();
+            assert_eq!(error, format!("invalid color `{}` (expected #rrggbb)", color));
+        }
+        let colors = vec!["#000000".to_string(); 5];
+        assert_eq!(Palette::from_colors(&colors), Err("a palette has at most 4 colors, not 5".into()));
+    }
+
+    #[test]
+    fn png_uses_the_palette() {
+        // Rows that don't fill their last byte are padded
+        let image = Image::new(5, 2, vec![0, 1, 2, 3, 1, 3, 2, 1, 0, 2]);
+        let palette = Palette::from_colors(&[
+            "#000000".into(), "#ff0000".into(), "#00ff00".into(), "#0000ff".into(),
+        ]).unwrap();
+        let png = image.to_png(&palette);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png)).read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (5, 2));
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::Indexed, png::BitDepth::Two));
+        assert_eq!(
+            info.palette.as_deref(),
+            Some(&[0, 0, 0, 0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff][..])
+        );
+
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(data, [0b00_01_10_11, 0b01_000000, 0b11_10_01_00, 0b10_000000]);
+    }
+}
@@ -0,0 +1,439 @@
+use std::fmt;
+
+use crate::variant::Variant;
+
+// A decoded opcode. Register operands are register numbers, not values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // 0NNN: machine code routine, ignored
+    Sys(u16),
+    Cls,
+    Ret,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Low,
+    High,
+    Jp(u16),
+    Call(u16),
+    SeByte { x: u8, kk: u8 },
+    SneByte { x: u8, kk: u8 },
+    SeReg { x: u8, y: u8 },
+    Save { x: u8, y: u8 },
+    Load { x: u8, y: u8 },
+    LdByte { x: u8, kk: u8 },
+    AddByte { x: u8, kk: u8 },
+    LdReg { x: u8, y: u8 },
+    Or { x: u8, y: u8 },
+    And { x: u8, y: u8 },
+    Xor { x: u8, y: u8 },
+    AddReg { x: u8, y: u8 },
+    Sub { x: u8, y: u8 },
+    Shr { x: u8, y: u8 },
+    Subn { x: u8, y: u8 },
+    Shl { x: u8, y: u8 },
+    SneReg { x: u8, y: u8 },
+    LdI(u16),
+    JpV0(u16),
+    Rnd { x: u8, kk: u8 },
+    Drw { x: u8, y: u8, n: u8 },
+    Skp(u8),
+    Sknp(u8),
+    // F000 NNNN: the address is the word following the opcode
+    LdILong,
+    Audio,
+    Plane(u8),
+    LdVxDt(u8),
+    LdVxK(u8),
+    LdDtVx(u8),
+    LdStVx(u8),
+    AddI(u8),
+    LdF(u8),
+    LdHf(u8),
+    LdB(u8),
+    Pitch(u8),
+    LdMemVx(u8),
+    LdVxMem(u8),
+    LdRVx(u8),
+    LdVxR(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid opcode {:04x}", self.opcode)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// Decodes any opcode known to the latest variant
+pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+    let x = get_x(opcode) as u8;
+    let y = get_y(opcode) as u8;
+    let n = get_n(opcode);
+    let kk = get_kk(opcode);
+    let nnn = get_nnn(opcode);
+
+    let instruction = match opcode >> 12 {
+        0x0 => match opcode {
+            0x00e0 => Instruction::Cls,
+            0x00ee => Instruction::Ret,
+            0x00c0..=0x00cf => Instruction::ScrollDown(n),
+            0x00d0..=0x00df => Instruction::ScrollUp(n),
+            0x00fb => Instruction::ScrollRight,
+            0x00fc => Instruction::ScrollLeft,
+            0x00fd => Instruction::Exit,
+            0x00fe => Instruction::Low,
+            0x00ff => Instruction::High,
+            _ => Instruction::Sys(nnn),
+        },
+        0x1 => Instruction::Jp(nnn),
+        0x2 => Instruction::Call(nnn),
+        0x3 => Instruction::SeByte { x, kk },
+        0x4 => Instruction::SneByte { x, kk },
+        0x5 => match n {
+            0x0 => Instruction::SeReg { x, y },
+            0x2 => Instruction::Save { x, y },
+            0x3 => Instruction::Load { x, y },
+            _ => return Err(DecodeError { opcode }),
+        },
+        0x6 => Instruction::LdByte { x, kk },
+        0x7 => Instruction::AddByte { x, kk },
+        0x8 => match n {
+            0x0 => Instruction::LdReg { x, y },
+            0x1 => Instruction::Or { x, y },
+            0x2 => Instruction::And { x, y },
+            0x3 => Instruction::Xor { x, y },
+            0x4 => Instruction::AddReg { x, y },
+            0x5 => Instruction::Sub { x, y },
+            0x6 => Instruction::Shr { x, y },
+            0x7 => Instruction::Subn { x, y },
+            0xe => Instruction::Shl { x, y },
+            _ => return Err(DecodeError { opcode }),
+        },
+        0x9 => Instruction::SneReg { x, y },
+        0xa => Instruction::LdI(nnn),
+        0xb => Instruction::JpV0(nnn),
+        0xc => Instruction::Rnd { x, kk },
+        0xd => Instruction::Drw { x, y, n },
+        0xe => match kk {
+            0x9e => Instruction::Skp(x),
+            0xa1 => Instruction::Sknp(x),
+            _ => return Err(DecodeError { opcode }),
+        },
+        _ => match kk {
+            0x00 if opcode == 0xf000 => Instruction::LdILong,
+            0x02 if opcode == 0xf002 => Instruction::Audio,
+            0x01 => Instruction::Plane(x),
+            0x07 => Instruction::LdVxDt(x),
+            0x0a => Instruction::LdVxK(x),
+            0x15 => Instruction::LdDtVx(x),
+            0x18 => Instruction::LdStVx(x),
+            0x1e => Instruction::AddI(x),
+            0x29 => Instruction::LdF(x),
+            0x30 => Instruction::LdHf(x),
+            0x33 => Instruction::LdB(x),
+            0x3a => Instruction::Pitch(x),
+            0x55 => Instruction::LdMemVx(x),
+            0x65 => Instruction::LdVxMem(x),
+            0x75 => Instruction::LdRVx(x),
+            0x85 => Instruction::LdVxR(x),
+            _ => return Err(DecodeError { opcode }),
+        },
+    };
+
+    Ok(instruction)
+}
+
+// Decodes an opcode as `variant` understands it. Older interpreters treat
+// the later 00NN instructions as machine code calls; anything else they
+// don't know is invalid.
+pub fn decode_for(opcode: u16, variant: Variant) -> Result<Instruction, DecodeError> {
+    let instruction = decode(opcode)?;
+    if instruction.variant() <= variant {
+        Ok(instruction)
+    } else if opcode & 0xf000 == 0 {
+        Ok(Instruction::Sys(get_nnn(opcode)))
+    } else {
+        Err(DecodeError { opcode })
+    }
+}
+
+impl Instruction {
+    // First variant that has this instruction
+    pub fn variant(self) -> Variant {
+        match self {
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::Low
+            | Instruction::High
+            | Instruction::LdHf(_)
+            | Instruction::LdRVx(_)
+            | Instruction::LdVxR(_) => Variant::SuperChip,
+            Instruction::ScrollUp(_)
+            | Instruction::Save { .. }
+            | Instruction::Load { .. }
+            | Instruction::LdILong
+            | Instruction::Audio
+            | Instruction::Plane(_)
+            | Instruction::Pitch(_) => Variant::XoChip,
+            _ => Variant::Chip8,
+        }
+    }
+
+    // Size in bytes, including the address word of `LdILong`
+    pub fn size(self) -> u16 {
+        if self == Instruction::LdILong { 4 } else { 2 }
+    }
+
+    // Opcode of the instruction; `LdILong` is followed by its address word
+    pub fn encode(self) -> u16 {
+        let x_kk = |x: u8, kk: u8| (x as u16) << 8 | kk as u16;
+        let x_y = |x: u8, y: u8| (x as u16) << 8 | (y as u16) << 4;
+
+        match self {
+            Instruction::Sys(nnn) => nnn & 0x0fff,
+            Instruction::Cls => 0x00e0,
+            Instruction::Ret => 0x00ee,
+            Instruction::ScrollDown(n) => 0x00c0 | (n & 0xf) as u16,
+            Instruction::ScrollUp(n) => 0x00d0 | (n & 0xf) as u16,
+            Instruction::ScrollRight => 0x00fb,
+            Instruction::ScrollLeft => 0x00fc,
+            Instruction::Exit => 0x00fd,
+            Instruction::Low => 0x00fe,
+            Instruction::High => 0x00ff,
+            Instruction::Jp(nnn) => 0x1000 | nnn & 0x0fff,
+            Instruction::Call(nnn) => 0x2000 | nnn & 0x0fff,
+            Instruction::SeByte { x, kk } => 0x3000 | x_kk(x, kk),
+            Instruction::SneByte { x, kk } => 0x4000 | x_kk(x, kk),
+            Instruction::SeReg { x, y } => 0x5000 | x_y(x, y),
+            Instruction::Save { x, y } => 0x5002 | x_y(x, y),
+            Instruction::Load { x, y } => 0x5003 | x_y(x, y),
+            Instruction::LdByte { x, kk } => 0x6000 | x_kk(x, kk),
+            Instruction::AddByte { x, kk } => 0x7000 | x_kk(x, kk),
+            Instruction::LdReg { x, y } => 0x8000 | x_y(x, y),
+            Instruction::Or { x, y } => 0x8001 | x_y(x, y),
+            Instruction::And { x, y } => 0x8002 | x_y(x, y),
+            Instruction::Xor { x, y } => 0x8003 | x_y(x, y),
+            Instruction::AddReg { x, y } => 0x8004 | x_y(x, y),
+            Instruction::Sub { x, y } => 0x8005 | x_y(x, y),
+            Instruction::Shr { x, y } => 0x8006 | x_y(x, y),
+            Instruction::Subn { x, y } => 0x8007 | x_y(x, y),
+            Instruction::Shl { x, y } => 0x800e | x_y(x, y),
+            Instruction::SneReg { x, y } => 0x9000 | x_y(x, y),
+            Instruction::LdI(nnn) => 0xa000 | nnn & 0x0fff,
+            Instruction::JpV0(nnn) => 0xb000 | nnn & 0x0fff,
+            Instruction::Rnd { x, kk } => 0xc000 | x_kk(x, kk),
+            Instruction::Drw { x, y, n } => 0xd000 | x_y(x, y) | (n & 0xf) as u16,
+            Instruction::Skp(x) => 0xe09e | x_kk(x, 0),
+            Instruction::Sknp(x) => 0xe0a1 | x_kk(x, 0),
+            Instruction::LdILong => 0xf000,
+            Instruction::Audio => 0xf002,
+            Instruction::Plane(n) => 0xf001 | x_kk(n, 0),
+            Instruction::LdVxDt(x) => 0xf007 | x_kk(x, 0),
+            Instruction::LdVxK(x) => 0xf00a | x_kk(x, 0),
+            Instruction::LdDtVx(x) => 0xf015 | x_kk(x, 0),
+            Instruction::LdStVx(x) => 0xf018 | x_kk(x, 0),
+            Instruction::AddI(x) => 0xf01e | x_kk(x, 0),
+            Instruction::LdF(x) => 0xf029 | x_kk(x, 0),
+            Instruction::LdHf(x) => 0xf030 | x_kk(x, 0),
+            Instruction::LdB(x) => 0xf033 | x_kk(x, 0),
+            Instruction::Pitch(x) => 0xf03a | x_kk(x, 0),
+            Instruction::LdMemVx(x) => 0xf055 | x_kk(x, 0),
+            Instruction::LdVxMem(x) => 0xf065 | x_kk(x, 0),
+            Instruction::LdRVx(x) => 0xf075 | x_kk(x, 0),
+            Instruction::LdVxR(x) => 0xf085 | x_kk(x, 0),
+        }
+    }
+
+    // Instructions that may skip the one after them
+    pub fn is_skip(self) -> bool {
+        matches!(
+            self,
+            Instruction::SeByte { .. }
+                | Instruction::SneByte { .. }
+                | Instruction::SeReg { .. }
+                | Instruction::SneReg { .. }
+                | Instruction::Skp(_)
+                | Instruction::Sknp(_)
+        )
+    }
+}
+
+// Assembly syntax. `LdILong` has no operand here since it isn't part of
+// the opcode; the disassembler reads it from the following word.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Sys(nnn) => write!(f, "SYS {:#05x}", nnn),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Jp(nnn) => write!(f, "JP {:#05x}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL {:#05x}", nnn),
+            Instruction::SeByte { x, kk } => write!(f, "SE V{:X}, {:#04x}", x, kk),
+            Instruction::SneByte { x, kk } => write!(f, "SNE V{:X}, {:#04x}", x, kk),
+            Instruction::SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Save { x, y } => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::Load { x, y } => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdByte { x, kk } => write!(f, "LD V{:X}, {:#04x}", x, kk),
+            Instruction::AddByte { x, kk } => write!(f, "ADD V{:X}, {:#04x}", x, kk),
+            Instruction::LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(nnn) => write!(f, "LD I, {:#05x}", nnn),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, {:#05x}", nnn),
+            Instruction::Rnd { x, kk } => write!(f, "RND V{:X}, {:#04x}", x, kk),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong => write!(f, "LD I, long"),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdF(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdB(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdMemVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxMem(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+pub(crate) fn get_x(opcode: u16) -> usize { ((opcode & 0x0f00) >> 8) as usize }
+
+fn get_y(opcode: u16) -> usize { ((opcode & 0x00f0) >> 4) as usize }
+
+fn get_n(opcode: u16) -> u8 { (opcode & 0x000f) as u8 }
+
+fn get_kk(opcode: u16) -> u8 { (opcode & 0x00ff) as u8 }
+
+fn get_nnn(opcode: u16) -> u16 { opcode & 0x0fff }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One opcode from every family, with distinct operands
+    const OPCODES: [(u16, Instruction); 51] = [
+        (0x0123, Instruction::Sys(0x123)),
+        (0x00e0, Instruction::Cls),
+        (0x00ee, Instruction::Ret),
+        (0x00c4, Instruction::ScrollDown(4)),
+        (0x00d5, Instruction::ScrollUp(5)),
+        (0x00fb, Instruction::ScrollRight),
+        (0x00fc, Instruction::ScrollLeft),
+        (0x00fd, Instruction::Exit),
+        (0x00fe, Instruction::Low),
+        (0x00ff, Instruction::High),
+        (0x1234, Instruction::Jp(0x234)),
+        (0x2345, Instruction::Call(0x345)),
+        (0x3a12, Instruction::SeByte { x: 0xa, kk: 0x12 }),
+        (0x4b34, Instruction::SneByte { x: 0xb, kk: 0x34 }),
+        (0x5120, Instruction::SeReg { x: 1, y: 2 }),
+        (0x5342, Instruction::Save { x: 3, y: 4 }),
+        (0x5563, Instruction::Load { x: 5, y: 6 }),
+        (0x6c56, Instruction::LdByte { x: 0xc, kk: 0x56 }),
+        (0x7d78, Instruction::AddByte { x: 0xd, kk: 0x78 }),
+        (0x8120, Instruction::LdReg { x: 1, y: 2 }),
+        (0x8231, Instruction::Or { x: 2, y: 3 }),
+        (0x8342, Instruction::And { x: 3, y: 4 }),
+        (0x8453, Instruction::Xor { x: 4, y: 5 }),
+        (0x8564, Instruction::AddReg { x: 5, y: 6 }),
+        (0x8675, Instruction::Sub { x: 6, y: 7 }),
+        (0x8786, Instruction::Shr { x: 7, y: 8 }),
+        (0x8897, Instruction::Subn { x: 8, y: 9 }),
+        (0x89ae, Instruction::Shl { x: 9, y: 0xa }),
+        (0x9ab0, Instruction::SneReg { x: 0xa, y: 0xb }),
+        (0xa456, Instruction::LdI(0x456)),
+        (0xb567, Instruction::JpV0(0x567)),
+        (0xce9a, Instruction::Rnd { x: 0xe, kk: 0x9a }),
+        (0xd12f, Instruction::Drw { x: 1, y: 2, n: 0xf }),
+        (0xe39e, Instruction::Skp(3)),
+        (0xe4a1, Instruction::Sknp(4)),
+        (0xf000, Instruction::LdILong),
+        (0xf002, Instruction::Audio),
+        (0xf201, Instruction::Plane(2)),
+        (0xf507, Instruction::LdVxDt(5)),
+        (0xf60a, Instruction::LdVxK(6)),
+        (0xf715, Instruction::LdDtVx(7)),
+        (0xf818, Instruction::LdStVx(8)),
+        (0xf91e, Instruction::AddI(9)),
+        (0xfa29, Instruction::LdF(0xa)),
+        (0xfb30, Instruction::LdHf(0xb)),
+        (0xfc33, Instruction::LdB(0xc)),
+        (0xfd3a, Instruction::Pitch(0xd)),
+        (0xfe55, Instruction::LdMemVx(0xe)),
+        (0xff65, Instruction::LdVxMem(0xf)),
+        (0xf175, Instruction::LdRVx(1)),
+        (0xf285, Instruction::LdVxR(2)),
+    ];
+
+    #[test]
+    fn decodes_every_family() {
+        for (opcode, instruction) in OPCODES {
+            assert_eq!(decode(opcode), Ok(instruction), "{:04x}", opcode);
+            assert_eq!(decode_for(opcode, Variant::XoChip), Ok(instruction), "{:04x}", opcode);
+            assert_eq!(instruction.encode(), opcode, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        for opcode in [0x5001, 0x5124, 0x8008, 0x800f, 0xe000, 0xe19f, 0xf100, 0xf102, 0xf0ff] {
+            assert_eq!(decode(opcode), Err(DecodeError { opcode }), "{:04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn older_variants_reject_newer_opcodes() {
+        for (opcode, instruction) in OPCODES {
+            for variant in [Variant::Chip8, Variant::SuperChip] {
+                let decoded = decode_for(opcode, variant);
+                if instruction.variant() <= variant {
+                    assert_eq!(decoded, Ok(instruction), "{:04x}", opcode);
+                } else if opcode & 0xf000 == 0 {
+                    // Machine code calls, as far as older interpreters know
+                    assert_eq!(decoded, Ok(Instruction::Sys(opcode)), "{:04x}", opcode);
+                } else {
+                    assert_eq!(decoded, Err(DecodeError { opcode }), "{:04x}", opcode);
+                }
+            }
+        }
+
+        assert_eq!(decode_for(0x00ff, Variant::Chip8), Ok(Instruction::Sys(0x0ff)));
+        assert_eq!(decode_for(0x00ff, Variant::SuperChip), Ok(Instruction::High));
+        assert!(decode_for(0xf030, Variant::Chip8).is_err());
+        assert!(decode_for(0x5342, Variant::SuperChip).is_err());
+        assert!(decode_for(0xf000, Variant::SuperChip).is_err());
+    }
+}
@@ -5,71 +5,97 @@ use sdl2::{
     keyboard::Scancode
 };
 
-#[derive(PartialEq, Debug)]
-enum KeyState {
-    None,
-    KeyDown(u8),
-    KeyUp(u8),
+// Emulator controls, as opposed to CHIP-8 keypad keys
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hotkey {
+    Quit,
+    SelectSlot(u8),
+    SaveState,
+    LoadState,
+    Screenshot,
+    ToggleRecording,
 }
+
 pub struct Keyboard {
-    key_state: KeyState,
     event_pump: EventPump,
+    // Rewind runs for as long as its key is held
+    rewinding: bool,
+    // CHIP-8 keys held down, one bit per key
+    keys: u16,
+    // Keys pressed since the last call to `handle_input`, even if they were
+    // released again before it ended
+    taps: u16,
 }
 
 impl Keyboard {
     pub fn new(sdl_context: &Sdl) -> Result<Keyboard, String> {
         Ok(
             Keyboard {
-                key_state: KeyState::None,
                 event_pump: sdl_context.event_pump()?,
+                rewinding: false,
+                keys: 0,
+                taps: 0,
             }
         )
     }
 
-    pub fn handle_input(&mut self) -> bool {
-        if let KeyState::KeyUp(_) = self.key_state {
-            self.key_state = KeyState::None;
-        };
-
+    // Updates the keys held and returns the hotkeys pressed since the last call
+    pub fn handle_input(&mut self) -> Vec<Hotkey> {
+        let mut hotkeys = Vec::new();
+        self.taps = 0;
         for event in self.event_pump.poll_iter() {
             match event {
-                Event::Quit {..} => { return false; },
-                Event::KeyDown { scancode: Some(scancode), ..} => {
-                    match scancode {
-                        Scancode::Escape => {
-                            return false;
+                Event::Quit {..} => { hotkeys.push(Hotkey::Quit); },
+                Event::KeyDown { scancode: Some(Scancode::Backspace), ..} => {
+                    self.rewinding = true;
+                },
+                Event::KeyUp { scancode: Some(Scancode::Backspace), ..} => {
+                    self.rewinding = false;
+                },
+                Event::KeyDown { scancode: Some(scancode), repeat, ..} => {
+                    match to_hotkey(scancode) {
+                        Some(hotkey) => {
+                            if !repeat { hotkeys.push(hotkey); }
                         },
-                        s => {
-                            if let Some(key) =  to_hex(s) {
-                                self.key_state = KeyState::KeyDown(key)
+                        None => {
+                            if let Some(key) = to_hex(scancode) {
+                                self.keys |= 1 << key;
+                                self.taps |= 1 << key;
                             };
                         },
                     }
                 },
                 Event::KeyUp {scancode: Some(scancode), ..} => {
-                    match to_hex(scancode) {
-                        Some(key) if self.key_state == KeyState::KeyDown(key) => {
-                            self.key_state = KeyState::KeyUp(key);
-                        },
-                        _ => ()
+                    if let Some(key) = to_hex(scancode) {
+                        self.keys &= !(1 << key);
                     }
                 }
                 _ => ()
             }
         }
         
-        true
+        hotkeys
     }
 
-    pub fn is_pressed(&self, key: u8) -> bool {
-        self.key_state == KeyState::KeyDown(key)
-    }
+    pub fn is_rewinding(&self) -> bool { self.rewinding }
 
-    pub fn get_keyup(&self) -> Option<u8> {
-        match self.key_state {
-            KeyState::KeyUp(key) => Some(key),
-            _ => None
-        }
+    // Keypad state to apply to the machine, as `Keypad::set_state` takes it.
+    // Keys tapped within a frame count as held for it, so they aren't lost.
+    pub fn keys(&self) -> u16 { self.keys | self.taps }
+}
+
+pub fn to_hotkey(scancode: Scancode) -> Option<Hotkey> {
+    match scancode {
+        Scancode::Escape => Some(Hotkey::Quit),
+        Scancode::F1 => Some(Hotkey::SelectSlot(1)),
+        Scancode::F2 => Some(Hotkey::SelectSlot(2)),
+        Scancode::F3 => Some(Hotkey::SelectSlot(3)),
+        Scancode::F4 => Some(Hotkey::SelectSlot(4)),
+        Scancode::F5 => Some(Hotkey::SaveState),
+        Scancode::F9 => Some(Hotkey::LoadState),
+        Scancode::F11 => Some(Hotkey::ToggleRecording),
+        Scancode::F12 => Some(Hotkey::Screenshot),
+        _ => None,
     }
 }
 
@@ -93,4 +119,4 @@ pub fn to_hex(scancode: Scancode) -> Option<u8> {
         Scancode::V => Some(0xf),
         _ => None,
     }
-}
\ No newline at end of file
+}
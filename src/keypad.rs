@@ -0,0 +1,68 @@
+// State of the 16 keys, one bit per key with key 0 in the lowest bit. Any
+// number of keys can be held at once. Keys pressed and released since the
+// last frame are tracked too.
+pub struct Keypad {
+    // Keys held down
+    keys: u16,
+    // Keys that went down or up since the last frame
+    pressed: u16,
+    released: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad { keys: 0, pressed: 0, released: 0 }
+    }
+
+    // Keys held down
+    pub fn state(&self) -> u16 { self.keys }
+
+    // Starts a frame with the keys in a `state` mask held, and the keys that
+    // changed since the last frame as its edges. Frontends set the keys once
+    // per frame this way, so movies can replay them exactly.
+    pub fn set_state(&mut self, keys: u16) {
+        self.pressed = keys & !self.keys;
+        self.released = self.keys & !keys;
+        self.keys = keys;
+    }
+
+    // Only the low nibble counts, as EX9E and EXA1 use it on the VIP
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys & (1 << (key & 0xf)) != 0
+    }
+
+    // Keys that went down since the last frame
+    pub fn pressed(&self) -> u16 { self.pressed }
+
+    // Keys that went up since the last frame
+    pub fn released(&self) -> u16 { self.released }
+}
+
+impl Default for Keypad {
+    fn default() -> Self { Keypad::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_presses_and_releases_between_frames() {
+        let mut keypad = Keypad::new();
+        keypad.set_state(0b0011);
+        assert_eq!((keypad.pressed(), keypad.released()), (0b0011, 0));
+        keypad.set_state(0b0110);
+        assert_eq!((keypad.pressed(), keypad.released()), (0b0100, 0b0001));
+        keypad.set_state(0b0110);
+        assert_eq!((keypad.pressed(), keypad.released()), (0, 0));
+    }
+
+    #[test]
+    fn holds_any_number_of_keys() {
+        let mut keypad = Keypad::new();
+        keypad.set_state(0x8001);
+        assert!(keypad.is_pressed(0x0) && keypad.is_pressed(0xf) && !keypad.is_pressed(0x1));
+        // Only the low nibble counts
+        assert!(keypad.is_pressed(0x10));
+    }
+}
@@ -0,0 +1,26 @@
+pub mod asm;
+pub mod bus;
+pub mod constants;
+mod cpu;
+pub mod debugger;
+pub mod decompile;
+pub mod disasm;
+pub mod error;
+pub mod framebuffer;
+pub mod gdb;
+pub mod hash;
+pub mod image;
+pub mod instruction;
+pub mod keypad;
+pub mod machine;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod quirks;
+pub mod recording;
+pub mod registers;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod sound;
+pub mod variant;
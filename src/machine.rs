@@ -0,0 +1,339 @@
+use crate::bus::Bus;
+use crate::constants::{DEFAULT_IPF, DEFAULT_PITCH, RPL_FLAG_COUNT};
+use crate::error::Error;
+use crate::framebuffer::FrameBuffer;
+use crate::hash::rom_hash;
+use crate::instruction::get_x;
+use crate::keypad::Keypad;
+use crate::quirks::Quirks;
+use crate::registers::Registers;
+use crate::rng::Rng;
+use crate::sound::{AudioPattern, Sound, BUZZER_PATTERN};
+use crate::variant::Variant;
+
+// Headless CHIP-8 core: CPU, RAM, frame buffer, timers and keypad state.
+// Frontends feed it key events, step it and present what it draws.
+//
+// Time is counted in instructions, not wall-clock time: every `ipf`
+// instructions make up one 60 Hz frame, at the end of which the timers tick.
+// Running the same program with the same input is always reproducible;
+// frontends are responsible for pacing frames in real time.
+pub struct Machine {
+    pub(crate) bus: Bus,
+    pub(crate) registers: Registers,
+    pub(crate) frame_buffer: FrameBuffer,
+    pub(crate) keypad: Keypad,
+    pub(crate) opcode: u16,
+    // Address `opcode` was fetched from
+    pub(crate) opcode_addr: u16,
+    // Set while LD Vx, K waits for a key
+    pub(crate) halted: bool,
+    // Key that went down during the wait, taken once it's released
+    pub(crate) wait_key: Option<u8>,
+    // Keys held since the wait began, which must be let go to count
+    pub(crate) held_keys: u16,
+    pub(crate) exited: bool,
+    pub(crate) rpl: [u8; RPL_FLAG_COUNT],
+    pub(crate) audio_pattern: AudioPattern,
+    pub(crate) pitch: u8,
+    pub(crate) variant: Variant,
+    pub(crate) quirks: Quirks,
+    // Instructions per frame
+    pub(crate) ipf: u32,
+    // Instructions run so far in the current frame
+    pub(crate) frame_cycles: u32,
+    pub(crate) frames: u64,
+    // Identifies the loaded ROM in save states
+    pub(crate) rom_hash: u64,
+    // Sound at the start of the current frame, then every change with the
+    // instruction slot it happened in
+    pub(crate) sound_timeline: Vec<(u32, Sound)>,
+    // Source of RND results, seeded randomly unless a seed is set
+    pub(crate) rng: Rng,
+}
+
+impl Machine {
+    pub fn new(variant: Variant, quirks: Quirks) -> Machine {
+        let mut bus = Bus::new(variant.ram_size());
+        bus.init_ram();
+
+        Machine {
+            bus,
+            registers: Registers::new(),
+            frame_buffer: FrameBuffer::new(),
+            keypad: Keypad::new(),
+            opcode: 0x0000,
+            opcode_addr: 0x0000,
+            halted: false,
+            wait_key: None,
+            held_keys: 0,
+            exited: false,
+            rpl: [0; RPL_FLAG_COUNT],
+            audio_pattern: BUZZER_PATTERN,
+            pitch: DEFAULT_PITCH,
+            variant,
+            quirks,
+            ipf: DEFAULT_IPF,
+            frame_cycles: 0,
+            frames: 0,
+            rom_hash: rom_hash(&[]),
+            sound_timeline: Vec::new(),
+            rng: Rng::new(rand::random()),
+        }
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        self.bus.load_rom(rom)?;
+        self.rom_hash = rom_hash(rom);
+
+        Ok(())
+    }
+
+    pub fn set_ipf(&mut self, ipf: u32) {
+        self.ipf = ipf.max(1);
+    }
+
+    // Runs instructions up to the end of the current frame
+    pub fn run_frame(&mut self) -> Result<(), Error> {
+        while !self.step()? {}
+
+        Ok(())
+    }
+
+    // Runs one instruction slot. Returns true if it was the last one of the
+    // frame, in which case the timers have ticked.
+    pub fn step(&mut self) -> Result<bool, Error> {
+        if self.frame_cycles == 0 {
+            self.sound_timeline.clear();
+            self.sound_timeline.push((0, self.sound()));
+        }
+
+        self.cycle()?;
+
+        let sound = self.sound();
+        if self.sound_timeline.last().is_some_and(|(_, last)| *last != sound) {
+            self.sound_timeline.push((self.frame_cycles + 1, sound));
+        }
+
+        self.frame_cycles += 1;
+        if self.frame_cycles < self.ipf {
+            return Ok(false);
+        }
+
+        self.frame_cycles = 0;
+        self.frames += 1;
+        self.decrement_timers();
+
+        Ok(true)
+    }
+
+    // Runs a single instruction, or polls the keypad while waiting on LD Vx, K
+    fn cycle(&mut self) -> Result<(), Error> {
+        if self.exited {
+            return Ok(());
+        }
+
+        if self.halted {
+            self.poll_key();
+            return Ok(());
+        }
+
+        let instruction = self.fetch()?;
+        self.execute(instruction)
+    }
+
+    // LD Vx, K, like the COSMAC VIP: the first key held down is taken once
+    // it's released, even if it was already down when the wait began. The
+    // timers keep running meanwhile. With the press quirk a key is taken as
+    // soon as it goes down, so keys held when the wait began must be pressed
+    // again, or one held key would satisfy every wait. Both go by the
+    // keypad's edges, which frontends set once per frame.
+    fn poll_key(&mut self) {
+        let keypad = &self.keypad;
+        let key = if self.quirks.key_wait_press {
+            self.held_keys &= !keypad.released();
+            let pressed = keypad.pressed() & !self.held_keys;
+            (pressed != 0).then(|| pressed.trailing_zeros() as u8)
+        } else {
+            match self.wait_key {
+                Some(key) => (keypad.released() & 1 << key != 0).then_some(key),
+                None => {
+                    let keys = keypad.state();
+                    if keys != 0 { self.wait_key = Some(keys.trailing_zeros() as u8); }
+                    None
+                },
+            }
+        };
+
+        if let Some(key) = key {
+            self.registers.v[get_x(self.opcode)] = key;
+            self.halted = false;
+            self.wait_key = None;
+        }
+    }
+
+    fn decrement_timers(&mut self) {
+        if self.registers.dt > 0 { self.registers.dt -= 1; }
+        if self.registers.st > 0 { self.registers.st -= 1; }
+    }
+
+    // Getters
+    pub fn frame_buffer(&self) -> &FrameBuffer { &self.frame_buffer }
+
+    pub fn keypad(&self) -> &Keypad { &self.keypad }
+
+    pub fn keypad_mut(&mut self) -> &mut Keypad { &mut self.keypad }
+
+    pub fn registers(&self) -> &Registers { &self.registers }
+
+    pub fn registers_mut(&mut self) -> &mut Registers { &mut self.registers }
+
+    pub fn rng(&self) -> &Rng { &self.rng }
+
+    // Seeding the RND sequence or scripting it makes runs reproducible
+    pub fn rng_mut(&mut self) -> &mut Rng { &mut self.rng }
+
+    pub fn ipf(&self) -> u32 { self.ipf }
+
+    // Frames completed since the machine was created
+    pub fn frame_count(&self) -> u64 { self.frames }
+
+    pub fn rom_hash(&self) -> u64 { self.rom_hash }
+
+    pub fn bus(&self) -> &Bus { &self.bus }
+
+    pub fn bus_mut(&mut self) -> &mut Bus { &mut self.bus }
+
+    pub fn variant(&self) -> Variant { self.variant }
+
+    pub fn quirks(&self) -> Quirks { self.quirks }
+
+    // Set once a SUPER-CHIP program executes EXIT
+    pub fn has_exited(&self) -> bool { self.exited }
+
+    pub fn is_sound_playing(&self) -> bool {
+        self.registers.st > 0 || (self.quirks.key_wait_beep && self.wait_key.is_some())
+    }
+
+    pub fn audio_pattern(&self) -> &AudioPattern { &self.audio_pattern }
+
+    pub fn pitch(&self) -> u8 { self.pitch }
+
+    pub fn sound(&self) -> Sound {
+        Sound { playing: self.is_sound_playing(), pattern: self.audio_pattern, pitch: self.pitch }
+    }
+
+    // How the sound changed over the last frame, for sample-accurate audio:
+    // the sound at its start, then each change with the number of
+    // instructions run before it
+    pub fn sound_timeline(&self) -> &[(u32, Sound)] { &self.sound_timeline }
+}
+
+impl Default for Machine {
+    fn default() -> Self { Machine::new(Variant::default(), Quirks::default()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{FONT_HEIGHT, FONT_RAM_START, PROGRAM_RAM_START};
+
+    #[test]
+    fn new_machines_are_ready_to_run() {
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        assert_eq!(machine.registers().pc as usize, PROGRAM_RAM_START);
+        assert_eq!(machine.bus().size(), 0x1000);
+        // The font's 0
+        let zero: Vec<u8> = (0..FONT_HEIGHT)
+            .map(|row| machine.bus().read_byte((FONT_RAM_START + row) as u16))
+            .collect();
+        assert_eq!(zero, [0xf0, 0x90, 0x90, 0x90, 0xf0]);
+
+        // v0 := 0xab, loop
+        machine.load_rom(&[0x60, 0xab, 0x12, 0x02]).unwrap();
+        assert_eq!(machine.bus().read_word(PROGRAM_RAM_START as u16), 0x60ab);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.registers().v[0], 0xab);
+        assert!(machine.frame_buffer().pixels().iter().all(|pixel| *pixel == 0));
+        assert!(!machine.is_sound_playing());
+        assert_eq!(Machine::new(Variant::XoChip, Quirks::XO_CHIP).bus().size(), 0x10000);
+    }
+
+    #[test]
+    fn runs_ipf_instructions_per_frame() {
+        // v0 += 1, v1 += 1, loop
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&[0x70, 0x01, 0x71, 0x01, 0x12, 0x00]).unwrap();
+        machine.set_ipf(30);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.registers().v[0], 10);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.registers().v[0], 20);
+        assert_eq!(machine.frame_count(), 2);
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame() {
+        // v0 := 10, delay := v0, buzzer := v0, loop
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&[0x60, 0x0a, 0xf0, 0x15, 0xf0, 0x18, 0x12, 0x06]).unwrap();
+        machine.set_ipf(1000);
+        for _ in 0..4 { machine.run_frame().unwrap(); }
+        assert_eq!((machine.registers().dt, machine.registers().st), (6, 6));
+    }
+
+    // v0 := key, then loop
+    const WAIT_KEY: [u8; 4] = [0xf0, 0x0a, 0x12, 0x02];
+
+    fn waiting(quirks: Quirks) -> Machine {
+        let mut machine = Machine::new(Variant::Chip8, quirks);
+        machine.load_rom(&WAIT_KEY).unwrap();
+        machine.run_frame().unwrap();
+        machine
+    }
+
+    // Runs a frame with `keys` held and returns whether FX0A is still waiting
+    fn frame(machine: &mut Machine, keys: u16) -> bool {
+        machine.keypad_mut().set_state(keys);
+        machine.run_frame().unwrap();
+        machine.halted
+    }
+
+    #[test]
+    fn vip_takes_a_key_once_released() {
+        let mut machine = waiting(Quirks::COSMAC_VIP);
+        assert!(frame(&mut machine, 1 << 5));
+        assert!(machine.is_sound_playing());
+        assert!(frame(&mut machine, 1 << 5));
+        assert!(!frame(&mut machine, 0));
+        assert_eq!(machine.registers().v[0], 5);
+    }
+
+    #[test]
+    fn vip_takes_a_one_frame_tap() {
+        let mut machine = waiting(Quirks::COSMAC_VIP);
+        assert!(frame(&mut machine, 1 << 0xa));
+        assert!(!frame(&mut machine, 0));
+        assert_eq!(machine.registers().v[0], 0xa);
+    }
+
+    #[test]
+    fn press_quirk_needs_held_keys_pressed_again() {
+        let mut machine = Machine::new(Variant::Chip8, Quirks::CHIP_48);
+        machine.load_rom(&WAIT_KEY).unwrap();
+        assert!(frame(&mut machine, 1 << 3));
+        assert!(frame(&mut machine, 1 << 3));
+        assert!(frame(&mut machine, 0));
+        assert!(!frame(&mut machine, 1 << 3));
+        assert_eq!(machine.registers().v[0], 3);
+    }
+
+    #[test]
+    fn press_quirk_takes_a_new_key_while_another_is_held() {
+        let mut machine = Machine::new(Variant::Chip8, Quirks::CHIP_48);
+        machine.load_rom(&WAIT_KEY).unwrap();
+        assert!(frame(&mut machine, 1 << 3));
+        assert!(!frame(&mut machine, 1 << 3 | 1 << 7));
+        assert_eq!(machine.registers().v[0], 7);
+    }
+}
@@ -1,34 +1,375 @@
 mod audio;
-mod bus;
-mod constants;
-mod cpu;
+mod capture;
+mod cli;
+mod config;
+mod console;
 mod display;
-mod error;
+mod frontend;
 mod keyboard;
-mod registers;
 
 extern crate native_dialog;
 
+use clap::Parser;
 use native_dialog::{MessageDialog, MessageType};
+use std::{fs, path::Path, process::ExitCode, thread};
+use std::time::{Duration, Instant};
 
-use crate::cpu::Cpu;
+use chip_8r::asm;
+use chip_8r::constants::MICROS_PER_FRAME;
+use chip_8r::debugger::Symbols;
+use chip_8r::decompile;
+use chip_8r::disasm;
+use chip_8r::error::Error as MachineError;
+use chip_8r::gdb::{GdbStub, Session};
+use chip_8r::image::{Image, ImageFormat};
+use chip_8r::machine::Machine;
+use chip_8r::movie::Movie;
+use chip_8r::octo;
 
-fn main() -> Result<(), String> {
-    let mut cpu = match Cpu::new() {
-        Ok(cpu) => cpu,
-        Err(_) => {
-            handle_fatal_error();
-            return Ok(());
+use crate::capture::{AudioRecording, InputRecording, Recording};
+use crate::cli::{AsmArgs, Cli, Command, DebugArgs, DecompileArgs, DisasmArgs, GdbArgs, RunArgs, TestArgs};
+use crate::config::{Config, Options};
+use crate::console::Console;
+use crate::frontend::Frontend;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = Config::load(cli.config.as_deref()).and_then(|config| {
+        match cli.command {
+            Some(Command::Run(args)) => run(&config, args),
+            Some(Command::Debug(args)) => debug(&config, args),
+            Some(Command::Gdb(args)) => gdb(&config, args),
+            Some(Command::Disasm(args)) => disasm(&config, args),
+            Some(Command::Decompile(args)) => decompile(&config, args),
+            Some(Command::Asm(args)) => assemble(args),
+            Some(Command::Test(args)) => test(&config, args),
+            None => run(&config, RunArgs::default()),
+        }
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("chip_8r: {}", e);
+            ExitCode::FAILURE
         }
+    }
+}
+
+fn run(config: &Config, args: RunArgs) -> Result<(), String> {
+    // Dialogs are only used when no ROM was given on the command line
+    let interactive = args.rom.is_none();
+
+    let result = run_rom(config, &args);
+    if let Err(e) = &result {
+        if interactive { handle_fatal_error(e); }
+    }
+
+    result
+}
+
+fn run_rom(config: &Config, args: &RunArgs) -> Result<(), String> {
+    let path = match &args.rom {
+        Some(path) => path.clone(),
+        None => frontend::pick_rom().map_err(|e| e.to_string())?,
     };
+    let options = Options::resolve(args, &config.settings_for(&path))?;
 
-    if !cpu.run() {
-        handle_fatal_error();
+    let replay = read_replay(&options)?;
+    let (mut machine, _) = load_machine(&path, &options, replay.as_ref())?;
+    let mut frontend = Frontend::new(&options)?;
+    frontend.set_input_recording(record_input(&options, &machine)?);
+    frontend.set_replay(replay);
+    frontend.run(&mut machine, &path)
+        .map_err(|e| format!("{} crashed: {}", path.display(), e))
+}
+
+fn debug(config: &Config, args: DebugArgs) -> Result<(), String> {
+    let path = args.run.rom.clone().ok_or("debug needs a ROM to load")?;
+    let options = Options::resolve(&args.run, &config.settings_for(&path))?;
+
+    let replay = read_replay(&options)?;
+    let (mut machine, symbols) = load_machine(&path, &options, replay.as_ref())?;
+    let mut console = Console::new(&machine, symbols);
+
+    if args.headless {
+        while console.update(&mut machine, true) && !machine.has_exited() {}
+        return Ok(());
+    }
+
+    let mut frontend = Frontend::new(&options)?;
+    frontend.set_console(console);
+    frontend.set_input_recording(record_input(&options, &machine)?);
+    frontend.set_replay(replay);
+    frontend.run(&mut machine, &path)
+        .map_err(|e| format!("{} crashed: {}", path.display(), e))
+}
+
+fn gdb(config: &Config, args: GdbArgs) -> Result<(), String> {
+    let path = args.run.rom.clone().ok_or("gdb needs a ROM to load")?;
+    let options = Options::resolve(&args.run, &config.settings_for(&path))?;
+
+    let replay = read_replay(&options)?;
+    let (mut machine, _) = load_machine(&path, &options, replay.as_ref())?;
+    let addr = ("127.0.0.1", args.port);
+    println!("Waiting for GDB on {}:{}", addr.0, addr.1);
+    let mut stub = GdbStub::listen(addr)
+        .map_err(|e| format!("failed to listen on port {}: {}", args.port, e))?;
+
+    if args.headless {
+        loop {
+            match stub.update(&mut machine, true) {
+                Ok(Session::Attached) => {},
+                Ok(Session::Detached) => break,
+                Ok(Session::Ended) => return Ok(()),
+                Err(e) => return Err(format!("GDB connection failed: {}", e)),
+            }
+        }
+        // As with a window, the program carries on once GDB detaches
+        return run_headless(&mut machine)
+            .map_err(|e| format!("{} crashed: {}", path.display(), e));
+    }
+
+    let mut frontend = Frontend::new(&options)?;
+    frontend.set_gdb(stub);
+    frontend.set_input_recording(record_input(&options, &machine)?);
+    frontend.set_replay(replay);
+    frontend.run(&mut machine, &path)
+        .map_err(|e| format!("{} crashed: {}", path.display(), e))
+}
+
+// Runs the machine at its normal speed until the program exits
+fn run_headless(machine: &mut Machine) -> Result<(), MachineError> {
+    let frame_duration = Duration::from_micros(MICROS_PER_FRAME as u64);
+    let mut next_frame = Instant::now();
+
+    while !machine.has_exited() {
+        machine.run_frame()?;
+        next_frame += frame_duration;
+        if let Some(wait) = next_frame.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    Ok(())
+}
+
+fn disasm(config: &Config, args: DisasmArgs) -> Result<(), String> {
+    let run_args = RunArgs {
+        rom: Some(args.rom.clone()),
+        quirks: args.quirks,
+        variant: args.variant,
+        ..RunArgs::default()
+    };
+    let options = Options::resolve(&run_args, &config.settings_for(&args.rom))?;
+    let (rom, _) = read_program(&args.rom)?;
+
+    for line in disasm::disassemble(&rom, options.variant) {
+        println!("{}", line);
     }
 
     Ok(())
 }
 
+fn decompile(config: &Config, args: DecompileArgs) -> Result<(), String> {
+    let run_args = RunArgs {
+        rom: Some(args.rom.clone()),
+        quirks: args.quirks,
+        variant: args.variant,
+        ..RunArgs::default()
+    };
+    let options = Options::resolve(&run_args, &config.settings_for(&args.rom))?;
+    let rom = fs::read(&args.rom)
+        .map_err(|e| format!("failed to read {}: {}", args.rom.display(), e))?;
+
+    let source = decompile::decompile(&rom, options.variant);
+    match args.output {
+        Some(output) => fs::write(&output, source)
+            .map_err(|e| format!("failed to write {}: {}", output.display(), e)),
+        None => {
+            print!("{}", source);
+            Ok(())
+        },
+    }
+}
+
+fn assemble(args: AsmArgs) -> Result<(), String> {
+    let rom = asm::assemble_file(&args.source).map_err(|e| e.to_string())?;
+    let output = args.output.unwrap_or_else(|| args.source.with_extension("ch8"));
+    fs::write(&output, &rom)
+        .map_err(|e| format!("failed to write {}: {}", output.display(), e))?;
+
+    println!("Wrote {} bytes to {}", rom.len(), output.display());
+    Ok(())
+}
+
+// Runs headless with scripted key presses, then checks the screen
+fn test(config: &Config, args: TestArgs) -> Result<(), String> {
+    let path = args.run.rom.clone().ok_or("test needs a ROM to load")?;
+    let options = Options::resolve(&args.run, &config.settings_for(&path))?;
+    let replay = read_replay(&options)?;
+    let (mut machine, _) = load_machine(&path, &options, replay.as_ref())?;
+    let mut recording = match &options.record {
+        Some(record) => Some(Recording::start(record, &options.palette, options.screenshot_scale)?),
+        None => None,
+    };
+
+    let mut audio_recording = match &options.record_audio {
+        Some(record) => Some(AudioRecording::start(record)?),
+        None => None,
+    };
+
+    let mut input_recording = record_input(&options, &machine)?;
+
+    let recordings = TestRecordings {
+        screen: recording.as_mut(),
+        audio: audio_recording.as_mut(),
+        input: input_recording.as_mut(),
+    };
+    let result = run_test_frames(&mut machine, &path, &args, replay.as_ref(), recordings);
+    // Recordings are most wanted when the ROM crashes, so they're always kept
+    if let Some(recording) = recording { recording.finish()?; }
+    if let Some(recording) = audio_recording { recording.finish()?; }
+    if let Some(recording) = input_recording { recording.finish()?; }
+    result?;
+
+    let screen = Image::from_frame_buffer(machine.frame_buffer());
+    if let Some(save) = &args.save {
+        write_image(save, &screen, &options)?;
+        println!("Saved the screen to {}", save.display());
+    }
+
+    let Some(expect) = &args.expect else {
+        print!("{}", screen.to_ascii());
+        return Ok(());
+    };
+    let (golden, planes) = read_image(expect)?;
+    match screen.differences(&golden, planes) {
+        Some(0) => {
+            println!("{}: ok", path.display());
+            Ok(())
+        },
+        Some(count) => {
+            print!("{}", screen.to_ascii());
+            Err(format!("{} pixels differ from {}", count, expect.display()))
+        },
+        None => Err(format!(
+            "screen is {}x{} but {} is {}x{}",
+            screen.width(), screen.height(), expect.display(), golden.width(), golden.height()
+        )),
+    }
+}
+
+// Recordings made while a test runs
+struct TestRecordings<'a> {
+    screen: Option<&'a mut Recording>,
+    audio: Option<&'a mut AudioRecording>,
+    input: Option<&'a mut InputRecording>,
+}
+
+// Runs the frames of a test, pressing its keys. Keys from a replayed movie
+// are held along with them.
+fn run_test_frames(
+    machine: &mut Machine,
+    path: &Path,
+    args: &TestArgs,
+    replay: Option<&Movie>,
+    mut recordings: TestRecordings,
+) -> Result<(), String> {
+    for frame in 0..args.frames {
+        let mut keys = replay.and_then(|movie| movie.keys(frame)).unwrap_or(0);
+        for press in &args.keys {
+            if (press.frame..press.frame.saturating_add(press.duration)).contains(&frame) { keys |= 1 << press.key; }
+        }
+        machine.keypad_mut().set_state(keys);
+
+        machine.run_frame()
+            .map_err(|e| format!("{} crashed on frame {}: {}", path.display(), frame, e))?;
+        if let Some(recording) = &mut recordings.screen { recording.push(machine.frame_buffer())?; }
+        if let Some(recording) = &mut recordings.audio { recording.push(machine)?; }
+        if let Some(recording) = &mut recordings.input { recording.push(machine, keys); }
+        if machine.has_exited() { break; }
+    }
+
+    Ok(())
+}
+
+fn is_pbm(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "pbm")
+}
+
+// Golden images are PBM or text. Returns whether the image keeps planes.
+fn read_image(path: &Path) -> Result<(Image, bool), String> {
+    let data = fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let image = if is_pbm(path) {
+        Image::from_pbm(&data)
+    } else {
+        Image::from_ascii(&String::from_utf8_lossy(&data))
+    };
+
+    image.map(|image| (image, !is_pbm(path)))
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))
+}
+
+fn write_image(path: &Path, image: &Image, options: &Options) -> Result<(), String> {
+    // Unscaled, so the image can be used as a golden image
+    let data = image.encode(ImageFormat::from_path(path), &options.palette);
+    fs::write(path, data).map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+// A movie being replayed decides the variant, quirks, speed and RND
+fn load_machine(path: &Path, options: &Options, replay: Option<&Movie>) -> Result<(Machine, Symbols), String> {
+    let (rom, symbols) = read_program(path)?;
+
+    let (variant, quirks) = match replay {
+        Some(movie) => (movie.variant(), movie.quirks()),
+        None => (options.variant, options.quirks),
+    };
+    let mut machine = Machine::new(variant, quirks);
+    machine.set_ipf(options.ipf);
+    machine.load_rom(&rom)
+        .map_err(|e| format!("failed to load {}: {}", path.display(), e))?;
+    if let Some(seed) = options.seed { machine.rng_mut().set_seed(seed); }
+    machine.rng_mut().set_script(options.random_bytes.clone());
+
+    if let Some(movie) = replay {
+        movie.start(&mut machine)
+            .map_err(|e| format!("can't replay the movie on {}: {}", path.display(), e))?;
+    }
+
+    Ok((machine, symbols))
+}
+
+fn read_replay(options: &Options) -> Result<Option<Movie>, String> {
+    let Some(path) = &options.replay else { return Ok(None) };
+    let data = fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let movie = Movie::from_bytes(&data)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+
+    println!("Replaying {} frames from {}", movie.len(), path.display());
+    Ok(Some(movie))
+}
+
+fn record_input(options: &Options, machine: &Machine) -> Result<Option<InputRecording>, String> {
+    match &options.record_input {
+        Some(path) => Ok(Some(InputRecording::start(path, machine)?)),
+        None => Ok(None),
+    }
+}
+
+// Octo sources are compiled on load; anything else is a ROM image
+fn read_program(path: &Path) -> Result<(Vec<u8>, Symbols), String> {
+    if path.extension().is_some_and(|extension| extension == "8o") {
+        let program = octo::compile_file(path).map_err(|e| e.to_string())?;
+        return Ok((program.rom, program.symbols));
+    }
+
+    let rom = fs::read(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    Ok((rom, Symbols::default()))
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum GameState {
     Playing,
@@ -36,11 +377,11 @@ pub enum GameState {
     Ended,
 }
 
-fn handle_fatal_error() {
+fn handle_fatal_error(message: &str) {
     MessageDialog::new()
         .set_type(MessageType::Error)
         .set_title("Chip-8 Crashed!")
-        .set_text("Something went wrong and Chip-8 will quit, sorry.")
+        .set_text(&format!("Something went wrong and Chip-8 will quit, sorry.\n\n{}", message))
         .show_alert()
         .unwrap();
-}
\ No newline at end of file
+}
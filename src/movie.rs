@@ -0,0 +1,239 @@
+use std::fmt;
+
+use crate::machine::Machine;
+use crate::quirks::Quirks;
+use crate::variant::Variant;
+
+const MAGIC: &[u8; 4] = b"C8RM";
+// Version 3 added the FX0A and lores sprite quirks to the quirk bits
+pub const MOVIE_VERSION: u16 = 3;
+
+// Size of everything before the RND script
+const HEADER_SIZE: usize = 36;
+
+// Movie layout, all integers little-endian:
+//   magic "C8RM", version u16, ROM hash u64, variant u8, quirks u8
+//   instructions per frame u32, RND seed u64, RND script length u32
+//   frame count u32, RND script [u8; script length]
+//   keys held on each frame [u16; frame count], key 0 in the lowest bit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion(u16),
+    // The variant byte names no variant, so the file is damaged
+    UnknownVariant(u8),
+    RomMismatch,
+    VariantMismatch,
+    QuirksMismatch,
+    Truncated,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(v) => write!(f, "movie version {} is not supported", v),
+            MovieError::UnknownVariant(v) => write!(f, "movie is corrupt: unknown variant {}", v),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::VariantMismatch =>
+                write!(f, "movie was recorded with a different CHIP-8 variant"),
+            MovieError::QuirksMismatch => write!(f, "movie was recorded with different quirks"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// Input recorded from frame 0 of a machine, with everything else a run
+// depends on. A machine started the same way and given the same keys on the
+// same frames runs exactly the same.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    rom_hash: u64,
+    variant: Variant,
+    quirks: Quirks,
+    ipf: u32,
+    seed: u64,
+    script: Vec<u8>,
+    keys: Vec<u16>,
+}
+
+impl Movie {
+    // An empty movie of a machine that hasn't run yet
+    pub fn new(machine: &Machine) -> Movie {
+        Movie {
+            rom_hash: machine.rom_hash(),
+            variant: machine.variant(),
+            quirks: machine.quirks(),
+            ipf: machine.ipf(),
+            seed: machine.rng().seed(),
+            script: machine.rng().script().to_vec(),
+            keys: Vec::new(),
+        }
+    }
+
+    // Adds the keys held on the next frame
+    pub fn push(&mut self, keys: u16) {
+        self.keys.push(keys);
+    }
+
+    // Drops the frames from `frames` on, e.g. after rewinding
+    pub fn truncate(&mut self, frames: usize) {
+        self.keys.truncate(frames);
+    }
+
+    // Keys held on a frame, or None past the end of the movie
+    pub fn keys(&self, frame: u64) -> Option<u16> {
+        self.keys.get(usize::try_from(frame).ok()?).copied()
+    }
+
+    // Sets up a machine with the movie's ROM loaded to replay it. The
+    // variant and quirks can't be changed on a machine, so they must match.
+    pub fn start(&self, machine: &mut Machine) -> Result<(), MovieError> {
+        if machine.rom_hash() != self.rom_hash { return Err(MovieError::RomMismatch); }
+        if machine.variant() != self.variant { return Err(MovieError::VariantMismatch); }
+        if machine.quirks() != self.quirks { return Err(MovieError::QuirksMismatch); }
+
+        machine.set_ipf(self.ipf);
+        machine.rng_mut().set_seed(self.seed);
+        machine.rng_mut().set_script(self.script.clone());
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE + self.script.len() + self.keys.len() * 2);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.rom_hash.to_le_bytes());
+        out.push(self.variant as u8);
+        out.push(quirk_bits(self.quirks));
+        out.extend_from_slice(&self.ipf.to_le_bytes());
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.extend_from_slice(&(self.script.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(self.keys.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.script);
+        for keys in &self.keys {
+            out.extend_from_slice(&keys.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if !data.starts_with(MAGIC) { return Err(MovieError::NotAMovie); }
+        if data.len() < HEADER_SIZE { return Err(MovieError::Truncated); }
+        let field = |start: usize, len: usize| &data[start..start + len];
+
+        let version = u16::from_le_bytes(field(4, 2).try_into().unwrap());
+        if version != MOVIE_VERSION { return Err(MovieError::UnsupportedVersion(version)); }
+        let rom_hash = u64::from_le_bytes(field(6, 8).try_into().unwrap());
+        let variant = match data[14] {
+            0 => Variant::Chip8,
+            1 => Variant::SuperChip,
+            2 => Variant::XoChip,
+            other => return Err(MovieError::UnknownVariant(other)),
+        };
+        let quirks = quirks_from_bits(data[15]);
+        let ipf = u32::from_le_bytes(field(16, 4).try_into().unwrap());
+        let seed = u64::from_le_bytes(field(20, 8).try_into().unwrap());
+        let script_len = u32::from_le_bytes(field(28, 4).try_into().unwrap()) as usize;
+        let frames = u32::from_le_bytes(field(32, 4).try_into().unwrap()) as usize;
+
+        let script = data.get(HEADER_SIZE..HEADER_SIZE + script_len)
+            .ok_or(MovieError::Truncated)?
+            .to_vec();
+        let keys = data[HEADER_SIZE + script_len..].chunks_exact(2)
+            .take(frames)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<u16>>();
+        if keys.len() != frames { return Err(MovieError::Truncated); }
+
+        Ok(Movie { rom_hash, variant, quirks, ipf, seed, script, keys })
+    }
+
+    // Getters
+    pub fn variant(&self) -> Variant { self.variant }
+
+    pub fn quirks(&self) -> Quirks { self.quirks }
+
+    pub fn ipf(&self) -> u32 { self.ipf }
+
+    pub fn seed(&self) -> u64 { self.seed }
+
+    pub fn script(&self) -> &[u8] { &self.script }
+
+    // Frames recorded
+    pub fn len(&self) -> usize { self.keys.len() }
+
+    pub fn is_empty(&self) -> bool { self.keys.is_empty() }
+}
+
+fn quirk_bits(quirks: Quirks) -> u8 {
+    [
+        quirks.shift_vy,
+        quirks.memory_increment_i,
+        quirks.jump_vx,
+        quirks.logic_reset_vf,
+        quirks.clip_sprites,
+        quirks.key_wait_press,
+        quirks.key_wait_beep,
+        quirks.lores_tall_sprites,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (index, set)| bits | (*set as u8) << index)
+}
+
+fn quirks_from_bits(bits: u8) -> Quirks {
+    let set = |index: u8| bits & (1 << index) != 0;
+    Quirks {
+        shift_vy: set(0),
+        memory_increment_i: set(1),
+        jump_vx: set(2),
+        logic_reset_vf: set(3),
+        clip_sprites: set(4),
+        key_wait_press: set(5),
+        key_wait_beep: set(6),
+        lores_tall_sprites: set(7),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn movie() -> Movie {
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&[0x12, 0x00]).unwrap();
+        let mut movie = Movie::new(&machine);
+        movie.push(0x0001);
+        movie.push(0x8000);
+        movie
+    }
+
+    #[test]
+    fn round_trips() {
+        let movie = movie();
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()), Ok(movie));
+    }
+
+    #[test]
+    fn rejects_older_versions() {
+        let mut bytes = movie().to_bytes();
+        bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(Movie::from_bytes(&bytes), Err(MovieError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn rejects_unknown_variants() {
+        let mut bytes = movie().to_bytes();
+        bytes[14] = 7;
+        assert_eq!(Movie::from_bytes(&bytes), Err(MovieError::UnknownVariant(7)));
+    }
+
+    #[test]
+    fn rejects_missing_frames() {
+        let bytes = movie().to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err(MovieError::Truncated));
+    }
+}
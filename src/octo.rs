@@ -0,0 +1,969 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::asm::AsmError;
+use crate::constants::{PROGRAM_RAM_START, XO_RAM_SIZE};
+use crate::debugger::{Monitor, Symbols};
+use crate::instruction::Instruction;
+
+// Macro expansions allowed in one program, to stop runaway recursion
+const MAX_EXPANSIONS: usize = 10_000;
+
+// A compiled Octo program: the ROM, plus names for the debugger
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub symbols: Symbols,
+}
+
+pub fn compile_file(path: &Path) -> Result<Program, AsmError> {
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        message: e.to_string(),
+    })?;
+
+    compile_source(&source, &path.display().to_string())
+}
+
+pub fn compile(source: &str) -> Result<Program, AsmError> {
+    compile_source(source, "<input>")
+}
+
+fn compile_source(source: &str, file: &str) -> Result<Program, AsmError> {
+    let mut compiler = Compiler::new(tokenize(source));
+    compiler.run().map_err(|(line, message)| AsmError { file: file.into(), line, message })?;
+
+    Ok(Program { rom: compiler.rom, symbols: compiler.symbols })
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+// Whitespace separated words; `#` starts a comment and strings are kept whole
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (index, text) in source.lines().enumerate() {
+        let mut rest = text;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') { break; }
+
+            let len = if let Some(string) = rest.strip_prefix('"') {
+                string.find('"').map_or(rest.len(), |end| end + 2)
+            } else {
+                rest.find(char::is_whitespace).unwrap_or(rest.len())
+            };
+            tokens.push(Token { text: rest[..len].to_string(), line: index + 1 });
+            rest = &rest[len..];
+        }
+    }
+
+    tokens
+}
+
+// Errors carry the line they happened on
+type CompileResult<T> = Result<T, (usize, String)>;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Open `if ... begin` and `loop` blocks
+enum Block {
+    // Address of the jump to patch with the end of the block
+    If(usize),
+    Else(usize),
+    Loop { start: u16, breaks: Vec<usize> },
+}
+
+// Operands that can name a label not defined yet
+#[derive(Clone, Copy)]
+enum Fixup {
+    Nnn,
+    Long,
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    pos: usize,
+    rom: Vec<u8>,
+    // Address the next byte is written to
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, String, Fixup, usize)>,
+    blocks: Vec<(Block, usize)>,
+    expansions: usize,
+    monitors: Vec<(Token, u16)>,
+    symbols: Symbols,
+    // Set when code would go past the end of memory
+    full: bool,
+}
+
+impl Compiler {
+    fn new(tokens: Vec<Token>) -> Compiler {
+        Compiler {
+            tokens,
+            pos: 0,
+            rom: Vec::new(),
+            here: PROGRAM_RAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+            monitors: Vec::new(),
+            symbols: Symbols::default(),
+            full: false,
+        }
+    }
+
+    fn run(&mut self) -> CompileResult<()> {
+        // Execution starts with a jump to `main`, unless it's right there
+        self.emit(Instruction::Jp(0));
+
+        while self.pos < self.tokens.len() {
+            let line = self.tokens[self.pos].line;
+            self.statement()?;
+            if self.full {
+                return Err((line, format!("program doesn't fit below {:#x}", XO_RAM_SIZE)));
+            }
+        }
+
+        if let Some((_, line)) = self.blocks.last() {
+            return Err((*line, "block is never closed".into()));
+        }
+        let Some(main) = self.labels.get("main").copied() else {
+            return Err((self.last_line(), "there is no `: main` label".to_string()));
+        };
+        if main as usize != PROGRAM_RAM_START {
+            self.patch(PROGRAM_RAM_START, Instruction::Jp(main).encode());
+        }
+
+        for (addr, name, fixup, line) in std::mem::take(&mut self.fixups) {
+            let Some(target) = self.labels.get(&name).copied() else {
+                return Err((line, format!("undefined name `{}`", name)));
+            };
+            match fixup {
+                Fixup::Nnn => {
+                    if target > 0xfff {
+                        return Err((line, format!("`{}` is above 0xfff; use `long`", name)));
+                    }
+                    let opcode = self.word(addr) | target;
+                    self.patch(addr, opcode);
+                },
+                Fixup::Long => self.patch(addr + 2, target),
+            }
+        }
+
+        for (name, len) in std::mem::take(&mut self.monitors) {
+            let addr = self.address_value(&name, 0)? as u16;
+            self.symbols.monitors.push(Monitor { name: name.text, addr, len });
+        }
+        self.symbols.labels = self.labels.clone();
+        Ok(())
+    }
+
+    fn statement(&mut self) -> CompileResult<()> {
+        let token = self.next()?;
+        let line = token.line;
+        let text = token.text.as_str();
+
+        match text {
+            ":" => {
+                let name = self.name()?;
+                // Like Octo, leave out the jump when `main` comes first
+                let only_jump = self.here == PROGRAM_RAM_START + 2 && self.rom.len() == 2;
+                if name == "main" && only_jump && self.labels.is_empty()
+                    && self.symbols.breakpoints.is_empty()
+                {
+                    self.rom.clear();
+                    self.here = PROGRAM_RAM_START;
+                }
+                let addr = self.here_addr(line)?;
+                if self.labels.insert(name.clone(), addr).is_some() {
+                    return Err((line, format!("`{}` is defined more than once", name)));
+                }
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.constant()?;
+                self.constants.insert(name, value);
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let reg = self.register()?;
+                self.aliases.insert(name, reg);
+            },
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            },
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                let name = self.name()?;
+                let addr = self.here_addr(line)?;
+                self.symbols.breakpoints.push((name, addr));
+            },
+            ":monitor" => {
+                // The address is usually a label further down
+                let name = self.next()?;
+                let len = self.next()?;
+                if len.text.starts_with('"') {
+                    return Err((len.line, "monitor format strings are not supported".into()));
+                }
+                let len = self.known_value(&len)?;
+                self.monitors.push((name, len as u16));
+            },
+            ":org" => {
+                let token = self.next()?;
+                self.here = self.address_value(&token, PROGRAM_RAM_START)?;
+            },
+            ":byte" => {
+                let value = self.byte()?;
+                self.emit_bytes(&[value]);
+            },
+            "clear" => self.emit(Instruction::Cls),
+            "return" | ";" => self.emit(Instruction::Ret),
+            "exit" => self.emit(Instruction::Exit),
+            "hires" => self.emit(Instruction::High),
+            "lores" => self.emit(Instruction::Low),
+            "scroll-left" => self.emit(Instruction::ScrollLeft),
+            "scroll-right" => self.emit(Instruction::ScrollRight),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollDown(n));
+            },
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollUp(n));
+            },
+            "audio" => self.emit(Instruction::Audio),
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::Plane(n));
+            },
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdB(x));
+            },
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek_is("-") {
+                    self.pos += 1;
+                    let y = self.register()?;
+                    self.emit(if text == "save" { Instruction::Save { x, y } } else { Instruction::Load { x, y } });
+                } else {
+                    self.emit(if text == "save" { Instruction::LdMemVx(x) } else { Instruction::LdVxMem(x) });
+                }
+            },
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdRVx(x));
+            },
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdVxR(x));
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Instruction::Drw { x, y, n });
+            },
+            "jump" => {
+                let addr = self.address(Fixup::Nnn)?;
+                self.emit(Instruction::Jp(addr));
+            },
+            "jump0" => {
+                let addr = self.address(Fixup::Nnn)?;
+                self.emit(Instruction::JpV0(addr));
+            },
+            "native" => {
+                let addr = self.address(Fixup::Nnn)?;
+                self.emit(Instruction::Sys(addr));
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match text {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                });
+            },
+            "i" => self.assign_i()?,
+            "if" => self.conditional(line)?,
+            "else" => {
+                let Some((Block::If(jump), _)) = self.blocks.pop() else {
+                    return Err((line, "`else` without `if ... begin`".into()));
+                };
+                let end_jump = self.here;
+                self.emit(Instruction::Jp(0));
+                self.patch_jump(jump, self.here, line)?;
+                self.blocks.push((Block::Else(end_jump), line));
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If(jump) | Block::Else(jump), _)) => self.patch_jump(jump, self.here, line)?,
+                _ => return Err((line, "`end` without `if ... begin`".into())),
+            },
+            "loop" => {
+                let start = self.here_addr(line)?;
+                self.blocks.push((Block::Loop { start, breaks: Vec::new() }, line));
+            },
+            "while" => {
+                // Leave the loop unless the condition holds
+                self.condition(true)?;
+                let jump = self.here;
+                self.emit(Instruction::Jp(0));
+                let Some((Block::Loop { breaks, .. }, _)) = self.blocks.iter_mut().rev()
+                    .find(|(block, _)| matches!(block, Block::Loop { .. })) else {
+                    return Err((line, "`while` outside of a loop".into()));
+                };
+                breaks.push(jump);
+            },
+            "again" => {
+                let Some((Block::Loop { start, breaks }, _)) = self.blocks.pop() else {
+                    return Err((line, "`again` without `loop`".into()));
+                };
+                if start > 0xfff {
+                    return Err((line, format!("loop starts at {:#x}, past the 0xfff a jump can reach", start)));
+                }
+                self.emit(Instruction::Jp(start));
+                for jump in breaks {
+                    self.patch_jump(jump, self.here, line)?;
+                }
+            },
+            _ if text.starts_with(':') => {
+                return Err((line, format!("unsupported directive `{}`", text)));
+            },
+            _ => {
+                if let Some(x) = self.register_named(text) {
+                    return self.assign_register(x, line);
+                }
+                if let Some(value) = parse_number(text) {
+                    // Bare numbers are data
+                    let byte = to_byte(value).ok_or((line, format!("`{}` doesn't fit in a byte", text)))?;
+                    self.emit_bytes(&[byte]);
+                } else if self.macros.contains_key(text) {
+                    self.expand_macro(&token)?;
+                } else {
+                    // Any other name calls a subroutine
+                    let addr = self.address_of(&token, Fixup::Nnn)?;
+                    self.emit(Instruction::Call(addr));
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    // `i := addr`, `i := long addr`, `i := hex vx`, `i := bighex vx`, `i += vx`
+    fn assign_i(&mut self) -> CompileResult<()> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => {
+                if self.peek_is("hex") || self.peek_is("bighex") {
+                    let big = self.next()?.text == "bighex";
+                    let x = self.register()?;
+                    self.emit(if big { Instruction::LdHf(x) } else { Instruction::LdF(x) });
+                } else if self.peek_is("long") {
+                    self.pos += 1;
+                    let addr = self.address(Fixup::Long)?;
+                    self.emit(Instruction::LdILong);
+                    self.emit_bytes(&addr.to_be_bytes());
+                } else {
+                    let addr = self.address(Fixup::Nnn)?;
+                    self.emit(Instruction::LdI(addr));
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddI(x));
+            },
+            _ => return Err((op.line, format!("expected `:=` or `+=` after `i`, found `{}`", op.text))),
+        }
+
+        Ok(())
+    }
+
+    fn assign_register(&mut self, x: u8, line: usize) -> CompileResult<()> {
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let y = self.register_named(&rhs.text);
+
+        let instruction = match (op.text.as_str(), y) {
+            (":=", Some(y)) => Instruction::LdReg { x, y },
+            (":=", None) => match rhs.text.as_str() {
+                "key" => Instruction::LdVxK(x),
+                "delay" => Instruction::LdVxDt(x),
+                "random" => {
+                    let kk = self.byte()?;
+                    Instruction::Rnd { x, kk }
+                },
+                _ => Instruction::LdByte { x, kk: self.byte_of(&rhs)? },
+            },
+            ("+=", Some(y)) => Instruction::AddReg { x, y },
+            ("+=", None) => Instruction::AddByte { x, kk: self.byte_of(&rhs)? },
+            ("-=", Some(y)) => Instruction::Sub { x, y },
+            ("-=", None) => Instruction::AddByte { x, kk: self.byte_of(&rhs)?.wrapping_neg() },
+            ("=-", Some(y)) => Instruction::Subn { x, y },
+            ("|=", Some(y)) => Instruction::Or { x, y },
+            ("&=", Some(y)) => Instruction::And { x, y },
+            ("^=", Some(y)) => Instruction::Xor { x, y },
+            (">>=", Some(y)) => Instruction::Shr { x, y },
+            ("<<=", Some(y)) => Instruction::Shl { x, y },
+            (op, _) => return Err((line, format!("invalid operation `{} {}`", op, rhs.text))),
+        };
+        self.emit(instruction);
+
+        Ok(())
+    }
+
+    // `if cond then` skips the next statement unless the condition holds;
+    // `if cond begin` runs a block, with an optional `else`
+    fn conditional(&mut self, line: usize) -> CompileResult<()> {
+        let start = self.pos;
+        self.skip_condition()?;
+        let keyword = self.next()?;
+        let end = self.pos;
+        self.pos = start;
+
+        match keyword.text.as_str() {
+            "then" => self.condition(false)?,
+            "begin" => {
+                self.condition(true)?;
+                let jump = self.here;
+                self.emit(Instruction::Jp(0));
+                self.blocks.push((Block::If(jump), line));
+            },
+            other => return Err((keyword.line, format!("expected `then` or `begin`, found `{}`", other))),
+        }
+        self.pos = end;
+
+        Ok(())
+    }
+
+    // Moves past a condition without compiling it
+    fn skip_condition(&mut self) -> CompileResult<()> {
+        self.next()?;
+        let op = self.next()?;
+        if op.text != "key" && op.text != "-key" { self.next()?; }
+        Ok(())
+    }
+
+    // Emits code that skips the next instruction when the condition is
+    // `skip_when`
+    fn condition(&mut self, skip_when: bool) -> CompileResult<()> {
+        let x = self.register()?;
+        let op = self.next()?;
+
+        if op.text == "key" || op.text == "-key" {
+            let pressed = op.text == "key";
+            self.emit(if pressed == skip_when { Instruction::Skp(x) } else { Instruction::Sknp(x) });
+            return Ok(());
+        }
+
+        let rhs = self.next()?;
+        let y = self.register_named(&rhs.text);
+        match op.text.as_str() {
+            "==" | "!=" => {
+                let equal_skips = (op.text == "==") == skip_when;
+                let instruction = match (y, equal_skips) {
+                    (Some(y), true) => Instruction::SeReg { x, y },
+                    (Some(y), false) => Instruction::SneReg { x, y },
+                    (None, true) => Instruction::SeByte { x, kk: self.byte_of(&rhs)? },
+                    (None, false) => Instruction::SneByte { x, kk: self.byte_of(&rhs)? },
+                };
+                self.emit(instruction);
+            },
+            // Compare with a subtraction into VF, which ends up 1 when the
+            // first operand is not less than the second
+            "<" | ">" | "<=" | ">=" => {
+                let rhs_load = match y {
+                    Some(y) => Instruction::LdReg { x: 0xf, y },
+                    None => Instruction::LdByte { x: 0xf, kk: self.byte_of(&rhs)? },
+                };
+                self.emit(rhs_load);
+                // VF := x >= rhs, or VF := rhs >= x
+                let (subtract, holds_when) = match op.text.as_str() {
+                    ">=" => (Instruction::Subn { x: 0xf, y: x }, 1),
+                    "<" => (Instruction::Subn { x: 0xf, y: x }, 0),
+                    "<=" => (Instruction::Sub { x: 0xf, y: x }, 1),
+                    _ => (Instruction::Sub { x: 0xf, y: x }, 0),
+                };
+                self.emit(subtract);
+                let kk = if skip_when { holds_when } else { 1 - holds_when };
+                self.emit(Instruction::SeByte { x: 0xf, kk });
+            },
+            other => return Err((op.line, format!("unknown comparison `{}`", other))),
+        }
+
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> CompileResult<()> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" { break; }
+            params.push(token.text);
+        }
+
+        let body = self.braced()?;
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    // Replaces a macro call with its body, arguments substituted
+    fn expand_macro(&mut self, call: &Token) -> CompileResult<()> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err((call.line, "too many macro expansions; is a macro recursive?".into()));
+        }
+
+        let mac = &self.macros[&call.text];
+        let count = mac.params.len();
+        if self.pos + count > self.tokens.len() {
+            return Err((call.line, format!("`{}` takes {} arguments", call.text, count)));
+        }
+        let args = &self.tokens[self.pos..self.pos + count];
+        let body: Vec<Token> = mac.body.iter()
+            .map(|token| {
+                let text = mac.params.iter().position(|param| *param == token.text)
+                    .map_or_else(|| token.text.clone(), |index| args[index].text.clone());
+                // Errors inside the expansion point at the call
+                Token { text, line: call.line }
+            })
+            .collect();
+
+        self.tokens.splice(self.pos..self.pos + count, body);
+        Ok(())
+    }
+
+    // Tokens up to the `}` matching an already consumed `{`
+    fn braced(&mut self) -> CompileResult<Vec<Token>> {
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 { return Ok(body); }
+                },
+                _ => {},
+            }
+            body.push(token);
+        }
+    }
+
+    // `{ expression }`, evaluated right to left without precedence as Octo
+    // does. Only constants and labels defined so far can be used.
+    fn calc(&mut self) -> CompileResult<f64> {
+        let open = self.next()?;
+        if open.text != "{" {
+            return Err((open.line, "expected `{`".into()));
+        }
+        let tokens = self.braced()?;
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos, open.line)?;
+        if pos < tokens.len() {
+            return Err((tokens[pos].line, format!("unexpected `{}`", tokens[pos].text)));
+        }
+        Ok(value)
+    }
+
+    fn expression(&self, tokens: &[Token], pos: &mut usize, line: usize) -> CompileResult<f64> {
+        let lhs = self.term(tokens, pos, line)?;
+        let Some(op) = tokens.get(*pos).filter(|token| token.text != ")") else {
+            return Ok(lhs);
+        };
+        *pos += 1;
+        let rhs = self.expression(tokens, pos, op.line)?;
+
+        let value = match op.text.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (lhs as i64 & rhs as i64) as f64,
+            "|" => (lhs as i64 | rhs as i64) as f64,
+            "^" => (lhs as i64 ^ rhs as i64) as f64,
+            "<<" => ((lhs as i64) << rhs as i64) as f64,
+            ">>" => ((lhs as i64) >> rhs as i64) as f64,
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            other => return Err((op.line, format!("unknown operator `{}`", other))),
+        };
+        Ok(value)
+    }
+
+    fn term(&self, tokens: &[Token], pos: &mut usize, line: usize) -> CompileResult<f64> {
+        let Some(token) = tokens.get(*pos) else {
+            return Err((line, "expression ends early".into()));
+        };
+        *pos += 1;
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.expression(tokens, pos, token.line)?;
+                if tokens.get(*pos).map(|token| token.text.as_str()) != Some(")") {
+                    return Err((token.line, "missing `)`".into()));
+                }
+                *pos += 1;
+                Ok(value)
+            },
+            "-" => Ok(-self.term(tokens, pos, token.line)?),
+            "HERE" => Ok(self.here as f64),
+            _ => self.known_value(token),
+        }
+    }
+
+    // A register number, or an alias for one
+    fn register(&mut self) -> CompileResult<u8> {
+        let token = self.next()?;
+        self.register_named(&token.text)
+            .ok_or((token.line, format!("expected a register, found `{}`", token.text)))
+    }
+
+    fn register_named(&self, text: &str) -> Option<u8> {
+        if let Some(reg) = self.aliases.get(text) { return Some(*reg); }
+        let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+        if digit.len() != 1 { return None; }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    // An address operand; labels not defined yet are patched in at the end
+    fn address(&mut self, fixup: Fixup) -> CompileResult<u16> {
+        let token = self.next()?;
+        self.address_of(&token, fixup)
+    }
+
+    fn address_of(&mut self, token: &Token, fixup: Fixup) -> CompileResult<u16> {
+        let max = match fixup { Fixup::Nnn => 0xfff, Fixup::Long => 0xffff };
+        if let Some(value) = self.value(&token.text) {
+            if value < 0.0 || value > max as f64 {
+                return Err((token.line, format!("`{}` is out of range", token.text)));
+            }
+            return Ok(value as u16);
+        }
+        if !is_name(&token.text) {
+            return Err((token.line, format!("expected an address, found `{}`", token.text)));
+        }
+
+        self.fixups.push((self.here, token.text.clone(), fixup, token.line));
+        Ok(0)
+    }
+
+    // Value of a number, constant or label defined so far
+    fn value(&self, text: &str) -> Option<f64> {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|addr| *addr as f64))
+    }
+
+    fn known_value(&self, token: &Token) -> CompileResult<f64> {
+        self.value(&token.text).ok_or((token.line, format!("undefined name `{}`", token.text)))
+    }
+
+    // A whole number from `min` up to the end of XO-CHIP memory
+    fn address_value(&self, token: &Token, min: usize) -> CompileResult<usize> {
+        let value = self.known_value(token)?;
+        if value.fract() != 0.0 || !(min as f64..XO_RAM_SIZE as f64).contains(&value) {
+            return Err((token.line, format!(
+                "`{}` is not an address from {:#x} to {:#x}", token.text, min, XO_RAM_SIZE - 1,
+            )));
+        }
+        Ok(value as usize)
+    }
+
+    fn constant(&mut self) -> CompileResult<f64> {
+        let token = self.next()?;
+        self.known_value(&token)
+    }
+
+    fn byte(&mut self) -> CompileResult<u8> {
+        let token = self.next()?;
+        self.byte_of(&token)
+    }
+
+    fn byte_of(&self, token: &Token) -> CompileResult<u8> {
+        let value = self.known_value(token)?;
+        to_byte(value).ok_or((token.line, format!("`{}` doesn't fit in a byte", token.text)))
+    }
+
+    fn nibble(&mut self) -> CompileResult<u8> {
+        let token = self.next()?;
+        let value = self.known_value(&token)?;
+        if !(0.0..16.0).contains(&value) {
+            return Err((token.line, format!("`{}` doesn't fit in 4 bits", token.text)));
+        }
+        Ok(value as u8)
+    }
+
+    fn name(&mut self) -> CompileResult<String> {
+        let token = self.next()?;
+        if !is_name(&token.text) || self.register_named(&token.text).is_some() {
+            return Err((token.line, format!("`{}` can't be used as a name", token.text)));
+        }
+        Ok(token.text)
+    }
+
+    fn expect(&mut self, text: &str) -> CompileResult<()> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err((token.line, format!("expected `{}`, found `{}`", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.get(self.pos).is_some_and(|token| token.text == text)
+    }
+
+    fn next(&mut self) -> CompileResult<Token> {
+        let token = self.tokens.get(self.pos).cloned()
+            .ok_or((self.last_line(), "unexpected end of file".to_string()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn last_line(&self) -> usize {
+        self.tokens.last().map_or(0, |token| token.line)
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.emit_bytes(&instruction.encode().to_be_bytes());
+    }
+
+    fn emit_bytes(&mut self, bytes: &[u8]) {
+        // Reported after the statement, which knows its line
+        if self.here + bytes.len() > XO_RAM_SIZE {
+            self.full = true;
+            return;
+        }
+        let offset = self.here - PROGRAM_RAM_START;
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+    }
+
+    fn word(&self, addr: usize) -> u16 {
+        let offset = addr - PROGRAM_RAM_START;
+        u16::from_be_bytes([self.rom[offset], self.rom[offset + 1]])
+    }
+
+    fn patch(&mut self, addr: usize, word: u16) {
+        let offset = addr - PROGRAM_RAM_START;
+        self.rom[offset..offset + 2].copy_from_slice(&word.to_be_bytes());
+    }
+
+    fn patch_jump(&mut self, jump: usize, target: usize, line: usize) -> CompileResult<()> {
+        if target > 0xfff {
+            return Err((line, format!("block ends at {:#x}, past the 0xfff a jump can reach", target)));
+        }
+        self.patch(jump, Instruction::Jp(target as u16).encode());
+        Ok(())
+    }
+
+    // Address the next byte goes to, for labels and loops
+    fn here_addr(&self, line: usize) -> CompileResult<u16> {
+        u16::try_from(self.here).map_err(|_| (line, "past the end of memory".to_string()))
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Negative bytes wrap, so -1 is 0xff
+fn to_byte(value: f64) -> Option<u8> {
+    if !(-128.0..256.0).contains(&value) { return None; }
+    Some((value as i64 as i16) as u8)
+}
+
+// Decimal, 0x hex or 0b binary, optionally negative
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source).unwrap().rom
+    }
+
+    fn error(source: &str) -> (usize, String) {
+        let error = compile(source).unwrap_err();
+        (error.line, error.message)
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        let source = "\
+: main
+  sub
+  jump main
+: sub
+  return
+";
+        assert_eq!(rom(source), [0x22, 0x04, 0x12, 0x00, 0x00, 0xee]);
+        assert_eq!(compile(source).unwrap().symbols.labels["sub"], 0x204);
+    }
+
+    #[test]
+    fn jumps_to_main_when_it_isnt_first() {
+        assert_eq!(rom(": sub return\n: main sub\n"), [0x12, 0x04, 0x00, 0xee, 0x22, 0x02]);
+    }
+
+    #[test]
+    fn constants_aliases_and_calc() {
+        let source = "\
+:const SPEED 3
+:alias px v1
+:calc DOUBLE { SPEED * 2 + 1 }
+: main
+  px := SPEED
+  px += DOUBLE
+";
+        // Right to left, without precedence: 3 * (2 + 1)
+        assert_eq!(rom(source), [0x61, 0x03, 0x71, 0x09]);
+    }
+
+    #[test]
+    fn macros_substitute_arguments() {
+        let source = ":macro twice reg { reg += 1 reg += 1 }\n: main twice v3 twice v4\n";
+        assert_eq!(rom(source), [0x73, 0x01, 0x73, 0x01, 0x74, 0x01, 0x74, 0x01]);
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "\
+: main
+  if v0 == 1 then v1 := 2
+  if v0 == 1 begin
+    v1 := 3
+  else
+    v1 := 4
+  end
+";
+        assert_eq!(rom(source), [
+            0x40, 0x01, 0x61, 0x02, // skip unless v0 == 1
+            0x30, 0x01, 0x12, 0x0c, // jump to the else part unless v0 == 1
+            0x61, 0x03, 0x12, 0x0e, // jump past the else part
+            0x61, 0x04,
+        ]);
+    }
+
+    #[test]
+    fn comparisons_go_through_vf() {
+        // VF := 7, VF := V2 - VF, which leaves VF 1 when V2 is not below 7
+        assert_eq!(rom(": main if v2 < 7 then v0 := 1\n"), [0x6f, 0x07, 0x8f, 0x27, 0x3f, 0x01, 0x60, 0x01]);
+    }
+
+    #[test]
+    fn loops() {
+        let source = "\
+: main
+  loop
+    v0 += 1
+    while v0 != 5
+  again
+";
+        assert_eq!(rom(source), [0x70, 0x01, 0x40, 0x05, 0x12, 0x08, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn org_moves_the_code() {
+        let rom = rom(": main jump far\n:org 0x300\n: far clear\n");
+        assert_eq!(rom.len(), 0x102);
+        assert_eq!(&rom[..2], [0x13, 0x00]);
+        assert_eq!(&rom[0x100..], [0x00, 0xe0]);
+    }
+
+    #[test]
+    fn long_addresses() {
+        assert_eq!(rom(": main i := long data\n: data 0xff\n"), [0xf0, 0x00, 0x02, 0x04, 0xff]);
+    }
+
+    #[test]
+    fn breakpoints_and_monitors() {
+        let source = "\
+:monitor counters 2
+: main
+  clear
+  :breakpoint cleared
+  return
+: counters 0 0
+";
+        let symbols = compile(source).unwrap().symbols;
+        assert_eq!(symbols.breakpoints, [("cleared".to_string(), 0x202)]);
+        assert_eq!(symbols.monitors, [Monitor { name: "counters".into(), addr: 0x204, len: 2 }]);
+    }
+
+    #[test]
+    fn errors_carry_their_line() {
+        assert_eq!(error(": main\n  v0 := 300\n"), (2, "`300` doesn't fit in a byte".into()));
+        assert_eq!(error(": main\n  jump nowhere\n"), (2, "undefined name `nowhere`".into()));
+        assert_eq!(error(": main\n\n  end\n"), (3, "`end` without `if ... begin`".into()));
+        assert_eq!(error(": main\n  loop\n  clear\n"), (2, "block is never closed".into()));
+        assert_eq!(error(": start\n  clear\n").1, "there is no `: main` label");
+    }
+
+    #[test]
+    fn org_must_be_an_address_in_memory() {
+        let expected = |text: &str| format!("`{}` is not an address from 0x200 to 0xffff", text);
+        assert_eq!(error(": main\n:org 1e12\n"), (2, expected("1e12")));
+        assert_eq!(error(": main\n:org 0x100\n"), (2, expected("0x100")));
+        assert_eq!(error(":calc HALF { 3 / 2 }\n: main\n:org HALF\n"), (3, expected("HALF")));
+    }
+
+    #[test]
+    fn code_must_fit_in_memory() {
+        assert!(compile(":org 0xfffe\n: main\nclear\n").is_ok());
+        assert_eq!(error(":org 0xfffe\n: main\nclear\nclear\n"), (4, "program doesn't fit below 0x10000".into()));
+        assert_eq!(error(":org 0xfffe\n: main\nclear\n: end\n"), (4, "past the end of memory".into()));
+    }
+
+    #[test]
+    fn blocks_must_be_in_jump_range() {
+        let source = ": main jump far\n:org 0x1000\n: far\n  loop\n  again\n";
+        assert_eq!(error(source), (5, "loop starts at 0x1000, past the 0xfff a jump can reach".into()));
+    }
+}
@@ -0,0 +1,72 @@
+use std::str::FromStr;
+
+use crate::constants::{
+    BG_RED, BG_GREEN, BG_BLUE,
+    FG_RED, FG_GREEN, FG_BLUE,
+    PLANE2_RED, PLANE2_GREEN, PLANE2_BLUE,
+    BOTH_RED, BOTH_GREEN, BOTH_BLUE,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+// `#rrggbb`, with or without the `#`
+impl FromStr for Rgb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rgb, String> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let value = (hex.len() == 6).then(|| u32::from_str_radix(hex, 16).ok()).flatten()
+            .ok_or_else(|| format!("invalid color `{}` (expected #rrggbb)", s))?;
+        Ok(Rgb::new((value >> 16) as u8, (value >> 8) as u8, value as u8))
+    }
+}
+
+// Colors for each pixel value: background, plane 1, plane 2 and both planes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Palette {
+    pub colors: [Rgb; 4],
+}
+
+impl Palette {
+    // Colors given in order replace the defaults; CHIP-8 programs only need
+    // the first two
+    pub fn from_colors(colors: &[String]) -> Result<Palette, String> {
+        if colors.len() > 4 {
+            return Err(format!("a palette has at most 4 colors, not {}", colors.len()));
+        }
+
+        let mut palette = Palette::default();
+        for (slot, color) in palette.colors.iter_mut().zip(colors) {
+            *slot = color.parse()?;
+        }
+        Ok(palette)
+    }
+
+    pub fn color(&self, pixel: u8) -> Rgb {
+        self.colors[(pixel & 0b11) as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            colors: [
+                Rgb::new(BG_RED, BG_GREEN, BG_BLUE),
+                Rgb::new(FG_RED, FG_GREEN, FG_BLUE),
+                Rgb::new(PLANE2_RED, PLANE2_GREEN, PLANE2_BLUE),
+                Rgb::new(BOTH_RED, BOTH_GREEN, BOTH_BLUE),
+            ],
+        }
+    }
+}
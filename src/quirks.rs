@@ -0,0 +1,249 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::variant::Variant;
+
+// Behaviors that differ between CHIP-8 interpreters. Each flag names the
+// behavior that is enabled when it is `true`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY and store the result in VX, instead of shifting VX
+    pub shift_vy: bool,
+    // FX55/FX65 leave I pointing past the last register accessed
+    pub memory_increment_i: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_reset_vf: bool,
+    // DXYN clips sprites at the screen edges instead of wrapping them
+    pub clip_sprites: bool,
+    // FX0A takes a key as soon as it's pressed, instead of once it's released
+    pub key_wait_press: bool,
+    // FX0A sounds the buzzer while the key is held down
+    pub key_wait_beep: bool,
+    // DXY0 draws 8x16 sprites in low resolution, as SUPER-CHIP 1.x does,
+    // instead of 16x16
+    pub lores_tall_sprites: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_vy: true,
+        memory_increment_i: true,
+        jump_vx: false,
+        logic_reset_vf: true,
+        clip_sprites: true,
+        key_wait_press: false,
+        key_wait_beep: true,
+        lores_tall_sprites: false,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_vy: false,
+        memory_increment_i: true,
+        jump_vx: true,
+        logic_reset_vf: false,
+        clip_sprites: true,
+        key_wait_press: true,
+        key_wait_beep: false,
+        lores_tall_sprites: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_vy: false,
+        memory_increment_i: false,
+        jump_vx: true,
+        logic_reset_vf: false,
+        clip_sprites: true,
+        key_wait_press: true,
+        key_wait_beep: false,
+        lores_tall_sprites: true,
+    };
+
+    pub const MODERN: Quirks = Quirks {
+        shift_vy: true,
+        memory_increment_i: true,
+        jump_vx: false,
+        logic_reset_vf: false,
+        clip_sprites: true,
+        key_wait_press: false,
+        key_wait_beep: false,
+        lores_tall_sprites: false,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_vy: true,
+        memory_increment_i: true,
+        jump_vx: false,
+        logic_reset_vf: false,
+        clip_sprites: false,
+        key_wait_press: false,
+        key_wait_beep: false,
+        lores_tall_sprites: false,
+    };
+}
+
+impl Default for Quirks {
+    fn default() -> Self { Profile::default().quirks() }
+}
+
+// Named quirk presets, selectable by name from the CLI and config file
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Profile {
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    #[default]
+    Modern,
+    XoChip,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 5] = [
+        Profile::CosmacVip,
+        Profile::Chip48,
+        Profile::SuperChip,
+        Profile::Modern,
+        Profile::XoChip,
+    ];
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Profile::CosmacVip => Quirks::COSMAC_VIP,
+            Profile::Chip48 => Quirks::CHIP_48,
+            Profile::SuperChip => Quirks::SUPER_CHIP,
+            Profile::Modern => Quirks::MODERN,
+            Profile::XoChip => Quirks::XO_CHIP,
+        }
+    }
+
+    // Instruction set the interpreter of this profile understood
+    pub fn variant(self) -> Variant {
+        match self {
+            Profile::CosmacVip | Profile::Chip48 | Profile::Modern => Variant::Chip8,
+            Profile::SuperChip => Variant::SuperChip,
+            Profile::XoChip => Variant::XoChip,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::CosmacVip => "vip",
+            Profile::Chip48 => "chip48",
+            Profile::SuperChip => "schip",
+            Profile::Modern => "modern",
+            Profile::XoChip => "xochip",
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Profile, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" | "chip-8" => Ok(Profile::CosmacVip),
+            "chip48" | "chip-48" => Ok(Profile::Chip48),
+            "schip" | "superchip" | "super-chip" | "schip1.1" => Ok(Profile::SuperChip),
+            "modern" => Ok(Profile::Modern),
+            "xochip" | "xo-chip" => Ok(Profile::XoChip),
+            _ => {
+                let names: Vec<&str> = Profile::ALL.iter().map(|p| p.name()).collect();
+                Err(format!("unknown quirk profile `{}` (expected one of {})", s, names.join(", ")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+
+    // Runs `steps` instructions of `rom` with one quirk set each way and
+    // returns both machines, quirk off first
+    fn run(rom: &[u8], steps: usize, set: fn(&mut Quirks, bool)) -> [Machine; 2] {
+        [false, true].map(|on| {
+            let mut quirks = Quirks::MODERN;
+            set(&mut quirks, on);
+            let mut machine = Machine::new(Variant::Chip8, quirks);
+            machine.load_rom(rom).unwrap();
+            for _ in 0..steps { machine.step().unwrap(); }
+            machine
+        })
+    }
+
+    #[test]
+    fn shift_source() {
+        // V1 := 0x81; V0 := 0x02; V0 >>= V1
+        let rom = [0x61, 0x81, 0x60, 0x02, 0x80, 0x16];
+        let [vx, vy] = run(&rom, 3, |quirks, on| quirks.shift_vy = on);
+        assert_eq!((vx.registers().v[0], vx.registers().v[0xf]), (0x01, 0));
+        assert_eq!((vy.registers().v[0], vy.registers().v[0xf]), (0x40, 1));
+    }
+
+    #[test]
+    fn load_and_store_increment_i() {
+        // I := 0x300; save V0-V1; load V0-V2
+        let rom = [0xa3, 0x00, 0xf1, 0x55, 0xf2, 0x65];
+        let [fixed, incremented] = run(&rom, 2, |quirks, on| quirks.memory_increment_i = on);
+        assert_eq!(fixed.registers().i, 0x300);
+        assert_eq!(incremented.registers().i, 0x302);
+
+        let [fixed, incremented] = run(&rom, 3, |quirks, on| quirks.memory_increment_i = on);
+        assert_eq!(fixed.registers().i, 0x300);
+        assert_eq!(incremented.registers().i, 0x305);
+    }
+
+    #[test]
+    fn jump_offset_register() {
+        // V0 := 4; V2 := 8; jump0 0x210
+        let rom = [0x60, 0x04, 0x62, 0x08, 0xb2, 0x10];
+        let [v0, vx] = run(&rom, 3, |quirks, on| quirks.jump_vx = on);
+        assert_eq!(v0.registers().pc, 0x214);
+        assert_eq!(vx.registers().pc, 0x218);
+    }
+
+    #[test]
+    fn logic_resets_vf() {
+        // VF := 5; V0 |= V1
+        let rom = [0x6f, 0x05, 0x80, 0x11];
+        let [kept, reset] = run(&rom, 2, |quirks, on| quirks.logic_reset_vf = on);
+        assert_eq!(kept.registers().v[0xf], 5);
+        assert_eq!(reset.registers().v[0xf], 0);
+    }
+
+    #[test]
+    fn sprites_clip_or_wrap() {
+        // V0 := 60; I := 0x208; draw 8x1 at V0, V1; then the row of the sprite
+        let rom = [0x60, 0x3c, 0xa2, 0x08, 0xd0, 0x11, 0x12, 0x06, 0xff];
+        let [wrapped, clipped] = run(&rom, 3, |quirks, on| quirks.clip_sprites = on);
+        assert_eq!(wrapped.frame_buffer().get(63, 0), 1);
+        assert_eq!(wrapped.frame_buffer().get(0, 0), 1);
+        assert_eq!(clipped.frame_buffer().get(63, 0), 1);
+        assert_eq!(clipped.frame_buffer().get(0, 0), 0);
+    }
+
+    #[test]
+    fn parses_profiles() {
+        for profile in Profile::ALL {
+            assert_eq!(profile.name().parse(), Ok(profile));
+        }
+        assert_eq!("COSMAC-VIP".parse(), Ok(Profile::CosmacVip));
+        assert_eq!("schip1.1".parse(), Ok(Profile::SuperChip));
+        assert_eq!("XO-CHIP".parse::<Profile>().map(Profile::quirks), Ok(Quirks::XO_CHIP));
+        assert_eq!(Quirks::default(), Quirks::MODERN);
+    }
+
+    #[test]
+    fn rejects_unknown_profiles() {
+        let error = "chip-9".parse::<Profile>().unwrap_err();
+        assert_eq!(error, "unknown quirk profile `chip-9` (expected one of vip, chip48, schip, modern, xochip)");
+    }
+}
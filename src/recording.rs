@@ -0,0 +1,330 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use gif::{DisposalMethod, Encoder, Frame, Repeat};
+
+use crate::constants::{FRAME_RATE, HIRES_HEIGHT, HIRES_WIDTH, SAMPLE_RATE};
+use crate::framebuffer::FrameBuffer;
+use crate::image::Image;
+use crate::machine::Machine;
+use crate::palette::Palette;
+use crate::sound::Oscillator;
+
+// GIF delays count hundredths of a second
+const GIF_TICKS_PER_SECOND: u64 = 100;
+
+// Browsers slow shorter delays down to a tenth of a second
+const MIN_DELAY: u64 = 2;
+
+const SAMPLES_PER_FRAME: u32 = SAMPLE_RATE as u32 / FRAME_RATE;
+
+// Size of the RIFF and fmt chunks before the sample data
+const WAV_HEADER_SIZE: u32 = 44;
+
+// Records every 60 Hz frame of the screen into an animated GIF. Frames that
+// repeat the previous one only lengthen its delay, and only the rectangle
+// that changed is stored for the others, so still scenes cost nothing. Low
+// resolution frames are doubled to fill the high resolution canvas, so a
+// recording can span resolution changes. GIFs can't show 60 frames a
+// second, so a frame replaced in under two hundredths of a second is
+// dropped.
+pub struct GifRecorder<W: Write> {
+    encoder: Encoder<W>,
+    scale: usize,
+    // Last frame, written once a different one shows how long it lasted
+    pending: Option<Image>,
+    // Frame on screen before the pending one
+    written: Option<Image>,
+    // Frames recorded so far, and the one the pending frame started on
+    frames: u64,
+    pending_start: u64,
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(writer: W, palette: &Palette, scale: usize) -> io::Result<GifRecorder<W>> {
+        let scale = scale.max(1);
+        let colors: Vec<u8> = palette.colors.iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+        let width = HIRES_WIDTH as usize * scale;
+        let height = HIRES_HEIGHT as usize * scale;
+
+        let mut encoder = Encoder::new(writer, width as u16, height as u16, &colors).map_err(io::Error::other)?;
+        encoder.set_repeat(Repeat::Infinite).map_err(io::Error::other)?;
+
+        Ok(GifRecorder {
+            encoder,
+            scale,
+            pending: None,
+            written: None,
+            frames: 0,
+            pending_start: 0,
+        })
+    }
+
+    pub fn push(&mut self, frame_buffer: &FrameBuffer) -> io::Result<()> {
+        let image = Image::from_frame_buffer(frame_buffer);
+        let fill = HIRES_WIDTH as usize / image.width();
+        let image = image.scaled(fill * self.scale);
+
+        if self.pending.as_ref() != Some(&image) {
+            if to_ticks(self.frames) - to_ticks(self.pending_start) >= MIN_DELAY {
+                self.write_pending()?;
+                self.pending_start = self.frames;
+            }
+            self.pending = Some(image);
+        }
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    // Writes the last frame and returns the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_pending()?;
+        self.encoder.into_inner().map_err(io::Error::other)
+    }
+
+    // Delays are rounded from the total time so far, so rounding errors
+    // don't add up over a long recording
+    fn write_pending(&mut self) -> io::Result<()> {
+        let Some(image) = self.pending.take() else { return Ok(()) };
+
+        let start = to_ticks(self.pending_start);
+        let end = to_ticks(self.frames);
+        let (left, top, width, height) = match &self.written {
+            Some(written) => changed_area(written, &image),
+            None => (0, 0, image.width(), image.height()),
+        };
+        let pixels: Vec<u8> = image.pixels()
+            .chunks(image.width())
+            .skip(top)
+            .take(height)
+            .flat_map(|row| row[left..left + width].iter().copied())
+            .collect();
+
+        let mut frame = Frame::from_indexed_pixels(width as u16, height as u16, pixels, None);
+        frame.left = left as u16;
+        frame.top = top as u16;
+        frame.dispose = DisposalMethod::Keep;
+        frame.delay = (end - start).clamp(MIN_DELAY, u16::MAX as u64) as u16;
+        self.encoder.write_frame(&frame).map_err(io::Error::other)?;
+
+        self.written = Some(image);
+        Ok(())
+    }
+
+    // Getters
+    pub fn frames(&self) -> u64 { self.frames }
+}
+
+// Records the buzzer to a 16-bit mono WAV file. Samples are made from each
+// frame's sound timeline, spreading the frame's instructions evenly over its
+// samples, so the output only depends on the program and its input.
+pub struct WavRecorder<W: Write + Seek> {
+    writer: W,
+    oscillator: Oscillator,
+    samples: u32,
+    // Last frame recorded, so a frame is never recorded twice
+    frame: Option<u64>,
+}
+
+impl<W: Write + Seek> WavRecorder<W> {
+    pub fn new(mut writer: W) -> io::Result<WavRecorder<W>> {
+        // Sizes are filled in by `finish`
+        write_wav_header(&mut writer, 0)?;
+
+        Ok(WavRecorder {
+            writer,
+            oscillator: Oscillator::new(SAMPLE_RATE),
+            samples: 0,
+            frame: None,
+        })
+    }
+
+    // Records the frame the machine just ran. Does nothing until it has
+    // finished another one, so it can be called on every displayed frame.
+    pub fn push(&mut self, machine: &Machine) -> io::Result<()> {
+        let frame = machine.frame_count();
+        if self.frame == Some(frame) { return Ok(()); }
+        self.frame = Some(frame);
+
+        let timeline = machine.sound_timeline();
+        let ipf = machine.ipf() as u64;
+        let mut data = Vec::with_capacity(SAMPLES_PER_FRAME as usize * 2);
+        let mut change = 0;
+        for sample in 0..SAMPLES_PER_FRAME as u64 {
+            let slot = (sample * ipf / SAMPLES_PER_FRAME as u64) as u32;
+            while timeline.get(change + 1).is_some_and(|(change_slot, _)| *change_slot <= slot) {
+                change += 1;
+            }
+
+            let value = match timeline.get(change) {
+                Some((_, sound)) if sound.playing => {
+                    self.oscillator.set_pattern(sound.pattern);
+                    self.oscillator.set_pitch(sound.pitch);
+                    self.oscillator.get_sample()
+                },
+                _ => 0.0,
+            };
+            data.extend_from_slice(&((value * i16::MAX as f32) as i16).to_le_bytes());
+        }
+
+        self.writer.write_all(&data)?;
+        self.samples += SAMPLES_PER_FRAME;
+        Ok(())
+    }
+
+    // Fills in the sizes in the header and returns the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.samples * 2)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    // Getters
+    pub fn samples(&self) -> u32 { self.samples }
+}
+
+fn write_wav_header(writer: &mut impl Write, data_size: u32) -> io::Result<()> {
+    let sample_rate = SAMPLE_RATE as u32;
+    let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel, 16 bits
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+
+    writer.write_all(&header)
+}
+
+fn to_ticks(frames: u64) -> u64 {
+    (frames * GIF_TICKS_PER_SECOND + FRAME_RATE as u64 / 2) / FRAME_RATE as u64
+}
+
+// Smallest rectangle holding every pixel that differs, as left, top, width
+// and height. At least one pixel, since a frame can't be empty.
+fn changed_area(before: &Image, after: &Image) -> (usize, usize, usize, usize) {
+    let width = after.width();
+    let (mut left, mut top, mut right, mut bottom) = (width, after.height(), 0, 0);
+    for (index, (a, b)) in before.pixels().iter().zip(after.pixels()).enumerate() {
+        if a == b { continue; }
+        let (x, y) = (index % width, index / width);
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x);
+        bottom = bottom.max(y);
+    }
+
+    if left > right { return (0, 0, 1, 1); }
+    (left, top, right - left + 1, bottom - top + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    // A low resolution screen with at most one pixel set
+    fn screen(pixel: Option<(usize, usize)>) -> FrameBuffer {
+        let mut frame_buffer = FrameBuffer::new();
+        if let Some((x, y)) = pixel { frame_buffer.toggle(x, y, 1); }
+        frame_buffer
+    }
+
+    #[test]
+    fn gif_frames_last_until_the_screen_changes() {
+        let palette = Palette::default();
+        let mut recorder = GifRecorder::new(Vec::new(), &palette, 1).unwrap();
+        let frames = [
+            (screen(None), 6),
+            (screen(Some((1, 1))), 13),
+            // Replaced after a single tick, so it's dropped
+            (screen(Some((5, 5))), 1),
+            (screen(None), 10),
+        ];
+        for (frame_buffer, count) in &frames {
+            for _ in 0..*count { recorder.push(frame_buffer).unwrap(); }
+        }
+        assert_eq!(recorder.frames(), 30);
+        let gif = recorder.finish().unwrap();
+
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[128, 0, 64, 0]);
+        assert!(gif.windows(11).any(|window| window == b"NETSCAPE2.0"));
+
+        let mut decoder = gif::DecodeOptions::new().read_info(Cursor::new(gif)).unwrap();
+        let colors: Vec<u8> = palette.colors.iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+        assert_eq!(decoder.global_palette(), Some(colors.as_slice()));
+
+        let mut written = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            written.push((frame.left, frame.top, frame.width, frame.height, frame.delay, frame.buffer.to_vec()));
+        }
+        // The whole screen, then only the 2x2 block of the doubled pixel
+        assert_eq!(written.len(), 3);
+        let (left, top, width, height, delay, pixels) = &written[0];
+        assert_eq!((*left, *top, *width, *height, *delay), (0, 0, 128, 64, 10));
+        assert!(pixels.iter().all(|pixel| *pixel == 0));
+        let (left, top, width, height, delay, pixels) = &written[1];
+        assert_eq!((*left, *top, *width, *height, *delay), (2, 2, 2, 2, 22));
+        assert_eq!(pixels, &[1; 4]);
+        let (left, top, width, height, delay, pixels) = &written[2];
+        assert_eq!((*left, *top, *width, *height, *delay), (2, 2, 2, 2, 18));
+        assert_eq!(pixels, &[0; 4]);
+    }
+
+    // V0 := 3; ST := V0 as the second instruction of the frame; loop
+    const BEEP: [u8; 6] = [0x60, 0x03, 0xf0, 0x18, 0x12, 0x04];
+
+    #[test]
+    fn wav_follows_the_sound_timer() {
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&BEEP).unwrap();
+        machine.set_ipf(10);
+
+        let mut recorder = WavRecorder::new(Cursor::new(Vec::new())).unwrap();
+        for _ in 0..4 {
+            machine.run_frame().unwrap();
+            recorder.push(&machine).unwrap();
+            // Already recorded
+            recorder.push(&machine).unwrap();
+        }
+        assert_eq!(recorder.samples(), 4 * SAMPLES_PER_FRAME);
+        let wav = recorder.finish().unwrap().into_inner();
+
+        let data_size = 4 * SAMPLES_PER_FRAME * 2;
+        let u16_at = |pos: usize| u16::from_le_bytes([wav[pos], wav[pos + 1]]);
+        let u32_at = |pos: usize| u32::from_le_bytes([wav[pos], wav[pos + 1], wav[pos + 2], wav[pos + 3]]);
+        assert_eq!(wav.len(), (WAV_HEADER_SIZE + data_size) as usize);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + data_size);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!((u32_at(16), u16_at(20), u16_at(22)), (16, 1, 1));
+        assert_eq!((u32_at(24), u32_at(28)), (44100, 88200));
+        assert_eq!((u16_at(32), u16_at(34)), (2, 16));
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(40), data_size);
+
+        let samples: Vec<i16> = wav[WAV_HEADER_SIZE as usize..].chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        let frames: Vec<&[i16]> = samples.chunks(SAMPLES_PER_FRAME as usize).collect();
+        // The buzzer starts after two of the first frame's ten instructions
+        let start = SAMPLES_PER_FRAME as usize / 5;
+        assert!(frames[0][..start].iter().all(|sample| *sample == 0));
+        assert!(frames[0][start..].iter().all(|sample| *sample != 0));
+        assert!(frames[1].iter().chain(frames[2]).all(|sample| *sample != 0));
+        assert!(frames[3].iter().all(|sample| *sample == 0));
+    }
+}
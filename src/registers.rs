@@ -1,5 +1,6 @@
 use crate::constants::{REGISTER_COUNT, STACK_SIZE};
 
+#[derive(Clone)]
 pub struct Registers {
     // Program Counter
     pub pc: u16,
@@ -29,3 +30,7 @@ impl Registers {
         }
     }
 }
+
+impl Default for Registers {
+    fn default() -> Self { Registers::new() }
+}
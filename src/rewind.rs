@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+
+use crate::machine::Machine;
+
+// Ring buffer of per-frame snapshots that can be restored newest first.
+//
+// Only the newest snapshot is kept whole. Each older one is stored as the
+// XOR with the snapshot that followed it, which is almost all zeros from one
+// frame to the next, with runs of zeros squeezed out.
+pub struct Rewind {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(frames: usize) -> Rewind {
+        Rewind {
+            capacity: frames,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // Records the machine's state, dropping the oldest frame when full
+    pub fn push(&mut self, machine: &Machine) {
+        if self.capacity == 0 { return; }
+
+        let state = machine.save_state();
+        if let Some(newest) = self.newest.take() {
+            if newest.len() == state.len() {
+                self.deltas.push_back(compress(&xor(&newest, &state)));
+            } else {
+                self.deltas.clear();
+            }
+        }
+        self.newest = Some(state);
+
+        while self.deltas.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    // Restores the frame before the newest one recorded. Returns false once
+    // there is nothing older to go back to.
+    pub fn rewind(&mut self, machine: &mut Machine) -> bool {
+        let (Some(newest), Some(delta)) = (self.newest.as_ref(), self.deltas.pop_back()) else {
+            return false;
+        };
+
+        let previous = xor(newest, &decompress(&delta, newest.len()));
+        if machine.load_state(&previous).is_err() {
+            self.clear();
+            return false;
+        }
+        self.newest = Some(previous);
+
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    // Frames that can currently be rewound
+    pub fn len(&self) -> usize { self.deltas.len() }
+
+    pub fn is_empty(&self) -> bool { self.deltas.is_empty() }
+
+    // Bytes used by the stored snapshots
+    pub fn memory_usage(&self) -> usize {
+        self.newest.as_ref().map_or(0, |state| state.len())
+            + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+// Alternating runs: a u16 count of zeros, then a u16 count of literal bytes
+// followed by the bytes themselves
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let zeros = data[pos..].iter()
+            .take(u16::MAX as usize)
+            .take_while(|byte| **byte == 0)
+            .count();
+        pos += zeros;
+
+        let literals = data[pos..].iter()
+            .take(u16::MAX as usize)
+            .take_while(|byte| **byte != 0)
+            .count();
+
+        out.extend_from_slice(&(zeros as u16).to_le_bytes());
+        out.extend_from_slice(&(literals as u16).to_le_bytes());
+        out.extend_from_slice(&data[pos..pos + literals]);
+        pos += literals;
+    }
+
+    out
+}
+
+fn decompress(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+
+    while pos + 4 <= data.len() {
+        let zeros = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        let literals = u16::from_le_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        pos += 4;
+
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[pos..pos + literals]);
+        pos += literals;
+    }
+    out.resize(len, 0);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts up in V0 and draws the count's digit, forever
+    const COUNTER: [u8; 10] = [0x70, 0x01, 0x00, 0xe0, 0xf0, 0x29, 0xd1, 0x15, 0x12, 0x00];
+
+    // A machine recorded into `rewind` for `frames` frames after its first
+    // state, with every state it was in
+    fn record(rewind: &mut Rewind, frames: usize) -> (Machine, Vec<Vec<u8>>) {
+        let mut machine = Machine::default();
+        machine.load_rom(&COUNTER).unwrap();
+        let mut states = vec![machine.save_state()];
+        rewind.push(&machine);
+        for _ in 0..frames {
+            machine.run_frame().unwrap();
+            states.push(machine.save_state());
+            rewind.push(&machine);
+        }
+        (machine, states)
+    }
+
+    #[test]
+    fn rewinding_restores_earlier_frames() {
+        let mut rewind = Rewind::new(100);
+        let (mut machine, states) = record(&mut rewind, 10);
+        assert_eq!(rewind.len(), 10);
+
+        for _ in 0..4 { assert!(rewind.rewind(&mut machine)); }
+        assert_eq!(machine.save_state(), states[6]);
+        assert_eq!(rewind.len(), 6);
+
+        // Recording carries on from the restored frame
+        machine.run_frame().unwrap();
+        rewind.push(&machine);
+        assert!(rewind.rewind(&mut machine));
+        assert_eq!(machine.save_state(), states[6]);
+    }
+
+    #[test]
+    fn full_buffer_drops_the_oldest_frames() {
+        let mut rewind = Rewind::new(3);
+        let (mut machine, states) = record(&mut rewind, 6);
+        assert_eq!(rewind.len(), 3);
+
+        for _ in 0..3 { assert!(rewind.rewind(&mut machine)); }
+        assert_eq!(machine.save_state(), states[3]);
+        assert!(!rewind.rewind(&mut machine));
+        assert_eq!(machine.save_state(), states[3]);
+    }
+
+    #[test]
+    fn zero_capacity_records_nothing() {
+        let mut rewind = Rewind::new(0);
+        let (mut machine, _) = record(&mut rewind, 2);
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_usage(), 0);
+        assert!(!rewind.rewind(&mut machine));
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        decompress(&compress(data), data.len())
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let max = u16::MAX as usize;
+        assert_eq!(round_trip(&[]), []);
+
+        // Runs longer than a count can hold are split
+        let zeros = vec![0; 3 * max + 5];
+        assert_eq!(round_trip(&zeros), zeros);
+        assert!(compress(&zeros).len() <= 4 * 4);
+
+        let literals: Vec<u8> = (0..2 * max + 7).map(|i| (i % 255) as u8 + 1).collect();
+        assert_eq!(round_trip(&literals), literals);
+
+        let mut exact = vec![0; max];
+        exact.extend(vec![0xaa; max]);
+        exact.push(0);
+        assert_eq!(round_trip(&exact), exact);
+
+        let alternating: Vec<u8> = (0..1000).map(|i| (i % 2) as u8).collect();
+        assert_eq!(round_trip(&alternating), alternating);
+    }
+}
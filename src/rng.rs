@@ -0,0 +1,82 @@
+// Source of RND results. Numbers come from SplitMix64, which is small, fast
+// and the same on every platform, so a seed always gives the same sequence.
+// A script of bytes can be plugged in instead to force specific results.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    pub(crate) seed: u64,
+    pub(crate) state: u64,
+    // Bytes returned in turn, repeating, instead of generated ones
+    pub(crate) script: Vec<u8>,
+    // Index of the next script byte
+    pub(crate) position: usize,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { seed, state: seed, script: Vec::new(), position: 0 }
+    }
+
+    // Restarts the sequence from a seed, and any script from its start
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.state = seed;
+        self.position = 0;
+    }
+
+    // An empty script goes back to generated numbers
+    pub fn set_script(&mut self, bytes: Vec<u8>) {
+        self.script = bytes;
+        self.position = 0;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        if self.script.is_empty() {
+            return (self.next_u64() >> 56) as u8;
+        }
+
+        let byte = self.script[self.position];
+        self.position = (self.position + 1) % self.script.len();
+        byte
+    }
+
+    // Getters
+    pub fn seed(&self) -> u64 { self.seed }
+
+    pub fn script(&self) -> &[u8] { &self.script }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(rng: &mut Rng) -> Vec<u8> {
+        (0..8).map(|_| rng.next_u8()).collect()
+    }
+
+    #[test]
+    fn seeds_repeat_their_sequence() {
+        let first = bytes(&mut Rng::new(1234));
+        assert_eq!(bytes(&mut Rng::new(1234)), first);
+        assert_ne!(bytes(&mut Rng::new(1235)), first);
+
+        let mut rng = Rng::new(1);
+        rng.next_u8();
+        rng.set_seed(1234);
+        assert_eq!(bytes(&mut rng), first);
+    }
+
+    #[test]
+    fn scripts_repeat() {
+        let mut rng = Rng::new(1234);
+        rng.set_script(vec![0x12, 0x34, 0x00]);
+        assert_eq!(bytes(&mut rng), [0x12, 0x34, 0x00, 0x12, 0x34, 0x00, 0x12, 0x34]);
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+
+use crate::constants::{HIRES_HEIGHT, HIRES_WIDTH, REGISTER_COUNT, RPL_FLAG_COUNT, STACK_SIZE};
+use crate::machine::Machine;
+
+const MAGIC: &[u8; 4] = b"C8RS";
+pub const SAVE_STATE_VERSION: u16 = 5;
+
+// Stored for the key waited on when there is none
+const NO_KEY: u8 = 0xff;
+
+// Snapshot layout, all integers little-endian:
+//   magic "C8RS", version u16, ROM hash u64, variant u8
+//   pc u16, i u16, sp u8, stack [u16; STACK_SIZE], v [u8; 16], dt u8, st u8
+//   opcode u16, opcode address u16, halted u8, exited u8
+//   key waited on u8 (0xff for none), keys held when the wait began u16
+//   RPL flags [u8; 16], audio pattern [u8; 16], pitch u8
+//   instructions into the frame u32, frame count u64
+//   RND seed u64, RND state u64, RND script position u32
+//   RND script length u32, RND script
+//   hires u8, planes u8, pixels [u8; 128 * 64]
+//   RAM size u32, RAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    NotASaveState,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    VariantMismatch,
+    Truncated,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) =>
+                write!(f, "save state version {} is not supported", v),
+            SaveStateError::RomMismatch => write!(f, "save state was made from a different ROM"),
+            SaveStateError::VariantMismatch =>
+                write!(f, "save state was made with a different CHIP-8 variant"),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl Machine {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.bytes(MAGIC);
+        w.u16(SAVE_STATE_VERSION);
+        w.u64(self.rom_hash);
+        w.u8(self.variant as u8);
+
+        let r = &self.registers;
+        w.u16(r.pc);
+        w.u16(r.i);
+        w.u8(r.sp as u8);
+        for addr in r.stack.iter() { w.u16(*addr); }
+        w.bytes(&r.v);
+        w.u8(r.dt);
+        w.u8(r.st);
+
+        w.u16(self.opcode);
+        w.u16(self.opcode_addr);
+        w.u8(self.halted as u8);
+        w.u8(self.exited as u8);
+        w.u8(self.wait_key.unwrap_or(NO_KEY));
+        w.u16(self.held_keys);
+        w.bytes(&self.rpl);
+        w.bytes(&self.audio_pattern);
+        w.u8(self.pitch);
+        w.u32(self.frame_cycles);
+        w.u64(self.frames);
+        w.u64(self.rng.seed);
+        w.u64(self.rng.state);
+        w.u32(self.rng.position as u32);
+        w.u32(self.rng.script.len() as u32);
+        w.bytes(&self.rng.script);
+
+        let fb = &self.frame_buffer;
+        w.u8(fb.is_hires() as u8);
+        w.u8(fb.planes);
+        w.bytes(&fb.pixels);
+
+        w.u32(self.bus.ram.len() as u32);
+        w.bytes(&self.bus.ram);
+
+        w.0
+    }
+
+    // Leaves the machine untouched if the state can't be loaded
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut r = Reader { data, pos: 0 };
+        if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let version = r.u16()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        if r.u64()? != self.rom_hash {
+            return Err(SaveStateError::RomMismatch);
+        }
+        if r.u8()? != self.variant as u8 {
+            return Err(SaveStateError::VariantMismatch);
+        }
+
+        let mut registers = self.registers.clone();
+        registers.pc = r.u16()?;
+        registers.i = r.u16()?;
+        registers.sp = (r.u8()? as usize).min(STACK_SIZE as usize);
+        for addr in registers.stack.iter_mut() { *addr = r.u16()?; }
+        registers.v.copy_from_slice(r.bytes(REGISTER_COUNT as usize)?);
+        registers.dt = r.u8()?;
+        registers.st = r.u8()?;
+
+        let opcode = r.u16()?;
+        let opcode_addr = r.u16()?;
+        let halted = r.u8()? != 0;
+        let exited = r.u8()? != 0;
+        let wait_key = Some(r.u8()?).filter(|key| *key != NO_KEY);
+        let held_keys = r.u16()?;
+        let rpl = r.bytes(RPL_FLAG_COUNT)?;
+        let audio_pattern = r.bytes(self.audio_pattern.len())?;
+        let pitch = r.u8()?;
+        let frame_cycles = r.u32()?;
+        let frames = r.u64()?;
+        let rng_seed = r.u64()?;
+        let rng_state = r.u64()?;
+        let rng_position = r.u32()? as usize;
+        let script_len = r.u32()? as usize;
+        let script = r.bytes(script_len)?;
+
+        let hires = r.u8()? != 0;
+        let planes = r.u8()?;
+        let pixels = r.bytes((HIRES_WIDTH * HIRES_HEIGHT) as usize)?;
+
+        let ram_size = r.u32()? as usize;
+        if ram_size != self.bus.ram.len() {
+            return Err(SaveStateError::VariantMismatch);
+        }
+        let ram = r.bytes(ram_size)?;
+
+        self.registers = registers;
+        self.opcode = opcode;
+        self.opcode_addr = opcode_addr;
+        self.halted = halted;
+        self.exited = exited;
+        self.wait_key = wait_key;
+        self.held_keys = held_keys;
+        self.rpl.copy_from_slice(rpl);
+        self.audio_pattern.copy_from_slice(audio_pattern);
+        self.pitch = pitch;
+        self.frame_cycles = frame_cycles;
+        self.frames = frames;
+        self.rng.seed = rng_seed;
+        self.rng.state = rng_state;
+        self.rng.script = script.to_vec();
+        self.rng.position = rng_position.checked_rem(script.len()).unwrap_or(0);
+        self.frame_buffer.set_hires(hires);
+        self.frame_buffer.select_planes(planes);
+        self.frame_buffer.pixels.copy_from_slice(pixels);
+        self.bus.restore_ram(ram);
+
+        Ok(())
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) { self.0.push(value); }
+
+    fn u16(&mut self, value: u16) { self.0.extend_from_slice(&value.to_le_bytes()); }
+
+    fn u32(&mut self, value: u32) { self.0.extend_from_slice(&value.to_le_bytes()); }
+
+    fn u64(&mut self, value: u64) { self.0.extend_from_slice(&value.to_le_bytes()); }
+
+    fn bytes(&mut self, bytes: &[u8]) { self.0.extend_from_slice(bytes); }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or(SaveStateError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    // v0 := random 0xff, forever
+    const RND_LOOP: [u8; 4] = [0xc0, 0xff, 0x12, 0x00];
+
+    fn rnd_machine() -> Machine {
+        let mut machine = Machine::new(Variant::Chip8, Quirks::default());
+        machine.load_rom(&RND_LOOP).unwrap();
+        machine
+    }
+
+    // The next few RND results
+    fn rnd(machine: &mut Machine) -> Vec<u8> {
+        (0..4).map(|_| {
+            machine.step().unwrap();
+            machine.step().unwrap();
+            machine.registers().v[0]
+        }).collect()
+    }
+
+    #[test]
+    fn loading_continues_the_random_numbers() {
+        let mut machine = rnd_machine();
+        machine.rng_mut().set_seed(1234);
+        rnd(&mut machine);
+        let state = machine.save_state();
+        let expected = rnd(&mut machine);
+
+        let mut other = rnd_machine();
+        other.load_state(&state).unwrap();
+        assert_eq!(rnd(&mut other), expected);
+    }
+
+    #[test]
+    fn loading_continues_the_script() {
+        let mut machine = rnd_machine();
+        machine.rng_mut().set_script(vec![1, 2, 3]);
+        rnd(&mut machine);
+        let state = machine.save_state();
+
+        let mut other = rnd_machine();
+        other.load_state(&state).unwrap();
+        assert_eq!(rnd(&mut other), [2, 3, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_other_roms() {
+        let state = rnd_machine().save_state();
+        let mut other = Machine::new(Variant::Chip8, Quirks::default());
+        other.load_rom(&[0x12, 0x00]).unwrap();
+        assert_eq!(other.load_state(&state), Err(SaveStateError::RomMismatch));
+        assert_eq!(other.load_state(&state[..10]), Err(SaveStateError::Truncated));
+    }
+
+    // hires; VA := 5; delay := VA; saverflags VA; then a subroutine that
+    // saves V0-VA to 0x300 and draws digits across the screen forever
+    const BUSY: [u8; 28] = [
+        0x00, 0xff, 0x6a, 0x05, 0xfa, 0x15, 0xfa, 0x75, 0x22, 0x10, 0x12, 0x0a, 0x00, 0x00, 0x00, 0x00,
+        0xa3, 0x00, 0xfa, 0x55, 0xfa, 0x29, 0xd0, 0x15, 0x70, 0x05, 0x12, 0x14,
+    ];
+
+    fn busy_machine() -> Machine {
+        let mut machine = Machine::new(Variant::XoChip, Quirks::XO_CHIP);
+        machine.load_rom(&BUSY).unwrap();
+        machine.set_ipf(7);
+        machine
+    }
+
+    fn run(machine: &mut Machine, frames: usize) -> Vec<u8> {
+        for _ in 0..frames { machine.run_frame().unwrap(); }
+        machine.save_state()
+    }
+
+    #[test]
+    fn loading_restores_the_whole_machine() {
+        let mut machine = busy_machine();
+        let state = run(&mut machine, 3);
+        assert!(machine.frame_buffer().is_hires());
+        assert_eq!(machine.registers().sp, 1);
+        let expected = run(&mut machine, 5);
+
+        let mut other = busy_machine();
+        other.load_state(&state).unwrap();
+        assert!(other.save_state() == state);
+        assert!(run(&mut other, 5) == expected);
+    }
+
+    #[test]
+    fn rejects_damaged_states() {
+        let mut machine = busy_machine();
+        let state = run(&mut machine, 1);
+        let mut other = busy_machine();
+        let before = other.save_state();
+
+        assert_eq!(other.load_state(b"C8RX"), Err(SaveStateError::NotASaveState));
+        let mut old = state.clone();
+        old[4..6].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(other.load_state(&old), Err(SaveStateError::UnsupportedVersion(4)));
+        assert_eq!(other.load_state(&state[..state.len() - 1]), Err(SaveStateError::Truncated));
+        assert!(other.save_state() == before, "a failed load changed the machine");
+
+        let mut schip = Machine::new(Variant::SuperChip, Quirks::SUPER_CHIP);
+        schip.load_rom(&BUSY).unwrap();
+        assert_eq!(schip.load_state(&state), Err(SaveStateError::VariantMismatch));
+    }
+}
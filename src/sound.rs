@@ -0,0 +1,141 @@
+use crate::constants::{
+    AUDIO_PATTERN_BITS,
+    AUDIO_PATTERN_SIZE,
+    DEFAULT_PITCH,
+    VOLUME,
+};
+
+pub type AudioPattern = [u8; AUDIO_PATTERN_SIZE];
+
+// What the machine sounds like at a point in time
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Sound {
+    pub playing: bool,
+    pub pattern: AudioPattern,
+    pub pitch: u8,
+}
+
+// Square wave played for the classic buzzer until a program loads its own
+// pattern. At the default pitch this is a 250 Hz tone.
+pub const BUZZER_PATTERN: AudioPattern = [
+    0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00,
+    0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00,
+];
+
+// Plays a 128-bit XO-CHIP audio pattern in a loop, one bit at a time, at
+// the rate set by the pitch register. Samples are pulled, so the same
+// oscillator feeds an audio device or anything else that needs them.
+pub struct Oscillator {
+    sample_rate: f32,
+    pattern: AudioPattern,
+    pitch: u8,
+    // Position in the pattern, in bits
+    cursor: f32,
+}
+
+impl Oscillator {
+    pub fn new(sample_rate: f32) -> Oscillator {
+        Oscillator {
+            sample_rate,
+            pattern: BUZZER_PATTERN,
+            pitch: DEFAULT_PITCH,
+            cursor: 0.0,
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: AudioPattern) {
+        self.pattern = pattern;
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+    }
+
+    // Getters
+    pub fn pattern(&self) -> AudioPattern { self.pattern }
+
+    pub fn pitch(&self) -> u8 { self.pitch }
+
+    pub fn get_sample(&mut self) -> f32 {
+        let bit = self.cursor as usize;
+        let on = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+
+        self.cursor += playback_rate(self.pitch) / self.sample_rate;
+        self.cursor %= AUDIO_PATTERN_BITS as f32;
+
+        if on { VOLUME } else { -VOLUME }
+    }
+}
+
+impl Iterator for Oscillator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.get_sample())
+    }
+}
+
+// Pattern bits played per second: 4000 * 2 ^ ((pitch - 64) / 48)
+pub fn playback_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    // Whether each of the next samples is high
+    fn levels(oscillator: &mut Oscillator, count: usize) -> Vec<bool> {
+        oscillator.take(count).map(|sample| sample > 0.0).collect()
+    }
+
+    #[test]
+    fn plays_one_bit_per_sample_at_4000_hz() {
+        // The default pitch plays 4000 bits a second
+        let mut oscillator = Oscillator::new(4000.0);
+        let mut pattern = [0; AUDIO_PATTERN_SIZE];
+        pattern[0] = 0b1010_0000;
+        oscillator.set_pattern(pattern);
+        assert_eq!(levels(&mut oscillator, 4), [true, false, true, false]);
+    }
+
+    #[test]
+    fn buzzer_is_a_square_wave() {
+        let mut oscillator = Oscillator::new(4000.0);
+        let expected: Vec<bool> = (0..32).map(|bit| bit % 16 < 8).collect();
+        assert_eq!(levels(&mut oscillator, 32), expected);
+    }
+
+    #[test]
+    fn pitch_doubles_every_48_steps() {
+        assert_eq!(playback_rate(DEFAULT_PITCH), 4000.0);
+        assert_eq!(playback_rate(DEFAULT_PITCH + 48), 8000.0);
+
+        let mut oscillator = Oscillator::new(4000.0);
+        oscillator.set_pitch(DEFAULT_PITCH + 48);
+        let expected: Vec<bool> = (0..16).map(|sample| sample % 8 < 4).collect();
+        assert_eq!(levels(&mut oscillator, 16), expected);
+    }
+
+    #[test]
+    fn programs_load_a_pattern_and_pitch() {
+        let mut rom = vec![
+            0xa2, 0x0a, // i := pattern
+            0xf0, 0x02, // audio
+            0x60, 0x70, // v0 := 0x70
+            0xf0, 0x3a, // pitch := v0
+            0x12, 0x08, // loop
+        ];
+        let pattern: Vec<u8> = (0..AUDIO_PATTERN_SIZE as u8).collect();
+        rom.extend_from_slice(&pattern);
+
+        let mut machine = Machine::new(Variant::XoChip, Quirks::XO_CHIP);
+        machine.load_rom(&rom).unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!(machine.audio_pattern()[..], pattern[..]);
+        assert_eq!(machine.pitch(), 0x70);
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::constants::{RAM_SIZE, XO_RAM_SIZE};
+
+// Instruction set understood by the machine. Each variant includes the
+// instructions of the ones before it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Variant {
+    #[default]
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Variant {
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Chip8 => "chip8",
+            Variant::SuperChip => "schip",
+            Variant::XoChip => "xochip",
+        }
+    }
+
+    pub fn has_super_chip(self) -> bool { self >= Variant::SuperChip }
+
+    pub fn has_xo_chip(self) -> bool { self >= Variant::XoChip }
+
+    pub fn ram_size(self) -> usize {
+        if self.has_xo_chip() { XO_RAM_SIZE } else { RAM_SIZE }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Variant, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Variant::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Variant::SuperChip),
+            "xochip" | "xo-chip" => Ok(Variant::XoChip),
+            _ => Err(format!("unknown variant `{}` (expected one of chip8, schip, xochip)", s)),
+        }
+    }
+}